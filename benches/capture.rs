@@ -0,0 +1,41 @@
+#[macro_use]
+extern crate criterion;
+extern crate screenshot;
+
+use criterion::Criterion;
+use screenshot::{capture_into, get_screenshot, Capturer, Screenshot};
+
+fn one_shot(c: &mut Criterion) {
+    c.bench_function("get_screenshot", |b| b.iter(|| get_screenshot(0).unwrap()));
+}
+
+fn reused_buffer(c: &mut Criterion) {
+    let mut frame = Screenshot::default();
+    c.bench_function("capture_into", |b| {
+        b.iter(|| capture_into(0, &mut frame).unwrap())
+    });
+
+    let mut capturer = Capturer::new(0).unwrap();
+    c.bench_function("Capturer::capture", |b| {
+        b.iter(|| capturer.capture().map(|frame| frame.raw_len()).unwrap())
+    });
+}
+
+#[cfg(feature = "png")]
+fn png_stream(c: &mut Criterion) {
+    let mut capturer = Capturer::new(0).unwrap();
+    c.bench_function("Capturer::capture + to_png_bytes", |b| {
+        b.iter(|| capturer.capture().unwrap().to_png_bytes().unwrap())
+    });
+
+    let mut buf = Vec::new();
+    c.bench_function("Capturer::capture_png_into", |b| {
+        b.iter(|| capturer.capture_png_into(&mut buf).unwrap())
+    });
+}
+
+#[cfg(not(feature = "png"))]
+fn png_stream(_: &mut Criterion) {}
+
+criterion_group!(benches, one_shot, reused_buffer, png_stream);
+criterion_main!(benches);
@@ -7,28 +7,347 @@
 //! origin at the top left corner. It uses ARGB pixels.
 //!
 //! The Windows GDI bitmap has its coordinate origin at the bottom left. We
-//! attempt to undo this by reordering the rows. Windows also uses ARGB pixels.
+//! request a top-down DIB so rows come back in the usual order. Windows also
+//! uses ARGB pixels.
+//!
+//! # Logging
+//!
+//! With the `log` feature, the platform backends report what they're doing
+//! through the [`log`](https://docs.rs/log) crate: the capture path taken and
+//! the image it returned at `debug` level, and native failures and
+//! fallbacks at `warn` level.
 
 #![allow(unused_assignments)]
 
 extern crate libc;
 
+#[cfg(feature = "gif")]
+extern crate gif;
+#[cfg(feature = "jpeg")]
+extern crate jpeg_encoder;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "memmap2")]
+extern crate memmap2;
+#[cfg(feature = "png")]
+extern crate png;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 #[cfg(target_os = "windows")]
 extern crate winapi;
 
-pub use ffi::get_screenshot;
+// Without the `log` feature, logging compiles to nothing, but still
+// type-checks its arguments.
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+#[cfg(not(feature = "log"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+pub use ffi::{get_active_window_screenshot, get_screenshot, list_displays};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::cmp;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::mem::size_of;
+use std::ops::{Deref, Index};
+use std::panic;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Quality used by `ImageFormat::Jpeg`, from 1 to 100.
+#[cfg(feature = "jpeg")]
+const JPEG_QUALITY: u8 = 90;
+
+/// Longest side of the frames `capture_gif` writes.
+#[cfg(feature = "gif")]
+const GIF_MAX_SIDE: usize = 800;
+
+/// Palette quantizer speed used by `capture_gif`, from 1 (best) to 30.
+#[cfg(feature = "gif")]
+const GIF_QUANTIZE_SPEED: i32 = 10;
+
+/// Encoded image formats understood by `Screenshot::write_to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// 8-bit RGBA PNG. Requires the `png` feature, which is on by default.
+    #[cfg(feature = "png")]
+    Png,
+    /// Uncompressed top-down BMP in the screenshot's own BGR(A) layout.
+    Bmp,
+    /// Baseline JPEG; alpha is dropped. Requires the `jpeg` feature.
+    #[cfg(feature = "jpeg")]
+    Jpeg,
+    /// Lossless [QOI](https://qoiformat.org/), much faster to encode than
+    /// PNG at the cost of larger files.
+    Qoi,
+    /// Binary PPM (P6): RGB only, alpha dropped.
+    Ppm,
+}
+
+impl ImageFormat {
+    /// The format conventionally saved with extension `ext`, ignoring case,
+    /// if its encoder is enabled.
+    fn from_extension(ext: &str) -> Option<ImageFormat> {
+        match &*ext.to_ascii_lowercase() {
+            #[cfg(feature = "png")]
+            "png" => Some(ImageFormat::Png),
+            "bmp" => Some(ImageFormat::Bmp),
+            #[cfg(feature = "jpeg")]
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "qoi" => Some(ImageFormat::Qoi),
+            "ppm" => Some(ImageFormat::Ppm),
+            _ => None,
+        }
+    }
+
+    /// The MIME type of files in this format.
+    fn mime_type(self) -> &'static str {
+        match self {
+            #[cfg(feature = "png")]
+            ImageFormat::Png => "image/png",
+            ImageFormat::Bmp => "image/bmp",
+            #[cfg(feature = "jpeg")]
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Qoi => "image/qoi",
+            ImageFormat::Ppm => "image/x-portable-pixmap",
+        }
+    }
+}
 
-#[derive(Clone, Copy)]
+/// Fields are laid out in the screenshot's memory order, so a 4-byte pixel
+/// of the buffer can be viewed as a `Pixel` in place.
+///
+/// Pixels are ordered by `to_u32`: by alpha, then red, green and blue.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Pixel {
-    pub a: u8,
-    pub r: u8,
-    pub g: u8,
     pub b: u8,
+    pub g: u8,
+    pub r: u8,
+    pub a: u8,
+}
+
+impl Pixel {
+    /// Move each channel, alpha included, `alpha` of the way from this
+    /// pixel's toward `other`'s: 0.0 gives `self` and 1.0 gives `other`.
+    /// `alpha` is clamped to that range.
+    pub fn blend(self, other: Pixel, alpha: f32) -> Pixel {
+        let t = alpha.clamp(0.0, 1.0);
+        Pixel {
+            b: blend_channel(self.b, other.b, t),
+            g: blend_channel(self.g, other.g, t),
+            r: blend_channel(self.r, other.r, t),
+            a: blend_channel(self.a, other.a, t),
+        }
+    }
+
+    /// The pixel packed as `0xAARRGGBB`.
+    pub fn to_u32(self) -> u32 {
+        (self.a as u32) << 24 | (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+
+    /// Keep only the top `bits` bits of the red, green and blue channels,
+    /// so similar colors fall in the same histogram bucket. Alpha is left
+    /// alone so opaque pixels stay opaque. `bits` of 8 or more changes
+    /// nothing.
+    pub fn quantize(self, bits: u8) -> Pixel {
+        let mask = match bits {
+            0 => 0,
+            1..=7 => !(0xffu8 >> bits),
+            _ => 0xff,
+        };
+        Pixel {
+            b: self.b & mask,
+            g: self.g & mask,
+            r: self.r & mask,
+            a: self.a,
+        }
+    }
+
+    /// Composite this pixel over `background` using its own (straight)
+    /// alpha.
+    pub fn blend_over(self, background: Pixel) -> Pixel {
+        let t = self.a as f32 / 255.0;
+        Pixel {
+            b: blend_channel(background.b, self.b, t),
+            g: blend_channel(background.g, self.g, t),
+            r: blend_channel(background.r, self.r, t),
+            a: (self.a as f32 + background.a as f32 * (1.0 - t)).round() as u8,
+        }
+    }
+}
+
+impl PartialOrd for Pixel {
+    fn partial_cmp(&self, other: &Pixel) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pixel {
+    fn cmp(&self, other: &Pixel) -> cmp::Ordering {
+        self.to_u32().cmp(&other.to_u32())
+    }
+}
+
+/// How a screenshot's alpha channel relates to its color channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlphaMode {
+    /// Red, green and blue have been multiplied by alpha, as compositors
+    /// keep them. macOS captures are premultiplied.
+    Premultiplied,
+    /// Red, green and blue are independent of alpha.
+    Straight,
+    /// Every pixel is opaque. The alpha bytes aren't meaningful and may not
+    /// be 255, as in GDI captures on Windows.
+    Opaque,
+}
+
+/// A pixel of a screenshot with 16 bits per component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pixel16 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+    pub a: u16,
+}
+
+/// A single color channel of a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Alpha,
+    Red,
+    Green,
+    Blue,
+}
+
+impl Channel {
+    /// This channel of `p`.
+    fn of(self, p: Pixel) -> u8 {
+        match self {
+            Channel::Blue => p.b,
+            Channel::Green => p.g,
+            Channel::Red => p.r,
+            Channel::Alpha => p.a,
+        }
+    }
+}
+
+/// Order of the channels of a 4-byte pixel in memory, or of the
+/// little-endian components of an 8-byte pixel.
+///
+/// Captures are `Bgra` (ARGB as a little-endian word) unless
+/// `CaptureOptions::output_format` asks otherwise, except for the odd
+/// big-endian image on macOS. Accessors and converters follow the format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    Bgra,
+    Argb,
+    Rgba,
+    Abgr,
+}
+
+impl PixelFormat {
+    /// The bytes of `p` as they are laid out in memory in this format.
+    fn to_memory(self, p: Pixel) -> [u8; 4] {
+        match self {
+            PixelFormat::Bgra => [p.b, p.g, p.r, p.a],
+            PixelFormat::Argb => [p.a, p.r, p.g, p.b],
+            PixelFormat::Rgba => [p.r, p.g, p.b, p.a],
+            PixelFormat::Abgr => [p.a, p.b, p.g, p.r],
+        }
+    }
+
+    /// Index of the alpha channel within a pixel.
+    fn alpha_offset(self) -> usize {
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => 3,
+            PixelFormat::Argb | PixelFormat::Abgr => 0,
+        }
+    }
+
+    /// The pixel whose bytes are laid out in memory as `m` in this format.
+    fn read_memory(self, m: &[u8]) -> Pixel {
+        let (b, g, r, a) = match self {
+            PixelFormat::Bgra => (m[0], m[1], m[2], m[3]),
+            PixelFormat::Argb => (m[3], m[2], m[1], m[0]),
+            PixelFormat::Rgba => (m[2], m[1], m[0], m[3]),
+            PixelFormat::Abgr => (m[1], m[2], m[3], m[0]),
+        };
+        Pixel { b, g, r, a }
+    }
+
+    /// The pixel stored in `px`, a 3-, 4- or 8-byte pixel in this format.
+    /// 3-byte pixels are BGR and opaque, and the 16-bit little-endian
+    /// components of 8-byte pixels are narrowed to their high byte.
+    fn load(self, px: &[u8]) -> Pixel {
+        match px.len() {
+            3 => Pixel {
+                a: 255,
+                r: px[2],
+                g: px[1],
+                b: px[0],
+            },
+            8 => self.read_memory(&[px[1], px[3], px[5], px[7]]),
+            _ => self.read_memory(px),
+        }
+    }
+
+    /// Store `p` into `px`, the inverse of `load`. 3-byte pixels drop
+    /// alpha, and 8-byte pixels widen each channel `c` to `c * 257`.
+    fn store(self, px: &mut [u8], p: Pixel) {
+        let m = self.to_memory(p);
+        match px.len() {
+            3 => px.copy_from_slice(&[p.b, p.g, p.r]),
+            8 => {
+                for (c, &byte) in px.chunks_mut(2).zip(m.iter()) {
+                    c.copy_from_slice(&[byte, byte]);
+                }
+            }
+            _ => px[..4].copy_from_slice(&m),
+        }
+    }
+
+    /// For each byte of a pixel in `to`, the byte of a pixel in this format
+    /// holding the same channel.
+    fn permutation_to(self, to: PixelFormat) -> [usize; 4] {
+        let ids = Pixel {
+            b: 0,
+            g: 1,
+            r: 2,
+            a: 3,
+        };
+        let (from, to) = (self.to_memory(ids), to.to_memory(ids));
+        let mut perm = [0; 4];
+        for (i, channel) in to.iter().enumerate() {
+            perm[i] = from.iter().position(|c| c == channel).unwrap();
+        }
+        perm
+    }
 }
 
 /// An image buffer containing the screenshot.
 /// Pixels are stored as [ARGB](https://en.wikipedia.org/wiki/ARGB).
+#[derive(Clone)]
 pub struct Screenshot {
     data: Vec<u8>,
     height: usize,
@@ -36,9 +355,36 @@ pub struct Screenshot {
     row_len: usize,
     // Might be superfluous
     pixel_width: usize,
+    color_space: Option<String>,
+    format: PixelFormat,
+    bits_per_component: usize,
+    captured_at: SystemTime,
+    alpha_mode: AlphaMode,
 }
 
 impl Screenshot {
+    /// A `width` x `height` image filled with `fill`, with 4-byte `Bgra`
+    /// pixels and no row padding. It is `Opaque` if `fill` is, and has
+    /// straight alpha otherwise.
+    pub fn new_blank(width: usize, height: usize, fill: Pixel) -> Screenshot {
+        Screenshot {
+            data: [fill.b, fill.g, fill.r, fill.a].repeat(width * height),
+            height,
+            width,
+            row_len: width * 4,
+            pixel_width: 4,
+            color_space: None,
+            format: PixelFormat::Bgra,
+            bits_per_component: 8,
+            captured_at: UNIX_EPOCH,
+            alpha_mode: if fill.a == 255 {
+                AlphaMode::Opaque
+            } else {
+                AlphaMode::Straight
+            },
+        }
+    }
+
     /// Height of image in pixels.
     #[inline]
     pub fn height(&self) -> usize {
@@ -51,6 +397,22 @@ impl Screenshot {
         self.width
     }
 
+    /// Number of pixels, `width * height`.
+    #[inline]
+    pub fn pixel_count(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// Width divided by height, or `None` for an image with no rows.
+    #[inline]
+    pub fn aspect_ratio(&self) -> Option<f64> {
+        if self.height == 0 {
+            None
+        } else {
+            Some(self.width as f64 / self.height as f64)
+        }
+    }
+
     /// Number of bytes in one row of bitmap.
     #[inline]
     pub fn row_len(&self) -> usize {
@@ -63,402 +425,8581 @@ impl Screenshot {
         self.pixel_width
     }
 
-    /// Raw bitmap.
+    /// Name of the color space the pixels are in, such as
+    /// `kCGColorSpaceDisplayP3`. Only macOS reports one.
     #[inline]
-    pub unsafe fn raw_data(&self) -> *const u8 {
-        &self.data[0] as *const u8
+    pub fn color_space(&self) -> Option<&str> {
+        self.color_space.as_deref()
     }
 
-    /// Raw bitmap.
+    /// Order of the channels within each pixel.
     #[inline]
-    pub unsafe fn raw_data_mut(&mut self) -> *mut u8 {
-        &mut self.data[0] as *mut u8
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.format
     }
 
-    /// Number of bytes in bitmap
+    /// When the platform handed over the image: right after the native
+    /// grab for captures, and the Unix epoch for screenshots that weren't
+    /// captured, such as `Screenshot::default()`. Derived images such as
+    /// crops keep their source's time.
     #[inline]
-    pub fn raw_len(&self) -> usize {
-        self.data.len() * size_of::<u8>()
+    pub fn captured_at(&self) -> SystemTime {
+        self.captured_at
     }
 
-    /// Gets pixel at (row, col)
-    pub fn get_pixel(&self, row: usize, col: usize) -> Pixel {
-        let idx = row * self.row_len() + col * self.pixel_width();
-        unsafe {
-            //let data = &self.data[0] as *const u8;
-            if idx > self.data.len() {
-                panic!("Bounds overflow");
+    /// Whether the color channels are premultiplied by alpha. See
+    /// `unpremultiply`.
+    #[inline]
+    pub fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    /// Bits per color component: 8, or 16 for a capture that kept the
+    /// display's depth (see `CaptureOptions::preserve_depth`). Everything
+    /// but `get_pixel_u16` expects 8.
+    #[inline]
+    pub fn bits_per_component(&self) -> usize {
+        self.bits_per_component
+    }
+
+    /// Reorder the channels of each 4-byte pixel to `Bgra` within the
+    /// existing buffer, leaving row padding alone.
+    ///
+    /// This changes how the buffer must be interpreted: after it,
+    /// `pixel_format()` is `Bgra` and `raw_data()` holds BGRA bytes.
+    /// Screenshots that aren't 4 bytes per pixel are left as they are.
+    pub fn to_bgra_in_place(&mut self) {
+        self.reorder_in_place(PixelFormat::Bgra);
+    }
+
+    /// Reorder the channels of each 4-byte pixel to `format` in place.
+    fn reorder_in_place(&mut self, format: PixelFormat) {
+        if self.pixel_width != 4 || self.format == format {
+            return;
+        }
+        let perm = self.format.permutation_to(format);
+        let packed_len = self.width * 4;
+        for row in 0..self.height {
+            let start = row * self.row_len;
+            for px in self.data[start..start + packed_len].chunks_mut(4) {
+                let old = [px[0], px[1], px[2], px[3]];
+                for (byte, &from) in px.iter_mut().zip(perm.iter()) {
+                    *byte = old[from];
+                }
             }
+        }
+        self.format = format;
+    }
 
-            Pixel {
-                a: *self.data.get_unchecked(idx + 3),
-                r: *self.data.get_unchecked(idx + 2),
-                g: *self.data.get_unchecked(idx + 1),
-                b: *self.data.get_unchecked(idx),
+    /// Replace the buffer with `src`, an image of this screenshot's size
+    /// whose 4-byte pixels are in `src_format`, reordering them to
+    /// `self.format` and, if `opaque`, setting their alpha on the way.
+    /// Other pixel widths are copied as they are.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn fill_from(&mut self, src: &[u8], src_format: PixelFormat, opaque: bool) {
+        self.data.clear();
+        if self.pixel_width != 4 || self.row_len == 0 || (src_format == self.format && !opaque) {
+            self.data.extend_from_slice(src);
+            return;
+        }
+        let perm = src_format.permutation_to(self.format);
+        let alpha = self.format.to_memory(Pixel {
+            b: 0,
+            g: 0,
+            r: 0,
+            a: 1,
+        });
+        let packed_len = self.width * 4;
+        self.data.resize(src.len(), 0);
+        for (dst, src) in self
+            .data
+            .chunks_mut(self.row_len)
+            .zip(src.chunks(self.row_len))
+        {
+            let (dst_pixels, dst_padding) = dst.split_at_mut(packed_len);
+            for (px, src_px) in dst_pixels.chunks_mut(4).zip(src.chunks(4)) {
+                for i in 0..4 {
+                    px[i] = if opaque && alpha[i] == 1 {
+                        255
+                    } else {
+                        src_px[perm[i]]
+                    };
+                }
             }
+            dst_padding.copy_from_slice(&src[packed_len..]);
         }
     }
-}
 
-impl AsRef<[u8]> for Screenshot {
+    /// Raw bitmap.
     #[inline]
-    fn as_ref<'a>(&'a self) -> &'a [u8] {
-        self.data.as_slice()
+    pub unsafe fn raw_data(&self) -> *const u8 {
+        self.data.as_ptr()
     }
-}
 
-pub type ScreenResult = Result<Screenshot, &'static str>;
+    /// Raw bitmap.
+    #[inline]
+    pub unsafe fn raw_data_mut(&mut self) -> *mut u8 {
+        self.data.as_mut_ptr()
+    }
 
-#[cfg(target_os = "linux")]
-mod ffi {
-    extern crate xlib;
+    /// Raw bitmap, including any row padding.
+    #[inline]
+    pub fn raw_data_slice(&self) -> &[u8] {
+        &self.data
+    }
 
-    use self::xlib::{
-        XAllPlanes, XCloseDisplay, XDestroyWindow, XGetImage, XGetWindowAttributes, XImage,
-        XOpenDisplay, XRootWindowOfScreen, XScreenOfDisplay, XWindowAttributes, ZPixmap,
-    };
-    use libc::{c_int, c_uint};
-    use std::mem;
-    use std::ptr::null_mut;
-    use std::slice;
-    use {ScreenResult, Screenshot};
+    /// Raw bitmap, including any row padding.
+    #[inline]
+    pub fn raw_data_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
 
-    pub fn get_screenshot(screen: u32) -> ScreenResult {
-        unsafe {
-            let display = XOpenDisplay(null_mut());
-            let screen = XScreenOfDisplay(display, screen as c_int);
-            let root = XRootWindowOfScreen(screen);
+    /// Number of bytes in bitmap
+    #[inline]
+    pub fn raw_len(&self) -> usize {
+        self.data.len() * size_of::<u8>()
+    }
 
-            let mut attr: XWindowAttributes = mem::uninitialized();
-            XGetWindowAttributes(display, root, &mut attr);
+    /// Removes any padding at the end of each row, so that `row_len()` equals
+    /// `width() * pixel_width()`.
+    pub fn compact(&mut self) {
+        let packed_len = self.width * self.pixel_width;
+        if self.row_len == packed_len {
+            return;
+        }
+        for row in 0..self.height {
+            let start = row * self.row_len;
+            self.data
+                .copy_within(start..start + packed_len, row * packed_len);
+        }
+        self.data.truncate(packed_len * self.height);
+        self.row_len = packed_len;
+    }
 
-            let mut img = &mut *XGetImage(
-                display,
-                root,
-                0,
-                0,
-                attr.width as c_uint,
-                attr.height as c_uint,
-                XAllPlanes(),
-                ZPixmap,
-            );
-            XDestroyWindow(display, root);
-            XCloseDisplay(display);
-            // This is the function which XDestroyImage macro calls.
-            // servo/rust-xlib doesn't handle function pointers correctly.
-            // We have to transmute the variable.
-            let destroy_image: extern "C" fn(*mut XImage) -> c_int =
-                mem::transmute(img.f.destroy_image);
-            let height = img.height as usize;
-            let width = img.width as usize;
-            let row_len = img.bytes_per_line as usize;
-            let pixel_bits = img.bits_per_pixel as usize;
-            if pixel_bits % 8 != 0 {
-                destroy_image(&mut *img);
-                return Err("Pixels aren't integral bytes.");
-            }
-            let pixel_width = pixel_bits / 8;
-
-            // Create a Vec for image
-            let size = width * height * pixel_width;
-            let mut data = slice::from_raw_parts(img.data as *mut u8, size as usize).to_vec();
-            destroy_image(&mut *img);
-
-            // Fix Alpha channel when xlib cannot retrieve info correctly
-            let has_alpha = data.iter().enumerate().any(|(n, x)| n % 4 == 3 && *x != 0);
-            if !has_alpha {
-                let mut n = 0;
-                for channel in &mut data {
-                    if n % 4 == 3 {
-                        *channel = 255;
-                    }
-                    n += 1;
-                }
+    /// Drop the alpha byte of each pixel, leaving tightly packed 3-byte BGR
+    /// pixels that read as opaque, to save a quarter of the memory of
+    /// opaque captures. The buffer is shrunk to fit.
+    ///
+    /// Colors are kept as they are, so unpremultiply premultiplied captures
+    /// first. Only 4-byte pixels are changed.
+    pub fn strip_alpha(&mut self) {
+        if self.pixel_width != 4 {
+            return;
+        }
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let src = row * self.row_len + col * 4;
+                let p = self.read_pixel(&self.data[src..src + 4]);
+                let dst = (row * self.width + col) * 3;
+                self.data[dst..dst + 3].copy_from_slice(&[p.b, p.g, p.r]);
             }
+        }
+        self.data.truncate(self.width * self.height * 3);
+        self.data.shrink_to_fit();
+        self.pixel_width = 3;
+        self.row_len = self.width * 3;
+        self.format = PixelFormat::Bgra;
+        self.alpha_mode = AlphaMode::Opaque;
+    }
 
-            Ok(Screenshot {
-                data,
-                height,
-                width,
-                row_len,
-                pixel_width,
-            })
+    /// Undo `strip_alpha`, giving 3-byte pixels an alpha byte of `alpha`, in
+    /// `Bgra` order with no row padding. Other pixel widths are left alone.
+    pub fn add_alpha(&mut self, alpha: u8) {
+        if self.pixel_width != 3 {
+            return;
         }
+        self.data = fill_rows(self.height, self.width * 4, |row, out| {
+            for (px, bgr) in out.chunks_mut(4).zip(self.row_pixels(row).chunks(3)) {
+                px.copy_from_slice(&[bgr[0], bgr[1], bgr[2], alpha]);
+            }
+        });
+        self.pixel_width = 4;
+        self.row_len = self.width * 4;
+        self.format = PixelFormat::Bgra;
+        self.alpha_mode = if alpha == 255 {
+            AlphaMode::Opaque
+        } else {
+            AlphaMode::Straight
+        };
     }
-}
 
-#[cfg(target_os = "macos")]
-mod ffi {
-    #![allow(non_upper_case_globals, dead_code)]
+    /// The bitmap as a slice of 4-byte pixels, for SIMD-friendly processing.
+    ///
+    /// Returns `None` unless pixels are 4 bytes wide and rows carry no
+    /// padding; call `compact()` first to remove padding. The returned slice
+    /// has exactly `width() * height()` elements in row-major order, each in
+    /// the in-memory channel order (B, G, R, A). Only byte alignment is
+    /// guaranteed, so don't reinterpret it as `&[u32]` without checking.
+    pub fn chunks_exact_pixels(&self) -> Option<&[[u8; 4]]> {
+        if self.pixel_width != 4 || self.row_len != self.width * 4 {
+            return None;
+        }
+        let len = self.width * self.height;
+        if self.data.len() < len * 4 {
+            return None;
+        }
+        // [u8; 4] has the same alignment as u8, and the length was checked.
+        unsafe {
+            Some(std::slice::from_raw_parts(
+                self.data.as_ptr() as *const [u8; 4],
+                len,
+            ))
+        }
+    }
 
-    use libc;
-    use std::slice;
-    use ScreenResult;
-    use Screenshot;
+    /// Iterate over the rows, top to bottom, excluding any padding.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.height).map(move |row| self.row_pixels(row))
+    }
 
-    type CFIndex = libc::c_long;
-    type CFDataRef = *const u8; // *const CFData
+    /// Move the screenshot behind a reference count so it can be handed to
+    /// several consumers without copying the pixels.
+    pub fn shared(self) -> SharedScreenshot {
+        SharedScreenshot {
+            inner: Arc::new(self),
+        }
+    }
 
-    #[cfg(target_arch = "x86")]
-    type CGFloat = libc::c_float;
-    #[cfg(target_arch = "x86_64")]
-    type CGFloat = libc::c_double;
-    type CGError = libc::int32_t;
+    /// The bytes of one row, excluding any padding.
+    fn row_pixels(&self, row: usize) -> &[u8] {
+        let start = row * self.row_len;
+        &self.data[start..start + self.width * self.pixel_width]
+    }
 
-    type CGDirectDisplayID = libc::uint32_t;
-    type CGDisplayCount = libc::uint32_t;
-    type CGImageRef = *mut u8;
-    // *mut CGImage
-    type CGDataProviderRef = *mut u8; // *mut CGDataProvider
+    /// An opaque black image.
+    #[cfg(target_os = "linux")]
+    fn black(width: usize, height: usize, pixel_width: usize) -> Screenshot {
+        let mut pixel = vec![0; pixel_width];
+        if pixel_width > 3 {
+            pixel[3] = 0xff;
+        }
+        Screenshot {
+            data: pixel.repeat(width * height),
+            height,
+            width,
+            row_len: width * pixel_width,
+            pixel_width,
+            color_space: None,
+            format: PixelFormat::Bgra,
+            bits_per_component: 8,
+            captured_at: SystemTime::now(),
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
 
-    const kCGErrorSuccess: CGError = 0;
-    const kCGErrorFailure: CGError = 1000;
-    const CGDisplayNoErr: CGError = kCGErrorSuccess;
+    /// Copy `src` over this image with its top-left corner at (`row`, `col`),
+    /// clipping whatever falls outside. Both must have the same pixel width.
+    #[cfg(target_os = "linux")]
+    fn paste(&mut self, src: &Screenshot, row: usize, col: usize) {
+        if row >= self.height || col >= self.width {
+            return;
+        }
+        let rows = cmp::min(src.height, self.height - row);
+        let len = cmp::min(src.width, self.width - col) * self.pixel_width;
+        for r in 0..rows {
+            let start = (row + r) * self.row_len + col * self.pixel_width;
+            self.data[start..start + len].copy_from_slice(&src.row_pixels(r)[..len]);
+        }
+    }
 
-    #[link(name = "CoreGraphics", kind = "framework")]
-    extern "C" {
-        fn CGGetActiveDisplayList(
-            max_displays: libc::uint32_t,
-            active_displays: *mut CGDirectDisplayID,
-            display_count: *mut CGDisplayCount,
-        ) -> CGError;
-        fn CGDisplayCreateImage(displayID: CGDirectDisplayID) -> CGImageRef;
-        fn CGImageRelease(image: CGImageRef);
+    /// Writes one row into `out` as RGBA bytes.
+    fn rgba_row(&self, row: usize, out: &mut [u8]) {
+        let pixels = self.row_pixels(row).chunks(self.pixel_width);
+        for (px, rgba) in pixels.zip(out.chunks_mut(4)) {
+            let p = self.read_pixel(px);
+            rgba.copy_from_slice(&[p.r, p.g, p.b, p.a]);
+        }
+    }
 
-        fn CGImageGetBitsPerComponent(image: CGImageRef) -> libc::size_t;
-        fn CGImageGetBitsPerPixel(image: CGImageRef) -> libc::size_t;
-        fn CGImageGetBytesPerRow(image: CGImageRef) -> libc::size_t;
-        fn CGImageGetDataProvider(image: CGImageRef) -> CGDataProviderRef;
-        fn CGImageGetHeight(image: CGImageRef) -> libc::size_t;
-        fn CGImageGetWidth(image: CGImageRef) -> libc::size_t;
+    /// Tightly-packed RGBA copy of the bitmap.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        fill_rows(self.height, self.width * 4, |row, out| {
+            self.rgba_row(row, out)
+        })
+    }
 
-        fn CGDataProviderCopyData(provider: CGDataProviderRef) -> CFDataRef;
+    /// One luma byte per pixel, using the BT.601 weights, with no padding.
+    pub fn to_grayscale(&self) -> Vec<u8> {
+        fill_rows(self.height, self.width, |row, out| {
+            let pixels = self.row_pixels(row).chunks(self.pixel_width);
+            for (px, gray) in pixels.zip(out.iter_mut()) {
+                let p = self.read_pixel(px);
+                *gray = luma(p.r, p.g, p.b);
+            }
+        })
     }
 
-    #[link(name = "CoreFoundation", kind = "framework")]
-    extern "C" {
-        fn CFDataGetLength(theData: CFDataRef) -> CFIndex;
-        fn CFDataGetBytePtr(theData: CFDataRef) -> *const u8;
-        fn CFRelease(cf: *const libc::c_void);
+    /// Count of pixels at each BT.601 luma value, following the pixel format.
+    pub fn luminance_histogram(&self) -> [u32; 256] {
+        let mut histogram = [0u32; 256];
+        for row in 0..self.height {
+            for px in self.row_pixels(row).chunks(self.pixel_width) {
+                let p = self.read_pixel(px);
+                histogram[luma(p.r, p.g, p.b) as usize] += 1;
+            }
+        }
+        histogram
     }
 
-    /// Get a screenshot of the requested display.
-    pub fn get_screenshot(screen: usize) -> ScreenResult {
-        unsafe {
-            // Get number of displays
-            let mut count: CGDisplayCount = 0;
-            let mut err = CGDisplayNoErr;
-            err = CGGetActiveDisplayList(0, 0 as *mut CGDirectDisplayID, &mut count);
-            if err != CGDisplayNoErr {
-                return Err("Error getting number of displays.");
+    /// Stretch the red, green and blue channels so that the luma range left
+    /// after clipping `clip_percent` percent of the pixels from each end of
+    /// the histogram maps to 0–255, leaving alpha alone. Does nothing if that
+    /// range is a single value.
+    pub fn auto_contrast(&mut self, clip_percent: f32) {
+        let histogram = self.luminance_histogram();
+        let total: u64 = histogram.iter().map(|&n| n as u64).sum();
+        let clip = (total as f64 * clip_percent.clamp(0.0, 50.0) as f64 / 100.0) as u64;
+        let percentile = |counts: &mut dyn Iterator<Item = (usize, &u32)>| {
+            let mut seen = 0;
+            for (i, &n) in counts {
+                seen += n as u64;
+                if seen > clip {
+                    return i;
+                }
             }
+            0
+        };
+        let low = percentile(&mut histogram.iter().enumerate());
+        let high = percentile(&mut histogram.iter().enumerate().rev());
+        if high <= low {
+            return;
+        }
+        let mut lut = [0u8; 256];
+        for (i, out) in lut.iter_mut().enumerate() {
+            let i = i.clamp(low, high);
+            *out = (((i - low) * 255 + (high - low) / 2) / (high - low)) as u8;
+        }
+        self.map_pixels(|p| Pixel {
+            r: lut[p.r as usize],
+            g: lut[p.g as usize],
+            b: lut[p.b as usize],
+            a: p.a,
+        });
+    }
 
-            // Get list of displays
-            let mut disps: Vec<CGDisplayCount> = Vec::with_capacity(count as usize);
-            disps.set_len(count as usize);
-            err = CGGetActiveDisplayList(
-                disps.len() as libc::uint32_t,
-                &mut disps[0] as *mut CGDirectDisplayID,
-                &mut count,
-            );
-            if err != CGDisplayNoErr {
-                return Err("Error getting list of displays.");
+    /// Convert to planar I420 (YUV 4:2:0) for video encoders, returning the
+    /// Y, U and V planes without padding. Colors are converted with BT.601
+    /// in limited range (Y from 16 to 235), and each U and V sample is the
+    /// average of a 2 x 2 block of pixels.
+    ///
+    /// The chroma planes are half the width and height, rounded up, so for
+    /// odd sizes the last column or row of chroma covers a single pixel.
+    pub fn to_i420(&self) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let (width, height) = (self.width, self.height);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let pixel = |row: usize, col: usize| {
+            let start = col * self.pixel_width;
+            self.read_pixel(&self.row_pixels(row)[start..start + self.pixel_width])
+        };
+        let y = fill_rows(height, width, |row, out| {
+            for (col, y) in out.iter_mut().enumerate() {
+                let p = pixel(row, col);
+                let (r, g, b) = (p.r as i32, p.g as i32, p.b as i32);
+                *y = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            }
+        });
+        let mut u = vec![0; chroma_width * chroma_height];
+        let mut v = vec![0; chroma_width * chroma_height];
+        for row in 0..chroma_height {
+            for col in 0..chroma_width {
+                let (mut r, mut g, mut b, mut n) = (0, 0, 0, 0);
+                for y in 2 * row..cmp::min(2 * row + 2, height) {
+                    for x in 2 * col..cmp::min(2 * col + 2, width) {
+                        let p = pixel(y, x);
+                        r += p.r as i32;
+                        g += p.g as i32;
+                        b += p.b as i32;
+                        n += 1;
+                    }
+                }
+                let (r, g, b) = ((r + n / 2) / n, (g + n / 2) / n, (b + n / 2) / n);
+                let i = row * chroma_width + col;
+                u[i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                v[i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
             }
+        }
+        (y, u, v)
+    }
 
-            // Get screenshot of requested display
-            let disp_id = disps[screen];
-            let cg_img = CGDisplayCreateImage(disp_id);
+    /// Render the screenshot as ASCII art `target_width` characters wide,
+    /// one line per row, for a quick look in a terminal or log. Rows are
+    /// halved to make up for characters being about twice as tall as they
+    /// are wide. Empty for an empty screenshot or a `target_width` of 0.
+    pub fn to_ascii_art(&self, target_width: usize) -> String {
+        const RAMP: &[u8] = b" .:-=+*#%@";
+        if self.width == 0 {
+            return String::new();
+        }
+        let height = ((self.height * target_width + self.width) / (2 * self.width)).max(1);
+        let small = match self.resize(target_width, height) {
+            Ok(small) => small,
+            Err(_) => return String::new(),
+        };
+        let mut art = String::with_capacity((target_width + 1) * small.height);
+        for line in small.to_grayscale().chunks(target_width) {
+            for &gray in line {
+                art.push(RAMP[gray as usize * RAMP.len() / 256] as char);
+            }
+            art.push('\n');
+        }
+        art
+    }
 
-            // Get info about image
-            let width = CGImageGetWidth(cg_img) as usize;
-            let height = CGImageGetHeight(cg_img) as usize;
-            let row_len = CGImageGetBytesPerRow(cg_img) as usize;
-            let pixel_bits = CGImageGetBitsPerPixel(cg_img) as usize;
-            if pixel_bits % 8 != 0 {
-                return Err("Pixels aren't integral bytes.");
+    /// Copy one channel into a tightly packed `width * height` buffer.
+    pub fn extract_channel(&self, channel: Channel) -> Vec<u8> {
+        fill_rows(self.height, self.width, |row, out| {
+            let pixels = self.row_pixels(row).chunks(self.pixel_width);
+            for (px, value) in pixels.zip(out.iter_mut()) {
+                *value = channel.of(self.read_pixel(px));
             }
+        })
+    }
 
-            // Copy image into a Vec buffer
-            let cf_data = CGDataProviderCopyData(CGImageGetDataProvider(cg_img));
-            let raw_len = CFDataGetLength(cf_data) as usize;
+    /// Fraction of pixels whose alpha is nonzero, from 0.0 to 1.0. Captures
+    /// with an all-zero alpha channel, which X servers often give, score 0;
+    /// the Linux backend makes those opaque. Screenshots without an alpha
+    /// byte count as opaque, and empty ones score 0.
+    pub fn nonzero_alpha_ratio(&self) -> f32 {
+        let total = self.width * self.height;
+        if total == 0 {
+            return 0.0;
+        }
+        if self.pixel_width < 4 {
+            return 1.0;
+        }
+        // Alpha is one component of 1 or 2 bytes.
+        let bytes = self.pixel_width / 4;
+        let alpha = self.format.alpha_offset() * bytes;
+        let nonzero: usize = (0..self.height)
+            .map(|row| {
+                self.row_pixels(row)
+                    .chunks(self.pixel_width)
+                    .filter(|px| px[alpha..alpha + bytes].iter().any(|&b| b != 0))
+                    .count()
+            })
+            .sum();
+        nonzero as f32 / total as f32
+    }
 
-            let res = if width * height * pixel_bits != raw_len * 8 {
-                Err("Image size is inconsistent with W*H*D.")
-            } else {
-                let data = slice::from_raw_parts(CFDataGetBytePtr(cf_data), raw_len).to_vec();
-                Ok(Screenshot {
-                    data,
-                    height,
-                    width,
-                    row_len,
-                    pixel_width: pixel_bits / 8,
+    /// Whether at least `fraction` (0.0 to 1.0) of the pixels have red, green
+    /// and blue each within `tolerance` of `color`'s. Alpha is ignored.
+    ///
+    /// Stops scanning as soon as the answer is known.
+    pub fn is_mostly(&self, color: Pixel, fraction: f32, tolerance: u8) -> bool {
+        let total = self.width * self.height;
+        let needed = (fraction.clamp(0.0, 1.0) * total as f32).ceil() as usize;
+        let mut matched = 0;
+        let mut seen = 0;
+        for row in 0..self.height {
+            for px in self.row_pixels(row).chunks(self.pixel_width) {
+                if matched >= needed {
+                    return true;
+                }
+                if matched + (total - seen) < needed {
+                    return false;
+                }
+                if near_color(self.read_pixel(px), color, tolerance) {
+                    matched += 1;
+                }
+                seen += 1;
+            }
+        }
+        matched >= needed
+    }
+
+    /// Crop away the rows and columns along each edge whose pixels all have
+    /// red, green and blue within `tolerance` of `color`'s. An image that
+    /// matches everywhere trims down to 0x0.
+    pub fn trim_border(&self, color: Pixel, tolerance: u8) -> Screenshot {
+        match self.content_bounds(color, tolerance) {
+            Some((x, y, width, height)) => self
+                .crop(x, y, width, height)
+                .expect("trimmed region lies inside the image"),
+            None => self.with_pixels(Vec::new(), 0, 0),
+        }
+    }
+
+    /// Like `trim_border`, but also returns the `(x, y)` of the content's
+    /// top-left corner in this image. Fails if every pixel matches
+    /// `background`, leaving no content.
+    pub fn crop_to_content(
+        &self,
+        background: Pixel,
+        tolerance: u8,
+    ) -> Result<(Screenshot, usize, usize), CaptureError> {
+        match self.content_bounds(background, tolerance) {
+            Some((x, y, width, height)) => Ok((self.crop(x, y, width, height)?, x, y)),
+            None => Err(CaptureError::InvalidArgument("Image is all background.")),
+        }
+    }
+
+    /// The `(x, y, width, height)` left by `trim_border`, or `None` if it
+    /// trims everything.
+    fn content_bounds(&self, color: Pixel, tolerance: u8) -> Option<Region> {
+        let pixel_width = self.pixel_width;
+        let row_matches = |row: usize| {
+            self.row_pixels(row)
+                .chunks(pixel_width)
+                .all(|px| near_color(self.read_pixel(px), color, tolerance))
+        };
+        let top = (0..self.height).find(|&row| !row_matches(row))?;
+        let bottom = (top..self.height)
+            .rev()
+            .find(|&row| !row_matches(row))
+            .unwrap()
+            + 1;
+        let col_matches = |col: usize| {
+            (top..bottom).all(|row| {
+                let start = col * pixel_width;
+                let px = &self.row_pixels(row)[start..start + pixel_width];
+                near_color(self.read_pixel(px), color, tolerance)
+            })
+        };
+        // Some row in [top, bottom) has a non-matching pixel, so both exist.
+        let left = (0..self.width).find(|&col| !col_matches(col)).unwrap();
+        let right = (left..self.width)
+            .rev()
+            .find(|&col| !col_matches(col))
+            .unwrap()
+            + 1;
+        Some((left, top, right - left, bottom - top))
+    }
+
+    /// Check that this looks like a whole-display capture of `screen`, as
+    /// from `get_screenshot(screen)`: that the buffer holds every row it
+    /// claims to, and that the size matches the display's current
+    /// `DisplayInfo`.
+    ///
+    /// On macOS the capture may be any whole multiple of the display's size
+    /// in points, up to 4x, since Retina displays capture at their pixel
+    /// size; elsewhere the sizes must match. On Windows the capture is
+    /// compared with the whole virtual desktop, which is what
+    /// `get_screenshot` grabs there. A mismatch, such as a capture half the
+    /// display's width from a DPI-unaware process, is a `SizeMismatch`.
+    pub fn validate_against_display(&self, screen: usize) -> Result<(), CaptureError> {
+        if self.row_len < self.width * self.pixel_width
+            || (self.height > 0
+                && self.data.len()
+                    < (self.height - 1) * self.row_len + self.width * self.pixel_width)
+        {
+            return Err(CaptureError::Native(
+                "Buffer is smaller than the image it holds.",
+            ));
+        }
+        let display = captured_area_size(screen)?;
+        let max_scale = if cfg!(target_os = "macos") { 4 } else { 1 };
+        let fits = (1..=max_scale)
+            .any(|scale| (self.width, self.height) == (display.0 * scale, display.1 * scale));
+        if fits {
+            Ok(())
+        } else {
+            Err(CaptureError::SizeMismatch {
+                expected: display,
+                actual: (self.width, self.height),
+            })
+        }
+    }
+
+    /// Whether `other` has the same width and height. Methods comparing two
+    /// images, like `subtract`, need this and fail with
+    /// `CaptureError::SizeMismatch` otherwise.
+    pub fn is_same_size(&self, other: &Screenshot) -> bool {
+        (self.width, self.height) == (other.width, other.height)
+    }
+
+    /// Fail with `SizeMismatch` unless `other` is the same size.
+    fn check_same_size(&self, other: &Screenshot) -> Result<(), CaptureError> {
+        if self.is_same_size(other) {
+            Ok(())
+        } else {
+            Err(CaptureError::SizeMismatch {
+                expected: (self.width, self.height),
+                actual: (other.width, other.height),
+            })
+        }
+    }
+
+    /// One 64-bit checksum per row, over its pixels without padding. Rows
+    /// whose checksums differ between two frames of the same layout have
+    /// changed, so comparing these narrows pixel-level work to those rows.
+    ///
+    /// The hash is fast rather than collision-resistant, and gives the same
+    /// values on every run and platform.
+    pub fn row_checksums(&self) -> Vec<u64> {
+        (0..self.height)
+            .map(|row| row_checksum(self.row_pixels(row)))
+            .collect()
+    }
+
+    /// The `(x, y, width, height)` of the smallest rectangle containing every
+    /// pixel that differs from `other` by more than `tolerance` in any byte.
+    ///
+    /// Returns `None` if nothing changed, or if the two aren't the same size
+    /// and pixel width.
+    pub fn bounding_box_of_change(
+        &self,
+        other: &Screenshot,
+        tolerance: u8,
+    ) -> Option<(usize, usize, usize, usize)> {
+        if !self.is_same_size(other) || self.pixel_width != other.pixel_width {
+            return None;
+        }
+        let (mut left, mut top, mut right, mut bottom) = (self.width, self.height, 0, 0);
+        for row in 0..self.height {
+            let pixels = self.row_pixels(row).chunks(self.pixel_width);
+            let other_pixels = other.row_pixels(row).chunks(self.pixel_width);
+            for (col, (a, b)) in pixels.zip(other_pixels).enumerate() {
+                let changed = a
+                    .iter()
+                    .zip(b)
+                    .any(|(&a, &b)| (a as i16 - b as i16).abs() > tolerance as i16);
+                if changed {
+                    left = cmp::min(left, col);
+                    right = cmp::max(right, col + 1);
+                    top = cmp::min(top, row);
+                    bottom = row + 1;
+                }
+            }
+        }
+        if left < right {
+            Some((left, top, right - left, bottom - top))
+        } else {
+            None
+        }
+    }
+
+    /// The absolute difference between this image and `other`, channel by
+    /// channel, so that whatever changed shows up bright on black. Alpha is
+    /// set opaque rather than differenced, so the result can be viewed.
+    ///
+    /// Fails unless both are the same size and pixel layout, with 8-bit
+    /// components.
+    pub fn subtract(&self, other: &Screenshot) -> Result<Screenshot, CaptureError> {
+        self.check_same_size(other)?;
+        self.check_same_layout(other)?;
+        let pixel_width = self.pixel_width;
+        let alpha = match pixel_width {
+            4 => Some(self.format.alpha_offset()),
+            _ => None,
+        };
+        let data = fill_rows(self.height, self.width * pixel_width, |row, out| {
+            let pairs = self.row_pixels(row).iter().zip(other.row_pixels(row));
+            for (i, (o, (&a, &b))) in out.iter_mut().zip(pairs).enumerate() {
+                *o = if Some(i % pixel_width) == alpha {
+                    255
+                } else {
+                    (a as i16 - b as i16).unsigned_abs() as u8
+                };
+            }
+        });
+        let mut diff = self.with_pixels(data, self.width, self.height);
+        diff.alpha_mode = AlphaMode::Opaque;
+        Ok(diff)
+    }
+
+    /// The mean of the squared differences between this image and `other`
+    /// over the red, green and blue channels; alpha is ignored. The two may
+    /// differ in pixel format, but fail with `SizeMismatch` unless they're
+    /// the same size.
+    pub fn mean_squared_error(&self, other: &Screenshot) -> Result<f64, CaptureError> {
+        self.check_same_size(other)?;
+        let mut sum = 0u64;
+        for row in 0..self.height {
+            let pixels = self.row_pixels(row).chunks(self.pixel_width);
+            let others = other.row_pixels(row).chunks(other.pixel_width);
+            for (a, b) in pixels.zip(others) {
+                let (a, b) = (self.read_pixel(a), other.read_pixel(b));
+                for &(x, y) in &[(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+                    let d = x.abs_diff(y) as u64;
+                    sum += d * d;
+                }
+            }
+        }
+        let samples = self.width * self.height * 3;
+        if samples == 0 {
+            return Ok(0.0);
+        }
+        Ok(sum as f64 / samples as f64)
+    }
+
+    /// Peak signal-to-noise ratio against `other`, in decibels, from
+    /// `mean_squared_error`. Higher is closer; identical images give
+    /// infinity.
+    pub fn psnr(&self, other: &Screenshot) -> Result<f64, CaptureError> {
+        let mse = self.mean_squared_error(other)?;
+        Ok(10.0 * (255.0 * 255.0 / mse).log10())
+    }
+
+    /// Edge map from the Sobel operator on the grayscale image: each pixel is
+    /// the gradient magnitude there, clamped to 255, in all three color
+    /// channels, with opaque alpha. Pixels past the borders are taken to
+    /// repeat the edge ones.
+    pub fn edges_sobel(&self) -> Screenshot {
+        let (width, height) = (self.width, self.height);
+        let gray = self.to_grayscale();
+        let at = |x: isize, y: isize| {
+            let x = x.clamp(0, width as isize - 1) as usize;
+            let y = y.clamp(0, height as isize - 1) as usize;
+            gray[y * width + x] as i32
+        };
+        let data = fill_rows(height, width * 4, |row, out| {
+            let y = row as isize;
+            for (col, px) in out.chunks_mut(4).enumerate() {
+                let x = col as isize;
+                let gx = at(x + 1, y - 1) + 2 * at(x + 1, y) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1)
+                    - 2 * at(x - 1, y)
+                    - at(x - 1, y + 1);
+                let gy = at(x - 1, y + 1) + 2 * at(x, y + 1) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1)
+                    - 2 * at(x, y - 1)
+                    - at(x + 1, y - 1);
+                let edge = ((gx * gx + gy * gy) as f64).sqrt().min(255.0) as u8;
+                px.copy_from_slice(&[edge, edge, edge, 255]);
+            }
+        });
+        Screenshot {
+            data,
+            height,
+            width,
+            row_len: width * 4,
+            pixel_width: 4,
+            color_space: self.color_space.clone(),
+            format: PixelFormat::Bgra,
+            bits_per_component: 8,
+            captured_at: self.captured_at,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+
+    /// How many pixels differ from `other`'s by more than `tolerance` in
+    /// some channel, or `None` if the images aren't the same size and pixel
+    /// width.
+    fn changed_pixels(&self, other: &Screenshot, tolerance: u8) -> Option<usize> {
+        if !self.is_same_size(other) || self.pixel_width != other.pixel_width {
+            return None;
+        }
+        let mut changed = 0;
+        for row in 0..self.height {
+            let pixels = self.row_pixels(row).chunks(self.pixel_width);
+            let other_pixels = other.row_pixels(row).chunks(self.pixel_width);
+            changed += pixels
+                .zip(other_pixels)
+                .filter(|&(a, b)| {
+                    a.iter()
+                        .zip(b)
+                        .any(|(&a, &b)| (a as i16 - b as i16).abs() > tolerance as i16)
                 })
+                .count();
+        }
+        Some(changed)
+    }
+
+    /// Whether the `width` x `height` rectangle at (`x`, `y`) is the same in
+    /// both images, allowing each channel to differ by `tolerance`.
+    ///
+    /// Fails if the rectangle isn't inside both images or their pixels are
+    /// laid out differently.
+    pub fn eq_region(
+        &self,
+        other: &Screenshot,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        tolerance: u8,
+    ) -> Result<bool, CaptureError> {
+        let fits = |s: &Screenshot| {
+            x.checked_add(width).is_some_and(|r| r <= s.width)
+                && y.checked_add(height).is_some_and(|b| b <= s.height)
+        };
+        if !fits(self) || !fits(other) {
+            return Err(CaptureError::InvalidArgument(
+                "Region must be inside both images.",
+            ));
+        }
+        if (self.pixel_width, self.format, self.bits_per_component)
+            != (other.pixel_width, other.format, other.bits_per_component)
+        {
+            return Err(CaptureError::InvalidArgument(
+                "Images must have the same pixel layout.",
+            ));
+        }
+        let (start, end) = (x * self.pixel_width, (x + width) * self.pixel_width);
+        for row in y..y + height {
+            let a = &self.row_pixels(row)[start..end];
+            let b = &other.row_pixels(row)[start..end];
+            if a.iter()
+                .zip(b)
+                .any(|(&a, &b)| (a as i16 - b as i16).abs() > tolerance as i16)
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Encode the screenshot into `w`. PNG, BMP and PPM are encoded a row at
+    /// a time, without building the whole file in memory first.
+    pub fn write_to<W: Write>(&self, w: W, format: ImageFormat) -> Result<(), CaptureError> {
+        match format {
+            #[cfg(feature = "png")]
+            ImageFormat::Png => self.write_png(w),
+            ImageFormat::Bmp => self.write_bmp(w),
+            #[cfg(feature = "jpeg")]
+            ImageFormat::Jpeg => self.write_jpeg(w),
+            ImageFormat::Qoi => self.write_qoi(w),
+            ImageFormat::Ppm => self.write_ppm(w),
+        }
+    }
+
+    /// Save the screenshot to `path`, picking the format from its extension:
+    /// `png`, `bmp`, `jpg`/`jpeg`, `qoi` or `ppm`. Extensions whose encoder
+    /// feature is off give `CaptureError::UnsupportedFormat`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CaptureError> {
+        let path = path.as_ref();
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let format = match ImageFormat::from_extension(ext) {
+            Some(format) => format,
+            None => return Err(CaptureError::UnsupportedFormat(ext.to_string())),
+        };
+        let mut w = io::BufWriter::new(File::create(path)?);
+        self.write_to(&mut w, format)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Encode the screenshot as a PNG file.
+    #[cfg(feature = "png")]
+    pub fn to_png_bytes(&self) -> Result<Vec<u8>, CaptureError> {
+        let mut buf = Vec::new();
+        self.write_png(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Encode the screenshot as a PNG `data:` URL,
+    /// `data:image/png;base64,...`, for embedding in HTML or Markdown.
+    #[cfg(feature = "png")]
+    pub fn to_data_url(&self) -> Result<String, CaptureError> {
+        self.to_data_url_as(ImageFormat::Png)
+    }
+
+    /// Encode the screenshot as a `data:` URL in `format`; see
+    /// `to_data_url`.
+    pub fn to_data_url_as(&self, format: ImageFormat) -> Result<String, CaptureError> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf, format)?;
+        let mut url = format!("data:{};base64,", format.mime_type());
+        base64_encode(&buf, &mut url);
+        Ok(url)
+    }
+
+    /// Encode the screenshot as a BMP file.
+    pub fn to_bmp_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_bmp(&mut buf)
+            .expect("writing to a Vec can't fail");
+        buf
+    }
+
+    /// Encode the screenshot as a QOI file.
+    pub fn to_qoi_bytes(&self) -> Result<Vec<u8>, CaptureError> {
+        let mut buf = Vec::new();
+        self.write_qoi(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Encode the screenshot as a binary (P6) PPM file, for debug dumps.
+    pub fn to_ppm_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_ppm(&mut buf)
+            .expect("writing to a Vec can't fail");
+        buf
+    }
+
+    /// Put the screenshot on the system clipboard: as a `CF_DIB` bitmap on
+    /// Windows, and as PNG on macOS and X11. Requires the `clipboard` feature.
+    ///
+    /// X11 applications serve their own clipboard contents, so on Linux this
+    /// starts a background thread that answers paste requests until another
+    /// application takes over the clipboard. The image is gone once the
+    /// process exits.
+    #[cfg(feature = "clipboard")]
+    pub fn copy_to_clipboard(&self) -> Result<(), CaptureError> {
+        ffi::copy_to_clipboard(self)
+    }
+
+    /// Encode the screenshot as a JPEG file.
+    #[cfg(feature = "jpeg")]
+    pub fn to_jpeg_bytes(&self) -> Result<Vec<u8>, CaptureError> {
+        let mut buf = Vec::new();
+        self.write_jpeg(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[cfg(feature = "png")]
+    fn write_png<W: Write>(&self, w: W) -> Result<(), CaptureError> {
+        fn encode_err(err: png::EncodingError) -> CaptureError {
+            CaptureError::Encode(err.to_string())
+        }
+
+        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(encode_err)?;
+        let mut stream = writer.stream_writer().map_err(encode_err)?;
+        let mut row_buf = vec![0; self.width * 4];
+        for row in 0..self.height {
+            self.rgba_row(row, &mut row_buf);
+            stream.write_all(&row_buf)?;
+        }
+        stream.finish().map_err(encode_err)
+    }
+
+    fn write_bmp<W: Write>(&self, mut w: W) -> Result<(), CaptureError> {
+        const HEADERS_LEN: u32 = 14 + 40;
+        // 16-bit components are narrowed, since BI_RGB has no 64-bpp pixels.
+        let bmp_pixel_width = cmp::min(self.pixel_width, 4);
+        // BMP rows are padded to a multiple of 4 bytes.
+        let packed_len = self.width * bmp_pixel_width;
+        let padding = (4 - packed_len % 4) % 4;
+        let image_len = ((packed_len + padding) * self.height) as u32;
+
+        // BITMAPFILEHEADER
+        w.write_all(b"BM")?;
+        w.write_all(&(HEADERS_LEN + image_len).to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&HEADERS_LEN.to_le_bytes())?;
+        // BITMAPINFOHEADER, with a negative height for top-down rows
+        w.write_all(&40u32.to_le_bytes())?;
+        w.write_all(&(self.width as i32).to_le_bytes())?;
+        w.write_all(&(-(self.height as i32)).to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&(bmp_pixel_width as u16 * 8).to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?; // BI_RGB
+        w.write_all(&image_len.to_le_bytes())?;
+        w.write_all(&2835i32.to_le_bytes())?; // 72 DPI
+        w.write_all(&2835i32.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+
+        // BI_RGB pixels are BGR(A) whatever the capture's format.
+        let reorder =
+            self.pixel_width == 8 || self.pixel_width == 4 && self.format != PixelFormat::Bgra;
+        let mut row_buf = vec![0; if reorder { packed_len } else { 0 }];
+        for row in 0..self.height {
+            if reorder {
+                let pixels = self.row_pixels(row).chunks(self.pixel_width);
+                for (px, out) in pixels.zip(row_buf.chunks_mut(4)) {
+                    out.copy_from_slice(&PixelFormat::Bgra.to_memory(self.read_pixel(px)));
+                }
+                w.write_all(&row_buf)?;
+            } else {
+                w.write_all(self.row_pixels(row))?;
+            }
+            w.write_all(&[0; 3][..padding])?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "jpeg")]
+    fn write_jpeg<W: Write>(&self, w: W) -> Result<(), CaptureError> {
+        if self.width > u16::MAX as usize || self.height > u16::MAX as usize {
+            return Err(CaptureError::Encode(
+                "JPEG images can't exceed 65535 pixels per side".to_string(),
+            ));
+        }
+        let encoder = jpeg_encoder::Encoder::new(w, JPEG_QUALITY);
+        encoder
+            .encode(
+                &self.to_rgba(),
+                self.width as u16,
+                self.height as u16,
+                jpeg_encoder::ColorType::Rgba,
+            )
+            .map_err(|err| CaptureError::Encode(err.to_string()))
+    }
+
+    fn write_ppm<W: Write>(&self, mut w: W) -> Result<(), CaptureError> {
+        write!(w, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let mut row_buf = vec![0; self.width * 3];
+        for row in 0..self.height {
+            let pixels = self.row_pixels(row).chunks(self.pixel_width);
+            for (px, rgb) in pixels.zip(row_buf.chunks_mut(3)) {
+                let p = self.read_pixel(px);
+                rgb.copy_from_slice(&[p.r, p.g, p.b]);
+            }
+            w.write_all(&row_buf)?;
+        }
+        Ok(())
+    }
+
+    fn write_qoi<W: Write>(&self, w: W) -> Result<(), CaptureError> {
+        if self.width > u32::MAX as usize || self.height > u32::MAX as usize {
+            return Err(CaptureError::Encode(
+                "QOI images can't exceed 2^32 - 1 pixels per side".to_string(),
+            ));
+        }
+        let mut w = io::BufWriter::new(w);
+        w.write_all(b"qoif")?;
+        w.write_all(&(self.width as u32).to_be_bytes())?;
+        w.write_all(&(self.height as u32).to_be_bytes())?;
+        w.write_all(&[4, 0])?; // RGBA, sRGB with linear alpha
+
+        let mut index = [[0u8; 4]; 64];
+        let mut prev = [0, 0, 0, 255];
+        let mut run = 0u8;
+        let mut row_buf = vec![0; self.width * 4];
+        for row in 0..self.height {
+            self.rgba_row(row, &mut row_buf);
+            for px in row_buf.chunks(4) {
+                let px = [px[0], px[1], px[2], px[3]];
+                if px == prev {
+                    run += 1;
+                    if run == 62 {
+                        w.write_all(&[0xc0 | (run - 1)])?;
+                        run = 0;
+                    }
+                    continue;
+                }
+                if run > 0 {
+                    w.write_all(&[0xc0 | (run - 1)])?;
+                    run = 0;
+                }
+
+                let hash = (px[0] as usize * 3
+                    + px[1] as usize * 5
+                    + px[2] as usize * 7
+                    + px[3] as usize * 11)
+                    % 64;
+                if index[hash] == px {
+                    w.write_all(&[hash as u8])?;
+                } else if px[3] != prev[3] {
+                    w.write_all(&[0xff, px[0], px[1], px[2], px[3]])?;
+                } else {
+                    let dr = px[0].wrapping_sub(prev[0]) as i8;
+                    let dg = px[1].wrapping_sub(prev[1]) as i8;
+                    let db = px[2].wrapping_sub(prev[2]) as i8;
+                    let dr_dg = dr.wrapping_sub(dg);
+                    let db_dg = db.wrapping_sub(dg);
+                    let small = |d: i8| (-2..=1).contains(&d);
+                    if small(dr) && small(dg) && small(db) {
+                        w.write_all(&[0x40
+                            | ((dr + 2) as u8) << 4
+                            | ((dg + 2) as u8) << 2
+                            | (db + 2) as u8])?;
+                    } else if (-32..=31).contains(&dg)
+                        && (-8..=7).contains(&dr_dg)
+                        && (-8..=7).contains(&db_dg)
+                    {
+                        w.write_all(&[
+                            0x80 | (dg + 32) as u8,
+                            ((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8,
+                        ])?;
+                    } else {
+                        w.write_all(&[0xfe, px[0], px[1], px[2]])?;
+                    }
+                }
+                index[hash] = px;
+                prev = px;
+            }
+        }
+        if run > 0 {
+            w.write_all(&[0xc0 | (run - 1)])?;
+        }
+        w.write_all(&[0, 0, 0, 0, 0, 0, 0, 1])?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Scale the screenshot to `width` x `height` with nearest-neighbor
+    /// sampling. The result has no row padding.
+    pub fn resize(&self, width: usize, height: usize) -> Result<Screenshot, CaptureError> {
+        if width == 0 || height == 0 || self.width == 0 || self.height == 0 {
+            return Err(CaptureError::InvalidArgument(
+                "Can't resize to or from an empty image.",
+            ));
+        }
+        let pixel_width = self.pixel_width;
+        let row_len = width * pixel_width;
+        let data = fill_rows(height, row_len, |row, out| {
+            // Sample at the center of each destination pixel.
+            let src_row = self.row_pixels((2 * row + 1) * self.height / (2 * height));
+            for (col, px) in out.chunks_mut(pixel_width).enumerate() {
+                let src_col = (2 * col + 1) * self.width / (2 * width);
+                px.copy_from_slice(&src_row[src_col * pixel_width..(src_col + 1) * pixel_width]);
+            }
+        });
+        Ok(Screenshot {
+            data,
+            height,
+            width,
+            row_len,
+            pixel_width,
+            color_space: self.color_space.clone(),
+            format: self.format,
+            bits_per_component: self.bits_per_component,
+            captured_at: self.captured_at,
+            alpha_mode: self.alpha_mode,
+        })
+    }
+
+    /// Copy the `width` x `height` region whose top-left corner is at column
+    /// `x`, row `y` into a tightly packed screenshot.
+    pub fn crop(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<Screenshot, CaptureError> {
+        let fits = x.checked_add(width).is_some_and(|r| r <= self.width)
+            && y.checked_add(height).is_some_and(|b| b <= self.height);
+        if width == 0 || height == 0 || !fits {
+            return Err(CaptureError::InvalidArgument(
+                "Crop region must be nonempty and inside the image.",
+            ));
+        }
+        let pixel_width = self.pixel_width;
+        let row_len = width * pixel_width;
+        let data = fill_rows(height, row_len, |row, out| {
+            let start = x * pixel_width;
+            out.copy_from_slice(&self.row_pixels(y + row)[start..start + row_len]);
+        });
+        Ok(Screenshot {
+            data,
+            height,
+            width,
+            row_len,
+            pixel_width,
+            color_space: self.color_space.clone(),
+            format: self.format,
+            bits_per_component: self.bits_per_component,
+            captured_at: self.captured_at,
+            alpha_mode: self.alpha_mode,
+        })
+    }
+
+    /// Crop the `width` x `height` region at the center of the image. When
+    /// the margin can't be split evenly the extra pixel is cut from the
+    /// right or bottom.
+    pub fn crop_centered(&self, width: usize, height: usize) -> Result<Screenshot, CaptureError> {
+        if width > self.width || height > self.height {
+            return Err(CaptureError::InvalidArgument(
+                "Crop size must not exceed the image size.",
+            ));
+        }
+        self.crop(
+            (self.width - width) / 2,
+            (self.height - height) / 2,
+            width,
+            height,
+        )
+    }
+
+    /// Center the image in a `width` x `height` one, filling the border
+    /// with `fill`. When the border can't be split evenly the extra pixel
+    /// goes on the right or bottom. Fails if the target is smaller than the
+    /// image in either dimension.
+    pub fn pad_to(
+        &self,
+        width: usize,
+        height: usize,
+        fill: Pixel,
+    ) -> Result<Screenshot, CaptureError> {
+        if width < self.width || height < self.height {
+            return Err(CaptureError::InvalidArgument(
+                "Padded size must be at least the image size.",
+            ));
+        }
+        if self.bits_per_component != 8 {
+            return Err(CaptureError::InvalidArgument(
+                "Padding needs 8 bits per component.",
+            ));
+        }
+        let pixel_width = self.pixel_width;
+        let row_len = width * pixel_width;
+        let fill = self.format.to_memory(fill);
+        let fill = &fill[..pixel_width.min(4)];
+        let left = (width - self.width) / 2 * pixel_width;
+        let top = (height - self.height) / 2;
+        let inner = self.width * pixel_width;
+        let data = fill_rows(height, row_len, |row, out| {
+            fill_pixels(out, fill, pixel_width);
+            if row >= top && row < top + self.height {
+                out[left..left + inner].copy_from_slice(&self.row_pixels(row - top)[..inner]);
+            }
+        });
+        Ok(Screenshot {
+            data,
+            height,
+            width,
+            row_len,
+            pixel_width,
+            color_space: self.color_space.clone(),
+            format: self.format,
+            bits_per_component: self.bits_per_component,
+            captured_at: self.captured_at,
+            alpha_mode: self.alpha_mode,
+        })
+    }
+
+    /// Put `other` to the right of this image, `gap` pixels apart. The gap,
+    /// and the space below the shorter image, is filled with `gap_color`;
+    /// both images are aligned to the top.
+    ///
+    /// Fails unless both have the same pixel layout with 8-bit components.
+    pub fn concat_horizontal(
+        &self,
+        other: &Screenshot,
+        gap: usize,
+        gap_color: Pixel,
+    ) -> Result<Screenshot, CaptureError> {
+        self.check_same_layout(other)?;
+        let pixel_width = self.pixel_width;
+        let left_len = self.width * pixel_width;
+        let right_start = left_len + gap * pixel_width;
+        let width = self.width + gap + other.width;
+        let height = cmp::max(self.height, other.height);
+        let fill = self.format.to_memory(gap_color);
+        let data = fill_rows(height, width * pixel_width, |row, out| {
+            fill_pixels(out, &fill[..pixel_width.min(4)], pixel_width);
+            if row < self.height {
+                out[..left_len].copy_from_slice(self.row_pixels(row));
+            }
+            if row < other.height {
+                out[right_start..].copy_from_slice(other.row_pixels(row));
+            }
+        });
+        Ok(self.with_pixels(data, width, height))
+    }
+
+    /// Put `other` below this image, `gap` pixels apart. The gap, and the
+    /// space right of the narrower image, is filled with `gap_color`; both
+    /// images are aligned to the left.
+    ///
+    /// Fails unless both have the same pixel layout with 8-bit components.
+    pub fn concat_vertical(
+        &self,
+        other: &Screenshot,
+        gap: usize,
+        gap_color: Pixel,
+    ) -> Result<Screenshot, CaptureError> {
+        self.check_same_layout(other)?;
+        let pixel_width = self.pixel_width;
+        let width = cmp::max(self.width, other.width);
+        let other_start = self.height + gap;
+        let height = other_start + other.height;
+        let fill = self.format.to_memory(gap_color);
+        let data = fill_rows(height, width * pixel_width, |row, out| {
+            fill_pixels(out, &fill[..pixel_width.min(4)], pixel_width);
+            let src = if row < self.height {
+                self.row_pixels(row)
+            } else if row >= other_start {
+                other.row_pixels(row - other_start)
+            } else {
+                return;
             };
+            out[..src.len()].copy_from_slice(src);
+        });
+        Ok(self.with_pixels(data, width, height))
+    }
 
-            // Release native objects
-            CGImageRelease(cg_img);
-            CFRelease(cf_data as *const libc::c_void);
+    /// Fail unless `other` has the same 8-bit pixel layout as this image.
+    fn check_same_layout(&self, other: &Screenshot) -> Result<(), CaptureError> {
+        if (self.pixel_width, self.format, self.bits_per_component)
+            != (other.pixel_width, other.format, other.bits_per_component)
+        {
+            return Err(CaptureError::InvalidArgument(
+                "Images must have the same pixel layout.",
+            ));
+        }
+        if self.bits_per_component != 8 {
+            return Err(CaptureError::InvalidArgument(
+                "Images must have 8 bits per component.",
+            ));
+        }
+        Ok(())
+    }
 
-            return res;
+    /// A tightly packed `width` x `height` image of `data`, otherwise like
+    /// this one.
+    fn with_pixels(&self, data: Vec<u8>, width: usize, height: usize) -> Screenshot {
+        Screenshot {
+            data,
+            height,
+            width,
+            row_len: width * self.pixel_width,
+            pixel_width: self.pixel_width,
+            color_space: self.color_space.clone(),
+            format: self.format,
+            bits_per_component: self.bits_per_component,
+            captured_at: self.captured_at,
+            alpha_mode: self.alpha_mode,
         }
     }
-}
 
-#[cfg(target_os = "windows")]
-mod ffi {
-    #![allow(non_snake_case, dead_code)]
+    /// Rotate the image a quarter turn clockwise.
+    pub fn rotate90(&self) -> Screenshot {
+        self.rotated(Rotation::Rot90)
+    }
 
-    use std::mem::size_of;
+    /// Rotate the image half a turn.
+    pub fn rotate180(&self) -> Screenshot {
+        self.rotated(Rotation::Rot180)
+    }
 
-    use winapi::shared::minwindef;
-    use winapi::shared::ntdef;
-    use winapi::shared::windef;
-    use winapi::um::wingdi;
-    use winapi::um::winuser;
+    /// Rotate the image a quarter turn counterclockwise.
+    pub fn rotate270(&self) -> Screenshot {
+        self.rotated(Rotation::Rot270)
+    }
 
-    use ScreenResult;
-    use Screenshot;
+    /// A tightly packed copy turned clockwise by `rotation`.
+    fn rotated(&self, rotation: Rotation) -> Screenshot {
+        let (width, height) = match rotation {
+            Rotation::Rot90 | Rotation::Rot270 => (self.height, self.width),
+            Rotation::Normal | Rotation::Rot180 => (self.width, self.height),
+        };
+        let pixel_width = self.pixel_width;
+        let data = fill_rows(height, width * pixel_width, |row, out| {
+            for (col, px) in out.chunks_mut(pixel_width).enumerate() {
+                let (src_row, src_col) = match rotation {
+                    Rotation::Normal => (row, col),
+                    Rotation::Rot90 => (self.height - 1 - col, row),
+                    Rotation::Rot180 => (self.height - 1 - row, self.width - 1 - col),
+                    Rotation::Rot270 => (col, self.width - 1 - row),
+                };
+                let start = src_col * pixel_width;
+                px.copy_from_slice(&self.row_pixels(src_row)[start..start + pixel_width]);
+            }
+        });
+        self.with_pixels(data, width, height)
+    }
+
+    /// Split into the rows above `at` and the rows from `at` down.
+    pub fn split_horizontally(&self, at: usize) -> Result<(Screenshot, Screenshot), CaptureError> {
+        if at == 0 || at >= self.height {
+            return Err(CaptureError::InvalidArgument(
+                "Split row must leave both halves nonempty.",
+            ));
+        }
+        Ok((
+            self.crop(0, 0, self.width, at)?,
+            self.crop(0, at, self.width, self.height - at)?,
+        ))
+    }
+
+    /// Split into the columns left of `at` and the columns from `at` on.
+    pub fn split_vertically(&self, at: usize) -> Result<(Screenshot, Screenshot), CaptureError> {
+        if at == 0 || at >= self.width {
+            return Err(CaptureError::InvalidArgument(
+                "Split column must leave both halves nonempty.",
+            ));
+        }
+        Ok((
+            self.crop(0, 0, at, self.height)?,
+            self.crop(at, 0, self.width - at, self.height)?,
+        ))
+    }
 
-    /// Reorder rows in bitmap, last to first.
-    /// TODO rewrite functionally
-    fn flip_rows(data: Vec<u8>, height: usize, row_len: usize) -> Vec<u8> {
-        let mut new_data = Vec::with_capacity(data.len());
-        unsafe { new_data.set_len(data.len()) };
-        for row_i in 0..height {
-            for byte_i in 0..row_len {
-                let old_idx = (height - row_i - 1) * row_len + byte_i;
-                let new_idx = row_i * row_len + byte_i;
-                new_data[new_idx] = data[old_idx];
+    /// Shrink the screenshot by an integer `factor`, averaging each
+    /// `factor` x `factor` block into one pixel. Blocks along the right and
+    /// bottom edges may be partial; they average only the pixels they cover.
+    ///
+    /// Fails for a zero `factor` or an image without 8-bit components.
+    pub fn downscale_box(&self, factor: usize) -> Result<Screenshot, CaptureError> {
+        if factor == 0 {
+            return Err(CaptureError::InvalidArgument(
+                "Downscale factor must be nonzero.",
+            ));
+        }
+        if self.bits_per_component != 8 {
+            return Err(CaptureError::InvalidArgument(
+                "Can only downscale images with 8 bits per component.",
+            ));
+        }
+        let pixel_width = self.pixel_width;
+        let width = self.width.div_ceil(factor);
+        let height = self.height.div_ceil(factor);
+        let row_len = width * pixel_width;
+        let data = fill_rows(height, row_len, |row, out| {
+            let src_rows = row * factor..cmp::min((row + 1) * factor, self.height);
+            let mut sums = vec![0u64; row_len];
+            for src_row in src_rows.clone() {
+                let src = self.row_pixels(src_row).chunks(pixel_width);
+                for (src_col, px) in src.enumerate() {
+                    let sum = &mut sums[src_col / factor * pixel_width..];
+                    for (c, &byte) in px.iter().enumerate() {
+                        sum[c] += byte as u64;
+                    }
+                }
+            }
+            for (col, px) in out.chunks_mut(pixel_width).enumerate() {
+                let cols = cmp::min((col + 1) * factor, self.width) - col * factor;
+                let count = (cols * src_rows.len()) as u64;
+                for (c, byte) in px.iter_mut().enumerate() {
+                    *byte = ((sums[col * pixel_width + c] + count / 2) / count) as u8;
+                }
             }
+        });
+        Ok(Screenshot {
+            data,
+            height,
+            width,
+            row_len,
+            pixel_width,
+            color_space: self.color_space.clone(),
+            format: self.format,
+            bits_per_component: self.bits_per_component,
+            captured_at: self.captured_at,
+            alpha_mode: self.alpha_mode,
+        })
+    }
+
+    /// Scale the screenshot so its longer side is `max_dim` pixels, keeping
+    /// the aspect ratio. Screenshots that already fit are returned as-is.
+    pub fn thumbnail(&self, max_dim: usize) -> Result<Screenshot, CaptureError> {
+        if max_dim == 0 {
+            return Err(CaptureError::InvalidArgument(
+                "Thumbnail size must be nonzero.",
+            ));
         }
-        new_data
+        if self.width <= max_dim && self.height <= max_dim {
+            return Ok(self.clone());
+        }
+        let (width, height) = if self.width >= self.height {
+            let height = (self.height * max_dim + self.width / 2) / self.width;
+            (max_dim, height.max(1))
+        } else {
+            let width = (self.width * max_dim + self.height / 2) / self.height;
+            (width.max(1), max_dim)
+        };
+        self.resize(width, height)
     }
 
-    /// TODO Support multiple screens
-    /// This may never happen, given the horrific quality of Win32 APIs
-    pub fn get_screenshot(_screen: usize) -> ScreenResult {
-        //        use std::ptr::null;
-        unsafe {
-            // Enumerate monitors, getting a handle and DC for requested monitor.
-            // loljk, because doing that on Windows is worse than death
+    /// Sample a `cols` x `rows` grid of pixels, one from the center of each
+    /// cell, in row-major order. A cheap stand-in for `resize` when only a
+    /// rough idea of the image is needed.
+    pub fn sample_grid(&self, cols: usize, rows: usize) -> Result<Vec<Pixel>, CaptureError> {
+        if cols == 0 || rows == 0 {
+            return Err(CaptureError::InvalidArgument(
+                "Sample grid must be nonzero.",
+            ));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(CaptureError::InvalidArgument(
+                "Can't sample an empty screenshot.",
+            ));
+        }
+        let center = |i: usize, n: usize, len: usize| ((2 * i + 1) * len / (2 * n)).min(len - 1);
+        let mut samples = Vec::with_capacity(cols * rows);
+        for r in 0..rows {
+            let row = center(r, rows, self.height);
+            for c in 0..cols {
+                samples.push(self.get_pixel(row, center(c, cols, self.width)));
+            }
+        }
+        Ok(samples)
+    }
 
-            let scale = 1;
+    /// 64-bit difference hash, for spotting near-duplicate captures with
+    /// `hamming_distance`. The grayscale image is box-averaged down to 9 x 8
+    /// and each bit, row by row, says whether a cell is brighter than the one
+    /// to its right. Comparing neighbors rather than a global average makes
+    /// it robust to brightness and contrast changes, but a flat image hashes
+    /// to 0. Empty images also hash to 0.
+    pub fn dhash(&self) -> u64 {
+        if self.width == 0 || self.height == 0 {
+            return 0;
+        }
+        let gray = self.to_grayscale();
+        // Each cell covers at least one pixel, even in images under 9 x 8.
+        let span = |i: usize, cells: usize, len: usize| {
+            let start = i * len / cells;
+            (start, cmp::max((i + 1) * len / cells, start + 1))
+        };
+        let mut cells = [[0u32; 9]; 8];
+        for (row, cells) in cells.iter_mut().enumerate() {
+            let (top, bottom) = span(row, 8, self.height);
+            for (col, cell) in cells.iter_mut().enumerate() {
+                let (left, right) = span(col, 9, self.width);
+                let sum: u32 = (top..bottom)
+                    .flat_map(|y| gray[y * self.width + left..y * self.width + right].iter())
+                    .map(|&g| g as u32)
+                    .sum();
+                *cell = sum / ((bottom - top) * (right - left)) as u32;
+            }
+        }
+        let mut hash = 0;
+        for row in cells.iter() {
+            for pair in row.windows(2) {
+                hash = hash << 1 | (pair[0] > pair[1]) as u64;
+            }
+        }
+        hash
+    }
 
-            let h_wnd_screen = winuser::GetDesktopWindow();
-            let h_dc_screen = winuser::GetDC(h_wnd_screen);
-            let width = winuser::GetSystemMetrics(winuser::SM_CXVIRTUALSCREEN) / scale;
-            let height = winuser::GetSystemMetrics(winuser::SM_CYVIRTUALSCREEN) / scale;
+    /// The average color of the `width` x `height` rectangle at (`x`, `y`),
+    /// channel by channel, following the pixel format. Pixels without an
+    /// alpha byte count as opaque.
+    ///
+    /// Fails if the rectangle is empty or not inside the image.
+    pub fn region_average(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<Pixel, CaptureError> {
+        if !region_fits(Some((x, y, width, height)), self.width, self.height) {
+            return Err(CaptureError::InvalidArgument(
+                "Region must be nonempty and inside the image.",
+            ));
+        }
+        let mut sums = [0u64; 4];
+        for row in y..y + height {
+            let pixels =
+                &self.row_pixels(row)[x * self.pixel_width..(x + width) * self.pixel_width];
+            for px in pixels.chunks(self.pixel_width) {
+                let p = self.read_pixel(px);
+                for (sum, &c) in sums.iter_mut().zip(&[p.b, p.g, p.r, p.a]) {
+                    *sum += c as u64;
+                }
+            }
+        }
+        let count = (width * height) as u64;
+        let avg = |i: usize| ((sums[i] + count / 2) / count) as u8;
+        Ok(Pixel {
+            b: avg(0),
+            g: avg(1),
+            r: avg(2),
+            a: avg(3),
+        })
+    }
 
-            let screen_x = winuser::GetSystemMetrics(winuser::SM_XVIRTUALSCREEN);
-            let screen_y = winuser::GetSystemMetrics(winuser::SM_YVIRTUALSCREEN);
+    /// The average color of the whole image; see `region_average`. Fails
+    /// for an empty image.
+    pub fn average_color(&self) -> Result<Pixel, CaptureError> {
+        self.region_average(0, 0, self.width, self.height)
+    }
 
-            // Create a Windows Bitmap, and copy the bits into it
-            let h_dc = wingdi::CreateCompatibleDC(h_dc_screen);
-            if h_dc.is_null() {
-                return Err("Can't get a Windows display.");
+    /// Up to `k` of the most common colors, most common first, each with the
+    /// fraction of the image it covers.
+    ///
+    /// This looks at a grid of at most 64 x 64 pixels from `sample_grid`
+    /// and buckets them by the top 4 bits of each channel, so the colors and
+    /// weights are estimates. Each color is the average of its bucket.
+    pub fn dominant_colors(&self, k: usize) -> Vec<(Pixel, f32)> {
+        let samples = match self.sample_grid(self.width.min(64), self.height.min(64)) {
+            Ok(samples) => samples,
+            Err(_) => return Vec::new(),
+        };
+        // Per bucket: count and channel sums in BGRA order.
+        let mut buckets = vec![(0u32, [0u32; 4]); 1 << 12];
+        for p in &samples {
+            let key = (p.r as usize >> 4) << 8 | (p.g as usize >> 4) << 4 | p.b as usize >> 4;
+            let bucket = &mut buckets[key];
+            bucket.0 += 1;
+            for (sum, &c) in bucket.1.iter_mut().zip(&[p.b, p.g, p.r, p.a]) {
+                *sum += c as u32;
             }
+        }
+        buckets.retain(|b| b.0 > 0);
+        buckets.sort_by_key(|b| cmp::Reverse(b.0));
+        let total = samples.len() as f32;
+        buckets
+            .iter()
+            .take(k)
+            .map(|&(count, sums)| {
+                let avg = |i: usize| ((sums[i] + count / 2) / count) as u8;
+                let color = Pixel {
+                    b: avg(0),
+                    g: avg(1),
+                    r: avg(2),
+                    a: avg(3),
+                };
+                (color, count as f32 / total)
+            })
+            .collect()
+    }
 
-            let h_bmp = wingdi::CreateCompatibleBitmap(h_dc_screen, width, height);
-            if h_bmp.is_null() {
-                return Err("Can't create a Windows buffer");
+    /// Number of distinct colors, alpha included, counting no further than
+    /// `max`. Flat UI captures have few colors and photos many, so a low
+    /// `max` is enough to tell them apart and stops early on photos.
+    pub fn count_unique_colors(&self, max: usize) -> usize {
+        let mut seen = HashSet::new();
+        for row in 0..self.height {
+            for px in self.row_pixels(row).chunks(self.pixel_width) {
+                if seen.len() >= max {
+                    return max;
+                }
+                seen.insert(self.read_pixel(px).to_u32());
             }
+        }
+        cmp::min(seen.len(), max)
+    }
 
-            let res = wingdi::SelectObject(h_dc, h_bmp as windef::HGDIOBJ);
-            if res == ntdef::NULL || res == wingdi::HGDI_ERROR {
-                return Err("Can't select Windows buffer.");
+    /// Replace every pixel with `f(pixel)`, leaving row padding untouched.
+    /// With 16 bits per component, `f` sees each pixel narrowed to 8 bits,
+    /// and only the pixels it changes are written back, widened.
+    ///
+    /// ```no_run
+    /// use screenshot::{get_screenshot, Pixel};
+    ///
+    /// let mut s = get_screenshot(0).unwrap();
+    /// // Swap the red and blue channels.
+    /// s.map_pixels(|p| Pixel { r: p.b, b: p.r, ..p });
+    /// ```
+    pub fn map_pixels<F: FnMut(Pixel) -> Pixel>(&mut self, mut f: F) {
+        let pixel_width = self.pixel_width;
+        let packed_len = self.width * pixel_width;
+        let format = self.format;
+        for row in 0..self.height {
+            let start = row * self.row_len;
+            for px in self.data[start..start + packed_len].chunks_mut(pixel_width) {
+                let old = format.load(px);
+                let p = f(old);
+                // Rewriting an unchanged 16-bit pixel would drop its low bytes.
+                if p != old {
+                    format.store(px, p);
+                }
             }
+        }
+    }
 
-            if scale != 1 {
-                wingdi::SetStretchBltMode(h_dc, wingdi::COLORONCOLOR);
-                let res = wingdi::StretchBlt(
-                    h_dc,
-                    0,
-                    0,
-                    width,
-                    height,
-                    h_dc_screen,
-                    screen_x,
-                    screen_y,
-                    width * scale,
-                    height * scale,
-                    wingdi::SRCCOPY | wingdi::CAPTUREBLT,
-                );
-                if res == 0 {
-                    return Err("Failed to copy screen to Windows buffer");
+    /// Convert premultiplied pixels to straight alpha by dividing each color
+    /// channel by alpha, clamping to the maximum. Fully transparent pixels
+    /// become transparent black. Works at 8 or 16 bits per component, and
+    /// does nothing unless `alpha_mode` is `Premultiplied`.
+    pub fn unpremultiply(&mut self) {
+        if self.alpha_mode != AlphaMode::Premultiplied {
+            return;
+        }
+        self.alpha_mode = AlphaMode::Straight;
+        let bytes = self.bits_per_component / 8;
+        if bytes == 0 || self.pixel_width != 4 * bytes {
+            return;
+        }
+        let alpha = self.format.alpha_offset();
+        let max = (1u64 << self.bits_per_component) - 1;
+        let pixel_width = self.pixel_width;
+        let packed_len = self.width * pixel_width;
+        for row in 0..self.height {
+            let start = row * self.row_len;
+            for px in self.data[start..start + packed_len].chunks_mut(pixel_width) {
+                // Components are little-endian.
+                let mut components = [0u64; 4];
+                for (c, bytes) in components.iter_mut().zip(px.chunks(bytes)) {
+                    *c = bytes.iter().rev().fold(0, |c, &b| c << 8 | b as u64);
                 }
+                let a = components[alpha];
+                for (i, bytes) in px.chunks_mut(bytes).enumerate() {
+                    if i == alpha {
+                        continue;
+                    }
+                    let mut c = match a {
+                        0 => 0,
+                        a => ((components[i] * max + a / 2) / a).min(max),
+                    };
+                    for b in bytes {
+                        *b = c as u8;
+                        c >>= 8;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace every pixel whose red, green and blue are each within
+    /// `tolerance` of `from`'s with `to`, alpha included. Keying out a
+    /// background is `replace_color(background, Pixel { a: 0, ..background }, 0)`.
+    pub fn replace_color(&mut self, from: Pixel, to: Pixel, tolerance: u8) {
+        self.map_pixels(|p| {
+            if near_color(p, from, tolerance) {
+                to
             } else {
-                let res = wingdi::BitBlt(
-                    h_dc,
-                    0,
-                    0,
-                    width,
-                    height,
-                    h_dc_screen,
-                    screen_x,
-                    screen_y,
-                    wingdi::SRCCOPY | wingdi::CAPTUREBLT,
-                );
-                if res == 0 {
-                    return Err("Failed to copy screen to Windows buffer");
-                }
-            }
-
-            // Get image info
-            let pixel_width: usize = 4; // FIXME
-
-            let mut bmi = wingdi::BITMAPINFO {
-                bmiHeader: wingdi::BITMAPINFOHEADER {
-                    biSize: size_of::<wingdi::BITMAPINFOHEADER>() as minwindef::DWORD,
-                    biWidth: width as ntdef::LONG,
-                    biHeight: height as ntdef::LONG,
-                    biPlanes: 1,
-                    biBitCount: 8 * pixel_width as minwindef::WORD,
-                    biCompression: wingdi::BI_RGB,
-                    biSizeImage: (width * height * pixel_width as minwindef::INT)
-                        as minwindef::DWORD,
-                    biXPelsPerMeter: 0,
-                    biYPelsPerMeter: 0,
-                    biClrUsed: 0,
-                    biClrImportant: 0,
-                },
-                bmiColors: [wingdi::RGBQUAD {
-                    rgbBlue: 0,
-                    rgbGreen: 0,
-                    rgbRed: 0,
-                    rgbReserved: 0,
-                }],
+                p
+            }
+        });
+    }
+
+    /// Gamma-correct the red, green and blue channels in place with
+    /// `out = 255 * (in / 255) ^ (1 / gamma)`, leaving alpha alone. A `gamma`
+    /// above 1 brightens the image; it should be positive.
+    pub fn apply_gamma(&mut self, gamma: f32) {
+        let mut lut = [0u8; 256];
+        for (i, out) in lut.iter_mut().enumerate() {
+            *out = (255.0 * (i as f32 / 255.0).powf(1.0 / gamma)).round() as u8;
+        }
+        self.map_pixels(|p| Pixel {
+            r: lut[p.r as usize],
+            g: lut[p.g as usize],
+            b: lut[p.b as usize],
+            a: p.a,
+        });
+    }
+
+    /// Dim everything outside the `width` x `height` rectangle at (`x`, `y`)
+    /// by blending it toward `dim`, leaving the inside untouched. `strength`
+    /// runs from 0.0 (unchanged) to 1.0 (fully `dim`). The rectangle is
+    /// clipped to the image.
+    pub fn mask_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        dim: Pixel,
+        strength: f32,
+    ) {
+        let strength = strength.clamp(0.0, 1.0);
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        let pixel_width = self.pixel_width;
+        let format = self.format;
+        for row in 0..self.height {
+            let start = row * self.row_len;
+            let pixels = self.data[start..start + self.width * pixel_width].chunks_mut(pixel_width);
+            for (col, px) in pixels.enumerate() {
+                if row >= y && row < y_end && col >= x && col < x_end {
+                    continue;
+                }
+                let p = format.load(px);
+                let blended = Pixel {
+                    b: blend_channel(p.b, dim.b, strength),
+                    g: blend_channel(p.g, dim.g, strength),
+                    r: blend_channel(p.r, dim.r, strength),
+                    a: blend_channel(p.a, dim.a, strength),
+                };
+                format.store(px, blended);
+            }
+        }
+    }
+
+    /// Sets pixel at (row, col), in the screenshot's `pixel_format`. Pixels
+    /// without an alpha byte ignore `color.a`.
+    ///
+    /// Panics if (row, col) is out of bounds.
+    pub fn set_pixel(&mut self, row: usize, col: usize, color: Pixel) {
+        if row >= self.height || col >= self.width {
+            panic!("Bounds overflow");
+        }
+        let idx = row * self.row_len + col * self.pixel_width;
+        let format = self.format;
+        format.store(&mut self.data[idx..idx + self.pixel_width], color);
+    }
+
+    /// Draw `text` in `color` with a built-in 5x7 pixel font, its top-left
+    /// corner at (`x`, `y`). Characters are 6 pixels apart and lines 8, and
+    /// `'\n'` starts a new line. Characters outside printable ASCII are
+    /// drawn as `'?'`, and anything past the edges is clipped.
+    pub fn overlay_text(&mut self, text: &str, x: usize, y: usize, color: Pixel) {
+        let (mut col, mut row) = (x, y);
+        for c in text.chars() {
+            if c == '\n' {
+                col = x;
+                row = row.saturating_add(8);
+                continue;
+            }
+            let glyph = match c {
+                ' '..='~' => &FONT_5X7[c as usize - ' ' as usize],
+                _ => &FONT_5X7['?' as usize - ' ' as usize],
             };
+            for (dx, &bits) in glyph.iter().enumerate() {
+                for dy in 0..7 {
+                    let (px_row, px_col) = (row.saturating_add(dy), col.saturating_add(dx));
+                    if bits & (1 << dy) != 0 && px_row < self.height && px_col < self.width {
+                        self.set_pixel(px_row, px_col, color);
+                    }
+                }
+            }
+            col = col.saturating_add(6);
+        }
+    }
+
+    /// Gets pixel at (row, col), or `None` if it's out of bounds or the
+    /// screenshot isn't 8 bits per component.
+    pub fn get_pixel_checked(&self, row: usize, col: usize) -> Option<Pixel> {
+        if row < self.height
+            && col < self.width
+            && self.pixel_width >= 3
+            && self.bits_per_component == 8
+        {
+            Some(self.get_pixel(row, col))
+        } else {
+            None
+        }
+    }
 
-            // Create a Vec for image
-            let size: usize = (width * height) as usize * pixel_width;
-            let mut data: Vec<u8> = Vec::with_capacity(size);
-            data.set_len(size);
+    /// Gets 16-bit pixel at (row, col), or `None` if it's out of bounds or
+    /// the screenshot isn't 16 bits per component.
+    pub fn get_pixel_u16(&self, row: usize, col: usize) -> Option<Pixel16> {
+        if row >= self.height
+            || col >= self.width
+            || self.pixel_width != 8
+            || self.bits_per_component != 16
+        {
+            return None;
+        }
+        let idx = row * self.row_len + col * 8;
+        let px = &self.data[idx..idx + 8];
+        let c = |i: usize| u16::from_le_bytes([px[2 * i], px[2 * i + 1]]);
+        // Component indices of red, green, blue and alpha.
+        let (r, g, b, a) = match self.format {
+            PixelFormat::Bgra => (2, 1, 0, 3),
+            PixelFormat::Argb => (1, 2, 3, 0),
+            PixelFormat::Rgba => (0, 1, 2, 3),
+            PixelFormat::Abgr => (3, 2, 1, 0),
+        };
+        Some(Pixel16 {
+            r: c(r),
+            g: c(g),
+            b: c(b),
+            a: c(a),
+        })
+    }
 
-            // copy bits into Vec
-            wingdi::GetDIBits(
-                h_dc,
-                h_bmp,
-                0,
-                height as minwindef::DWORD,
-                &mut data[0] as *mut u8 as minwindef::LPVOID,
-                &mut bmi as wingdi::LPBITMAPINFO,
-                wingdi::DIB_RGB_COLORS,
-            );
+    /// Gets pixel at (row, col), reading channels according to the
+    /// screenshot's `pixel_format`. 16-bit components are narrowed to 8
+    /// bits; use `get_pixel_u16` for full precision. Pixels without an
+    /// alpha byte, as in 24-bit captures, are opaque.
+    ///
+    /// Panics if (row, col) is out of bounds.
+    pub fn get_pixel(&self, row: usize, col: usize) -> Pixel {
+        if row >= self.height || col >= self.width {
+            panic!("Bounds overflow");
+        }
+        let idx = row * self.row_len + col * self.pixel_width;
+        self.read_pixel(&self.data[idx..idx + self.pixel_width])
+    }
+
+    /// The pixel stored in `px`, following the pixel format. Pixels without
+    /// an alpha byte are opaque.
+    fn read_pixel(&self, px: &[u8]) -> Pixel {
+        self.format.load(px)
+    }
+}
 
-            // Release native image buffers
-            winuser::ReleaseDC(h_wnd_screen, h_dc_screen); // don't need screen anymore
-            wingdi::DeleteDC(h_dc);
-            wingdi::DeleteObject(h_bmp as windef::HGDIOBJ);
+/// Number of bits that differ between two hashes from `Screenshot::dhash`.
+/// Images within a few bits of each other usually look alike.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
 
-            let data = flip_rows(data, height as usize, width as usize * pixel_width);
+/// 5x7 glyphs for printable ASCII, from `' '` to `'~'`, used by
+/// `Screenshot::overlay_text`. Each byte is a column, top row in bit 0.
+const FONT_5X7: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '\''
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // '*'
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7f, 0x09, 0x09, 0x01, 0x01], // 'F'
+    [0x3e, 0x41, 0x41, 0x51, 0x32], // 'G'
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7f, 0x02, 0x04, 0x02, 0x7f], // 'M'
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+    [0x7f, 0x20, 0x18, 0x20, 0x7f], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x03, 0x04, 0x78, 0x04, 0x03], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\\'
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+    [0x00, 0x7f, 0x10, 0x28, 0x44], // 'k'
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x08, 0x04, 0x08, 0x10, 0x08], // '~'
+];
 
-            Ok(Screenshot {
-                data,
-                height: height as usize,
-                width: width as usize,
-                row_len: width as usize * pixel_width,
-                pixel_width,
-            })
+/// Whether `p` has red, green and blue each within `tolerance` of `color`'s.
+fn near_color(p: Pixel, color: Pixel, tolerance: u8) -> bool {
+    let near = |a: u8, b: u8| (a as i16 - b as i16).abs() <= tolerance as i16;
+    near(p.r, color.r) && near(p.g, color.g) && near(p.b, color.b)
+}
+
+/// Append the standard, padded base64 encoding of `bytes` to `out`.
+fn base64_encode(bytes: &[u8], out: &mut String) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    out.reserve(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+/// `a` moved `t` of the way toward `b`, for `t` from 0.0 to 1.0.
+fn blend_channel(a: u8, b: u8, t: f32) -> u8 {
+    (a as f32 + (b as f32 - a as f32) * t).round() as u8
+}
+
+/// Set every `pixel_width`-byte pixel of `out` to start with `fill`.
+fn fill_pixels(out: &mut [u8], fill: &[u8], pixel_width: usize) {
+    for px in out.chunks_mut(pixel_width) {
+        px[..fill.len()].copy_from_slice(fill);
+    }
+}
+
+/// A quick multiplicative hash of `bytes`, eight at a time.
+fn row_checksum(bytes: &[u8]) -> u64 {
+    const K: u64 = 0x517c_c1b7_2722_0a95;
+    let mix = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(K);
+    let mut words = bytes.chunks_exact(8);
+    let mut hash = bytes.len() as u64;
+    for word in &mut words {
+        let mut le = [0; 8];
+        le.copy_from_slice(word);
+        hash = mix(hash, u64::from_le_bytes(le));
+    }
+    let mut rest = [0; 8];
+    rest[..words.remainder().len()].copy_from_slice(words.remainder());
+    mix(hash, u64::from_le_bytes(rest))
+}
+
+/// BT.601 luma of an 8-bit RGB color.
+fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((77 * r as u32 + 150 * g as u32 + 29 * b as u32 + 128) >> 8) as u8
+}
+
+/// Allocate `rows * row_len` bytes and fill each row with `f(row, out)`.
+/// Rows are filled on the rayon thread pool when the `rayon` feature is on.
+fn fill_rows<F>(rows: usize, row_len: usize, f: F) -> Vec<u8>
+where
+    F: Fn(usize, &mut [u8]) + Sync + Send,
+{
+    let mut data = vec![0; rows * row_len];
+    if row_len == 0 {
+        return data;
+    }
+    #[cfg(feature = "rayon")]
+    data.par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(row, out)| f(row, out));
+    #[cfg(not(feature = "rayon"))]
+    for (row, out) in data.chunks_mut(row_len).enumerate() {
+        f(row, out);
+    }
+    data
+}
+
+/// An empty 0x0 screenshot, useful as a buffer for `capture_into`.
+impl Default for Screenshot {
+    fn default() -> Screenshot {
+        Screenshot {
+            data: Vec::new(),
+            height: 0,
+            width: 0,
+            row_len: 0,
+            pixel_width: 4,
+            color_space: None,
+            format: PixelFormat::Bgra,
+            bits_per_component: 8,
+            captured_at: UNIX_EPOCH,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+/// `screenshot[(row, col)]` reads a pixel, panicking if it's out of bounds.
+/// Only 8-bit `Bgra` screenshots, whose pixels are laid out like `Pixel`,
+/// can be indexed; use `get_pixel` for anything else.
+impl Index<(usize, usize)> for Screenshot {
+    type Output = Pixel;
+
+    fn index(&self, (row, col): (usize, usize)) -> &Pixel {
+        assert!(
+            row < self.height && col < self.width,
+            "pixel ({}, {}) out of bounds for {}x{} screenshot",
+            row,
+            col,
+            self.width,
+            self.height
+        );
+        assert!(
+            self.format == PixelFormat::Bgra
+                && self.pixel_width == 4
+                && self.bits_per_component == 8,
+            "only 8-bit Bgra screenshots can be indexed"
+        );
+        let idx = row * self.row_len + col * 4;
+        let bytes = &self.data[idx..idx + 4];
+        // `Pixel` is four `u8`s in memory order, so any 4 bytes are a valid one.
+        unsafe { &*(bytes.as_ptr() as *const Pixel) }
+    }
+}
+
+/// Screenshots are equal when they have the same size, pixel layout and
+/// pixels. Row padding, color space and capture time are ignored.
+impl PartialEq for Screenshot {
+    fn eq(&self, other: &Screenshot) -> bool {
+        self.is_same_size(other)
+            && self.pixel_width == other.pixel_width
+            && self.format == other.format
+            && self.bits_per_component == other.bits_per_component
+            && self.alpha_mode == other.alpha_mode
+            && (0..self.height).all(|row| self.row_pixels(row) == other.row_pixels(row))
+    }
+}
+
+impl Eq for Screenshot {}
+
+/// Hashes what `PartialEq` compares, so screenshots that differ only in
+/// row padding hash equally. This reads every pixel; to fingerprint large
+/// captures cheaply, hash a `thumbnail` or `sample_grid` instead.
+impl Hash for Screenshot {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.width.hash(state);
+        self.height.hash(state);
+        self.pixel_width.hash(state);
+        self.format.hash(state);
+        self.bits_per_component.hash(state);
+        self.alpha_mode.hash(state);
+        for row in 0..self.height {
+            state.write(self.row_pixels(row));
+        }
+    }
+}
+
+impl AsRef<[u8]> for Screenshot {
+    #[inline]
+    fn as_ref<'a>(&'a self) -> &'a [u8] {
+        self.data.as_slice()
+    }
+}
+
+/// Tightly packed RGBA bytes, like `Screenshot::to_rgba`. Screenshots with
+/// 4-byte pixels are reordered and compacted in their own buffer rather
+/// than copied.
+impl From<Screenshot> for Vec<u8> {
+    fn from(mut screenshot: Screenshot) -> Vec<u8> {
+        if screenshot.pixel_width != 4 {
+            return screenshot.to_rgba();
+        }
+        screenshot.reorder_in_place(PixelFormat::Rgba);
+        screenshot.compact();
+        screenshot.data
+    }
+}
+
+/// A read-only screenshot that is cheap to clone: clones share one pixel
+/// buffer. It derefs to `Screenshot` for all the read methods.
+#[derive(Clone)]
+pub struct SharedScreenshot {
+    inner: Arc<Screenshot>,
+}
+
+impl SharedScreenshot {
+    /// Take back the screenshot, copying it only if other clones still
+    /// share it.
+    pub fn into_owned(self) -> Screenshot {
+        Arc::try_unwrap(self.inner).unwrap_or_else(|inner| (*inner).clone())
+    }
+}
+
+impl Deref for SharedScreenshot {
+    type Target = Screenshot;
+
+    fn deref(&self) -> &Screenshot {
+        &self.inner
+    }
+}
+
+impl AsRef<[u8]> for SharedScreenshot {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        (*self.inner).as_ref()
+    }
+}
+
+pub type ScreenResult = Result<Screenshot, CaptureError>;
+
+/// An error encountered while capturing a display.
+#[derive(Debug)]
+pub enum CaptureError {
+    /// The platform backend failed. The message names the step that failed.
+    Native(&'static str),
+    /// Writing an encoded image failed.
+    Io(io::Error),
+    /// The image encoder rejected the screenshot.
+    Encode(String),
+    /// An argument was out of range. The message says which.
+    InvalidArgument(&'static str),
+    /// No connected display matches the requested one.
+    DisplayNotFound(DisplayId),
+    /// No encoder for this file extension is available.
+    UnsupportedFormat(String),
+    /// An image isn't the size it needs to be, such as a capture that
+    /// doesn't fit its display or the second of two images to compare.
+    /// Sizes are `(width, height)` pairs.
+    SizeMismatch {
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    /// The capture panicked and `try_get_screenshot` caught it. The message
+    /// is the panic's, if it had one.
+    Panic { message: String },
+    /// The display kept changing size during capture, so no intact frame
+    /// could be taken. See `CaptureOptions::reconfigure_retries`.
+    DisplayReconfigured,
+    /// Waiting gave up, such as for the screen to settle in
+    /// `capture_when_stable`.
+    Timeout,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CaptureError::Native(msg) => f.write_str(msg),
+            CaptureError::Io(ref err) => write!(f, "I/O error: {}", err),
+            CaptureError::Encode(ref msg) => write!(f, "Encoding error: {}", msg),
+            CaptureError::InvalidArgument(msg) => f.write_str(msg),
+            CaptureError::DisplayNotFound(ref id) => write!(f, "Display not found: {:?}", id),
+            CaptureError::UnsupportedFormat(ref ext) => {
+                write!(f, "Unsupported image format: {:?}", ext)
+            }
+            CaptureError::SizeMismatch { expected, actual } => write!(
+                f,
+                "Expected a {}x{} image, got {}x{}",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            CaptureError::Panic { ref message } => write!(f, "Capture panicked: {}", message),
+            CaptureError::DisplayReconfigured => {
+                f.write_str("Display was reconfigured during capture")
+            }
+            CaptureError::Timeout => f.write_str("Timed out"),
+        }
+    }
+}
+
+impl Error for CaptureError {}
+
+impl From<&'static str> for CaptureError {
+    fn from(msg: &'static str) -> CaptureError {
+        CaptureError::Native(msg)
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(err: io::Error) -> CaptureError {
+        CaptureError::Io(err)
+    }
+}
+
+/// Position and size of a display, in virtual-desktop pixels.
+///
+/// On Linux each X screen is one display, since that's what `get_screenshot`
+/// captures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayInfo {
+    /// Index of the display, as passed to `get_screenshot`.
+    pub index: usize,
+    pub x: i32,
+    pub y: i32,
+    pub width: usize,
+    pub height: usize,
+    /// Human-readable name of the display, if the platform reports one: the
+    /// monitor's product name on macOS and Windows, and the RandR output name
+    /// (e.g. `DP-1`) of the screen's primary output on Linux.
+    pub name: Option<String>,
+    /// Refresh rate in hertz, if the platform knows it.
+    pub refresh_rate_hz: Option<f64>,
+    /// Physical width and height of the display area in millimeters, if the
+    /// platform knows them. Projectors and virtual machines often report
+    /// nothing, and Windows may derive the size from the DPI setting rather
+    /// than the monitor's EDID.
+    pub physical_size_mm: Option<(u32, u32)>,
+    /// Index of the display this one mirrors, if it's a secondary member of
+    /// a mirror set. Capturing it gives the same image as capturing that
+    /// display. Only macOS reports mirroring: Windows lists a mirror set as
+    /// one monitor, and X screens can't mirror each other.
+    pub mirror_of: Option<usize>,
+    /// How the display is rotated from its native orientation. Captures
+    /// are already upright on every platform; this says how the panel is
+    /// mounted, and why a portrait display is taller than it is wide.
+    pub rotation: Rotation,
+}
+
+/// Clockwise rotation of a display from its native orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rotation {
+    Normal,
+    Rot90,
+    Rot180,
+    Rot270,
+}
+
+impl Rotation {
+    /// The rotation of `turns` clockwise quarter turns.
+    fn from_quarter_turns(turns: i64) -> Rotation {
+        match turns.rem_euclid(4) {
+            0 => Rotation::Normal,
+            1 => Rotation::Rot90,
+            2 => Rotation::Rot180,
+            _ => Rotation::Rot270,
+        }
+    }
+}
+
+impl DisplayInfo {
+    /// Whether the display covers the point (`x`, `y`) of the virtual
+    /// desktop. The right and bottom edges are exclusive.
+    pub fn contains_point(&self, x: i32, y: i32) -> bool {
+        let (x, y) = (x as i64, y as i64);
+        x >= self.x as i64
+            && y >= self.y as i64
+            && x < self.x as i64 + self.width as i64
+            && y < self.y as i64 + self.height as i64
+    }
+}
+
+/// Index of the display covering the point (`x`, `y`) of the virtual
+/// desktop, or `None` if it falls between or outside all displays.
+///
+/// X screens each have their own coordinates, so on Linux this is the
+/// first screen large enough to hold the point.
+pub fn display_at_point(x: i32, y: i32) -> Result<Option<usize>, CaptureError> {
+    Ok(list_displays()?
+        .iter()
+        .find(|info| info.contains_point(x, y))
+        .map(|info| info.index))
+}
+
+/// Index of the display whose left edge is furthest left, for passing to
+/// `get_screenshot`. Ties go to the display listed first.
+///
+/// X screens each have their own coordinates, so on Linux these positional
+/// helpers can't tell screens apart and pick the first.
+pub fn leftmost_display() -> Result<usize, CaptureError> {
+    extreme_display(&list_displays()?, |d| d.x)
+}
+
+/// Index of the display whose left edge is furthest right.
+pub fn rightmost_display() -> Result<usize, CaptureError> {
+    extreme_display(&list_displays()?, |d| cmp::Reverse(d.x))
+}
+
+/// Index of the display whose top edge is highest.
+pub fn topmost_display() -> Result<usize, CaptureError> {
+    extreme_display(&list_displays()?, |d| d.y)
+}
+
+/// Index of the display whose top edge is lowest.
+pub fn bottommost_display() -> Result<usize, CaptureError> {
+    extreme_display(&list_displays()?, |d| cmp::Reverse(d.y))
+}
+
+/// Index of the first of `displays` with the smallest `key`.
+fn extreme_display<K, F>(displays: &[DisplayInfo], key: F) -> Result<usize, CaptureError>
+where
+    K: Ord,
+    F: Fn(&DisplayInfo) -> K,
+{
+    displays
+        .iter()
+        .min_by_key(|d| key(d))
+        .map(|d| d.index)
+        .ok_or(CaptureError::Native("No displays found."))
+}
+
+/// Identifies a display to capture with `get_screenshot_for`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisplayId {
+    /// Position in `list_displays()`.
+    Index(usize),
+    /// The display the OS considers primary.
+    Primary,
+    /// The platform's own display handle: a `CGDirectDisplayID` on macOS,
+    /// an `HMONITOR` on Windows, and the X screen number on Linux.
+    Native(u64),
+    /// The first display whose `DisplayInfo::name` equals this string.
+    Name(String),
+    /// Every display at once: the bounding box of all of them, with any
+    /// area no display covers left black. This is what Windows captures
+    /// for any index; on Linux it's the default X screen, whose root window
+    /// spans all of its monitors.
+    Virtual,
+}
+
+/// A rectangle of a display: x, y, width and height.
+type Region = (usize, usize, usize, usize);
+
+/// Put the rows of a DIB whose header height is `bi_height` in top-down
+/// order, in place. A positive height means GDI wrote the rows bottom-up,
+/// so they're flipped; the negative height captures ask for means they're
+/// already top-down.
+#[cfg(any(target_os = "windows", test))]
+fn dib_rows_top_down(data: &mut [u8], bi_height: i32, row_len: usize) {
+    if bi_height <= 0 || row_len == 0 {
+        return;
+    }
+    let height = bi_height as usize;
+    for row in 0..height / 2 {
+        let (top, bottom) = data.split_at_mut((height - 1 - row) * row_len);
+        top[row * row_len..(row + 1) * row_len].swap_with_slice(&mut bottom[..row_len]);
+    }
+}
+
+/// Whether `region` is nonempty and lies within a `width` x `height`
+/// display. No region means the whole display, which always fits.
+fn region_fits(region: Option<Region>, width: usize, height: usize) -> bool {
+    match region {
+        Some((x, y, w, h)) => {
+            w > 0 && h > 0 && x.saturating_add(w) <= width && y.saturating_add(h) <= height
+        }
+        None => true,
+    }
+}
+
+/// Callback invoked by a `DisplayWatch` with the new display list.
+type WatchCallback = Box<dyn FnMut(Vec<DisplayInfo>) + Send>;
+
+/// Keeps a display-change callback registered. Dropping it unregisters the
+/// callback.
+pub struct DisplayWatch {
+    _inner: ffi::DisplayWatch,
+}
+
+/// Calls `callback` with the new display list whenever displays are added,
+/// removed, or reconfigured.
+///
+/// The callback runs on a background thread on Linux and Windows. On macOS
+/// CoreGraphics delivers it through the main thread's run loop, so the
+/// application must be running one.
+pub fn watch_displays<F>(callback: F) -> Result<DisplayWatch, CaptureError>
+where
+    F: FnMut(Vec<DisplayInfo>) + Send + 'static,
+{
+    let inner = ffi::watch_displays(Box::new(callback))?;
+    Ok(DisplayWatch { _inner: inner })
+}
+
+/// Forget the cached display list, so the next capture or `list_displays`
+/// asks the OS again.
+///
+/// Only macOS caches it, for up to half a second, and it is dropped on its
+/// own when a `watch_displays` callback sees a change. Elsewhere this does
+/// nothing.
+pub fn invalidate_display_cache() {
+    ffi::invalidate_display_cache()
+}
+
+/// A way of capturing the screen on Linux, for `set_linux_backend_order`.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// `XShmGetImage` through the MIT-SHM extension, which saves copying the
+    /// pixels over the X socket. Only works with a local X server.
+    XShm,
+    /// Plain `XGetImage`, which works with any X server.
+    Xlib,
+}
+
+/// Set the order in which one-off captures such as `get_screenshot` try the
+/// Linux backends. The first one to succeed is used; errors that aren't
+/// the backend's fault, like a missing screen, are returned at once. An
+/// empty `order` restores the default, `[XShm, Xlib]`.
+///
+/// `Capturer` always uses `XGetSubImage` into its reused image.
+#[cfg(target_os = "linux")]
+pub fn set_linux_backend_order(order: Vec<Backend>) {
+    ffi::set_backend_order(order)
+}
+
+/// The order in which one-off captures try the Linux backends.
+#[cfg(target_os = "linux")]
+pub fn linux_backend_order() -> Vec<Backend> {
+    ffi::backend_order()
+}
+
+/// Timing and size information about a single capture.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureStats {
+    /// Wall-clock time spent in the platform capture, including the copy
+    /// out of the native buffer.
+    pub capture_duration: Duration,
+    /// Time the platform took to grab the pixels: `XShmGetImage` or
+    /// `XGetImage` on Linux, `CGDisplayCreateImage` on macOS, and `BitBlt`
+    /// on Windows.
+    pub native_duration: Duration,
+    /// Time spent copying the grabbed pixels into the `Screenshot` buffer.
+    pub copy_duration: Duration,
+    /// Number of bytes copied into the `Screenshot` buffer.
+    pub bytes_copied: usize,
+}
+
+/// Get a screenshot of the display identified by `id`.
+///
+/// Unlike `get_screenshot`, which always grabs the whole virtual desktop on
+/// Windows, this captures a single monitor on every platform.
+pub fn get_screenshot_for(id: DisplayId) -> ScreenResult {
+    ffi::get_screenshot_for(id)
+}
+
+/// Get a screenshot of the display whose `DisplayInfo::name` is `name`.
+///
+/// Names survive reboots and re-plugging better than indices or native
+/// handles, so this is the most stable way to pick a monitor in scripts.
+pub fn get_screenshot_by_name(name: &str) -> ScreenResult {
+    let displays = list_displays()?;
+    match displays
+        .iter()
+        .find(|info| info.name.as_deref() == Some(name))
+    {
+        Some(info) => ffi::get_screenshot_for(DisplayId::Index(info.index)),
+        None => Err(CaptureError::DisplayNotFound(DisplayId::Name(
+            name.to_string(),
+        ))),
+    }
+}
+
+/// Get a screenshot of the `width` x `height` region at (`x`, `y`) of the
+/// display whose `DisplayInfo::name` is `name`. The region is in that
+/// display's own coordinates, which are points on macOS, and must lie
+/// within it.
+pub fn get_screenshot_named_region(
+    name: &str,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> ScreenResult {
+    let region = (x, y, width, height);
+    let (screen, (global_x, global_y)) = named_region(&list_displays()?, name, region)?;
+    if cfg!(target_os = "linux") {
+        // Each X screen has its own root window, and the global rectangle
+        // is on the default one's.
+        let mut frame = Screenshot::default();
+        ffi::capture_region_into(screen, region, &mut frame)?;
+        Ok(frame)
+    } else {
+        get_screenshot_global_rect(global_x, global_y, width, height)
+    }
+}
+
+/// The index of the display named `name` among `displays`, and where
+/// `region` of it starts in `DisplayInfo` coordinates. Fails unless the
+/// display exists and `region` lies within it.
+fn named_region(
+    displays: &[DisplayInfo],
+    name: &str,
+    region: Region,
+) -> Result<(usize, (i32, i32)), CaptureError> {
+    let info = match displays
+        .iter()
+        .find(|info| info.name.as_deref() == Some(name))
+    {
+        Some(info) => info,
+        None => {
+            return Err(CaptureError::DisplayNotFound(DisplayId::Name(
+                name.to_string(),
+            )))
+        }
+    };
+    let (x, y, width, height) = region;
+    if x.saturating_add(width) > info.width || y.saturating_add(height) > info.height {
+        return Err(CaptureError::InvalidArgument(
+            "Region extends past the display.",
+        ));
+    }
+    Ok((info.index, (info.x + x as i32, info.y + y as i32)))
+}
+
+/// Get a screenshot of the requested display like `get_screenshot`, but
+/// turn a panic during the capture into `CaptureError::Panic` instead of
+/// letting it unwind into the caller, for long-running services.
+///
+/// This is a safety net: a panic here is a bug worth reporting. It can't
+/// help when panics abort the process (`panic = "abort"`), and the panic
+/// hook still runs, so the message is printed as usual.
+pub fn try_get_screenshot(screen: usize) -> ScreenResult {
+    match panic::catch_unwind(|| get_screenshot(screen as _)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let message = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                String::new()
+            };
+            Err(CaptureError::Panic { message })
+        }
+    }
+}
+
+/// Wait for `delay`, then get a screenshot of the requested display, giving
+/// the user time to arrange the screen first.
+///
+/// This sleeps the calling thread. No lock or display connection is held
+/// while it waits, since every capture opens its own, so captures on other
+/// threads go ahead as usual.
+pub fn get_screenshot_delayed(screen: usize, delay: Duration) -> ScreenResult {
+    thread::sleep(delay);
+    get_screenshot(screen as _)
+}
+
+/// Capture a burst of `count` frames of `screen`, one every `interval`,
+/// reusing one `Capturer`. Like `Capturer::frames`, a burst that falls
+/// behind doesn't make up for it with frames in quick succession.
+///
+/// The first capture that fails ends the burst, and its error is returned
+/// in place of the frames captured so far.
+pub fn capture_sequence(
+    screen: usize,
+    count: usize,
+    interval: Duration,
+) -> Result<Vec<Screenshot>, CaptureError> {
+    let mut capturer = Capturer::new(screen)?;
+    let mut frames = Vec::with_capacity(count);
+    let mut due = Instant::now();
+    for _ in 0..count {
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        } else {
+            due = now;
+        }
+        due += interval;
+        frames.push(capturer.capture()?.clone());
+    }
+    Ok(frames)
+}
+
+/// How often `capture_when_stable` captures while it waits.
+const STABLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Capture `screen` once its content has settled, as after an animation,
+/// so nothing is grabbed mid-transition. Frames are captured every 50 ms
+/// with one `Capturer`, and the first frame of a stretch lasting
+/// `stability_window` in which no pixel differs from it by more than
+/// `tolerance` in any channel is returned.
+///
+/// Fails with `Timeout` if the screen hasn't settled after `timeout`.
+/// While waiting, this costs about as much CPU as recording at 20 frames
+/// a second: each poll is a full capture and comparison.
+pub fn capture_when_stable(
+    screen: usize,
+    stability_window: Duration,
+    tolerance: u8,
+    timeout: Duration,
+) -> ScreenResult {
+    let mut capturer = Capturer::new(screen)?;
+    let start = Instant::now();
+    let mut stable = capturer.capture()?.clone();
+    let mut stable_since = Instant::now();
+    loop {
+        if stable_since.elapsed() >= stability_window {
+            return Ok(stable);
+        }
+        if start.elapsed() >= timeout {
+            return Err(CaptureError::Timeout);
+        }
+        thread::sleep(STABLE_POLL_INTERVAL);
+        let frame = capturer.capture()?;
+        if frame.changed_pixels(&stable, tolerance) != Some(0) {
+            stable = frame.clone();
+            stable_since = Instant::now();
+        }
+    }
+}
+
+/// Record `frames` frames of `screen`, one every `interval`, with
+/// `capture_sequence`, and save them to `path` as a looping animated GIF
+/// that plays at the same pace. Requires the `gif` feature.
+///
+/// Frames are box-downscaled by the smallest whole factor that brings
+/// their longest side to 800 pixels or less, and each is quantized to its
+/// own 256-color palette. Fails if the display changes size mid-burst.
+#[cfg(feature = "gif")]
+pub fn capture_gif(
+    screen: usize,
+    frames: usize,
+    interval: Duration,
+    path: &Path,
+) -> Result<(), CaptureError> {
+    let shots = capture_sequence(screen, frames, interval)?;
+    let mut w = io::BufWriter::new(File::create(path)?);
+    write_gif(&shots, interval, &mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Encode `shots` as an animated GIF into `w`, as `capture_gif` does.
+#[cfg(feature = "gif")]
+fn write_gif<W: Write>(shots: &[Screenshot], interval: Duration, w: W) -> Result<(), CaptureError> {
+    fn encode_err(err: gif::EncodingError) -> CaptureError {
+        CaptureError::Encode(err.to_string())
+    }
+
+    let first = match shots.first() {
+        Some(first) if first.width > 0 && first.height > 0 => first,
+        _ => {
+            return Err(CaptureError::InvalidArgument(
+                "A GIF needs at least one nonempty frame.",
+            ))
+        }
+    };
+    let factor = cmp::max(first.width, first.height).div_ceil(GIF_MAX_SIDE);
+    let width = first.width.div_ceil(factor) as u16;
+    let height = first.height.div_ceil(factor) as u16;
+    // GIF delays are in hundredths of a second.
+    let delay = cmp::min((interval.as_millis() + 5) / 10, u16::MAX as u128) as u16;
+
+    let mut encoder = gif::Encoder::new(w, width, height, &[]).map_err(encode_err)?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(encode_err)?;
+    for shot in shots {
+        first.check_same_size(shot)?;
+        let mut rgba = Vec::from(shot.downscale_box(factor)?);
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut rgba, GIF_QUANTIZE_SPEED);
+        frame.delay = delay;
+        encoder.write_frame(&frame).map_err(encode_err)?;
+    }
+    encoder.into_inner()?;
+    Ok(())
+}
+
+/// Get a screenshot of the requested display, along with how long the
+/// platform capture took, split into the native grab and the copy out of it.
+pub fn get_screenshot_with_stats(
+    screen: usize,
+) -> Result<(Screenshot, CaptureStats), CaptureError> {
+    let start = Instant::now();
+    let mut screenshot = Screenshot::default();
+    let mut stats = CaptureStats::default();
+    ffi::capture_into_timed(screen, &mut screenshot, &mut stats)?;
+    stats.capture_duration = start.elapsed();
+    stats.bytes_copied = screenshot.raw_len();
+    Ok((screenshot, stats))
+}
+
+/// Get a screenshot of a rectangle of the virtual desktop, which may span
+/// several displays. Parts no display covers are filled with black.
+///
+/// Coordinates are those of `DisplayInfo`. On macOS they are points, so the
+/// image is larger than `width` x `height` on Retina displays. On Linux they
+/// are relative to the default X screen's root window.
+pub fn get_screenshot_global_rect(x: i32, y: i32, width: usize, height: usize) -> ScreenResult {
+    if width == 0 || height == 0 {
+        return Err(CaptureError::InvalidArgument(
+            "Can't capture an empty rectangle.",
+        ));
+    }
+    ffi::get_screenshot_global_rect(x, y, width, height)
+}
+
+/// Clamp a rectangle, given relative to the top-left of display `screen` as
+/// numbered by `list_displays`, to that display's bounds.
+///
+/// Returns the `(x, y, width, height)` of the part on the display, or an
+/// error if none of it is.
+pub fn clamp_rect_to_display(
+    screen: usize,
+    x: i32,
+    y: i32,
+    width: usize,
+    height: usize,
+) -> Result<(usize, usize, usize, usize), CaptureError> {
+    let displays = list_displays()?;
+    let info = match displays.get(screen) {
+        Some(info) => info,
+        None => return Err(CaptureError::DisplayNotFound(DisplayId::Index(screen))),
+    };
+    let left = cmp::max(x as i64, 0);
+    let top = cmp::max(y as i64, 0);
+    let right = cmp::min(x as i64 + width as i64, info.width as i64);
+    let bottom = cmp::min(y as i64 + height as i64, info.height as i64);
+    if right <= left || bottom <= top {
+        return Err(CaptureError::InvalidArgument(
+            "Rectangle lies entirely outside the display.",
+        ));
+    }
+    Ok((
+        left as usize,
+        top as usize,
+        (right - left) as usize,
+        (bottom - top) as usize,
+    ))
+}
+
+/// Options for `get_screenshot_with_options`.
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Color-match the capture to sRGB. Only affects macOS, where captures
+    /// otherwise stay in the display's own color space, such as Display P3
+    /// on wide-gamut screens.
+    pub convert_to_srgb: bool,
+    /// Include layered (translucent or shaped) windows, by passing
+    /// `CAPTUREBLT` to `BitBlt`. Only affects Windows. Turning it off makes
+    /// the copy much faster and avoids cursor flicker on some systems, but
+    /// layered windows are missing from the capture. On by default.
+    pub include_layered_windows: bool,
+    /// Keep the display's component depth instead of reducing it to 8 bits,
+    /// so HDR displays give 16-bit components (see
+    /// `Screenshot::bits_per_component`). Only affects macOS.
+    pub preserve_depth: bool,
+    /// Channel order of the returned screenshot's 4-byte pixels. Pixels are
+    /// reordered while they're copied out of the platform's buffer, which
+    /// is cheaper than converting afterwards. `Bgra` by default.
+    pub output_format: PixelFormat,
+    /// Windows to leave out of the capture, as `HWND`s cast to `usize`. Only
+    /// affects Windows, and needs Windows 10 version 2004 or later.
+    ///
+    /// Each window is marked `WDA_EXCLUDEFROMCAPTURE` for the capture and
+    /// its old display affinity put back afterwards, whether or not the
+    /// capture succeeded. Windows only allows this for windows owned by
+    /// the calling process; if any can't be marked, nothing is captured and
+    /// an error is returned.
+    pub exclude_hwnds: Vec<usize>,
+    /// How many times to capture again when the display changes size during
+    /// a capture, which can leave a torn or wrongly sized image on macOS
+    /// and Windows instead of an error. The size is looked up in
+    /// `list_displays` before and after each capture; if it still changes
+    /// on the last try, the capture fails with
+    /// `CaptureError::DisplayReconfigured`. 0, the default, skips the
+    /// check and its two display lookups.
+    pub reconfigure_retries: u32,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> CaptureOptions {
+        CaptureOptions {
+            convert_to_srgb: false,
+            include_layered_windows: true,
+            preserve_depth: false,
+            output_format: PixelFormat::Bgra,
+            exclude_hwnds: Vec::new(),
+            reconfigure_retries: 0,
+        }
+    }
+}
+
+/// Get a screenshot of the requested display, as adjusted by `options`.
+pub fn get_screenshot_with_options(screen: usize, options: &CaptureOptions) -> ScreenResult {
+    if options.reconfigure_retries == 0 {
+        return ffi::get_screenshot_with_options(screen, options);
+    }
+    for _ in 0..=options.reconfigure_retries {
+        let before = captured_area_size(screen)?;
+        let screenshot = ffi::get_screenshot_with_options(screen, options)?;
+        if captured_area_size(screen)? == before {
+            return Ok(screenshot);
+        }
+        warn!(
+            "display {} changed size during the capture; retrying",
+            screen
+        );
+    }
+    Err(CaptureError::DisplayReconfigured)
+}
+
+/// Capture the requested display into `frame`, reusing its buffer when it is
+/// already large enough, and return how long the capture took.
+///
+/// This is `get_screenshot` without the per-call allocation, which makes it
+/// suitable for measuring the platform capture cost on its own.
+pub fn capture_into(screen: usize, frame: &mut Screenshot) -> Result<Duration, CaptureError> {
+    let start = Instant::now();
+    ffi::capture_into(screen, frame)?;
+    Ok(start.elapsed())
+}
+
+/// Capture the requested display again into `target`, which is usually an
+/// earlier screenshot of it, for code that holds a `Screenshot` rather than
+/// a `Capturer`.
+///
+/// The pixels are written into `target`'s existing buffer. It only
+/// reallocates when the display has grown past the buffer's capacity, as
+/// after a resolution change; when it shrinks, the spare capacity is kept.
+pub fn refresh_screenshot(screen: usize, target: &mut Screenshot) -> Result<(), CaptureError> {
+    ffi::capture_into(screen, target)
+}
+
+/// Repeatedly captures one display into the same buffer.
+///
+/// On Linux the X connection and the image the server writes into are also
+/// kept between frames, and only remade when the capture size changes.
+pub struct Capturer {
+    screen: usize,
+    frame: Screenshot,
+    info: DisplayInfo,
+    region: Option<Region>,
+    cache: ffi::CaptureCache,
+    /// The last frame `capture_diff_only` returned.
+    reference: Option<Screenshot>,
+}
+
+/// The `(x, y, width, height)` of the smallest rectangle covering all of
+/// `displays`, or `None` if they cover nothing.
+fn virtual_bounds(displays: &[DisplayInfo]) -> Option<(i32, i32, usize, usize)> {
+    let left = displays.iter().map(|d| d.x).min()?;
+    let top = displays.iter().map(|d| d.y).min()?;
+    let right = displays.iter().map(|d| d.x + d.width as i32).max()?;
+    let bottom = displays.iter().map(|d| d.y + d.height as i32).max()?;
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some((left, top, (right - left) as usize, (bottom - top) as usize))
+}
+
+/// A display's reported size in millimeters, or `None` if either side is
+/// zero (or nonsense), as it is when the OS doesn't know.
+fn physical_size_mm(width: f64, height: f64) -> Option<(u32, u32)> {
+    if width >= 1.0 && height >= 1.0 && width < u32::MAX as f64 && height < u32::MAX as f64 {
+        Some((width.round() as u32, height.round() as u32))
+    } else {
+        None
+    }
+}
+
+/// The size, in `DisplayInfo` units, of what `get_screenshot(screen)`
+/// grabs: the whole virtual desktop on Windows, and the display elsewhere.
+fn captured_area_size(screen: usize) -> Result<(usize, usize), CaptureError> {
+    if cfg!(target_os = "windows") {
+        let (_, _, width, height) = virtual_bounds(&list_displays()?)
+            .ok_or(CaptureError::DisplayNotFound(DisplayId::Virtual))?;
+        Ok((width, height))
+    } else {
+        let info = display_info(screen)?;
+        Ok((info.width, info.height))
+    }
+}
+
+/// Look up display `screen` in `list_displays()`.
+fn display_info(screen: usize) -> Result<DisplayInfo, CaptureError> {
+    list_displays()?
+        .into_iter()
+        .nth(screen)
+        .ok_or(CaptureError::DisplayNotFound(DisplayId::Index(screen)))
+}
+
+impl Capturer {
+    /// Create a capturer for the display `get_screenshot(screen)` would grab.
+    /// Fails with `DisplayNotFound` if there's no such display.
+    pub fn new(screen: usize) -> Result<Capturer, CaptureError> {
+        Ok(Capturer {
+            screen,
+            frame: Screenshot::default(),
+            info: display_info(screen)?,
+            region: None,
+            cache: ffi::CaptureCache::new(),
+            reference: None,
+        })
+    }
+
+    /// The display's geometry as of construction or the last
+    /// `refresh_display_info`.
+    ///
+    /// On Windows, where captures cover the whole virtual desktop, this
+    /// describes monitor `screen` rather than the captured area.
+    pub fn display_info(&self) -> &DisplayInfo {
+        &self.info
+    }
+
+    /// Query the display's geometry again, e.g. after a mode change reported
+    /// by `watch_displays`.
+    pub fn refresh_display_info(&mut self) -> Result<(), CaptureError> {
+        self.info = display_info(self.screen)?;
+        Ok(())
+    }
+
+    /// Whether the display, and the connection or handles kept for it, are
+    /// still live. They may not be after the machine sleeps or the display
+    /// is unplugged, and `reconnect` makes new ones. This doesn't capture.
+    pub fn is_valid(&self) -> bool {
+        self.cache.is_valid(self.screen)
+    }
+
+    /// Drop the connection and handles kept between frames and query the
+    /// display's geometry again, so the next capture starts afresh. Fails
+    /// with `DisplayNotFound`, changing nothing, if the display is gone.
+    pub fn reconnect(&mut self) -> Result<(), CaptureError> {
+        self.info = display_info(self.screen)?;
+        self.cache.reconnect();
+        Ok(())
+    }
+
+    /// Capture only the `(x, y, width, height)` rectangle of the display
+    /// from now on, or all of it again for `None`. The rectangle is in the
+    /// display's own coordinates (the virtual desktop's on Windows, points
+    /// on macOS), and captures fail with `InvalidArgument` if it doesn't fit.
+    ///
+    /// The frame buffer is kept, so it only grows when the region does.
+    pub fn set_region(&mut self, rect: Option<(usize, usize, usize, usize)>) {
+        self.region = rect;
+    }
+
+    /// Capture a new frame, overwriting the previous one.
+    pub fn capture(&mut self) -> Result<&Screenshot, CaptureError> {
+        ffi::capture_cached(&mut self.cache, self.screen, self.region, &mut self.frame)?;
+        Ok(&self.frame)
+    }
+
+    /// Capture a new frame right after the display's next vertical blank, so
+    /// it isn't torn by an update in progress.
+    ///
+    /// Only macOS synchronizes to the refresh, through `CVDisplayLink`. On
+    /// other platforms this is the same as `capture`.
+    pub fn capture_vsync(&mut self) -> Result<&Screenshot, CaptureError> {
+        ffi::capture_into_vsync(self.screen, self.region, &mut self.frame)?;
+        Ok(&self.frame)
+    }
+
+    /// Capture a new frame and return only how long it took.
+    pub fn capture_timed(&mut self) -> Result<Duration, CaptureError> {
+        let start = Instant::now();
+        self.capture()?;
+        Ok(start.elapsed())
+    }
+
+    /// Capture a frame, and return it unless fewer than `min_changed_pixels`
+    /// pixels differ from the last frame this returned, by more than
+    /// `tolerance` in some channel. The first frame, and any frame whose
+    /// size differs from the last, is always returned.
+    pub fn capture_diff_only(
+        &mut self,
+        tolerance: u8,
+        min_changed_pixels: usize,
+    ) -> Result<Option<Screenshot>, CaptureError> {
+        self.capture()?;
+        if let Some(ref reference) = self.reference {
+            match self.frame.changed_pixels(reference, tolerance) {
+                Some(changed) if changed < min_changed_pixels => return Ok(None),
+                _ => {}
+            }
+        }
+        self.reference = Some(self.frame.clone());
+        Ok(Some(self.frame.clone()))
+    }
+
+    /// Capture a frame and copy its pixels into the start of `map`, a row at
+    /// a time with the padding dropped, so another process can read them.
+    /// Returns the frame's width and height; pixels are
+    /// `frame().pixel_width()` bytes in `frame().pixel_format()`.
+    ///
+    /// Fails if `map` is too small for the frame, leaving it untouched.
+    /// Requires the `memmap2` feature.
+    #[cfg(feature = "memmap2")]
+    pub fn capture_into_mmap(
+        &mut self,
+        map: &mut memmap2::MmapMut,
+    ) -> Result<(usize, usize), CaptureError> {
+        let frame = self.capture()?;
+        let packed_len = frame.width * frame.pixel_width;
+        if map.len() < packed_len * frame.height {
+            return Err(CaptureError::InvalidArgument(
+                "Memory map is too small for the frame.",
+            ));
+        }
+        for row in 0..frame.height {
+            let start = row * packed_len;
+            map[start..start + packed_len].copy_from_slice(frame.row_pixels(row));
+        }
+        Ok((frame.width, frame.height))
+    }
+
+    /// Capture a frame and encode it as PNG into `buf`, replacing its
+    /// contents but keeping its capacity. Unlike `capture()` followed by
+    /// `to_png_bytes()`, this makes no new buffers once `buf` has grown to
+    /// fit a frame. Requires the `png` feature.
+    #[cfg(feature = "png")]
+    pub fn capture_png_into(&mut self, buf: &mut Vec<u8>) -> Result<(), CaptureError> {
+        buf.clear();
+        self.capture()?.write_png(buf)
+    }
+
+    /// Capture through DXGI Desktop Duplication rather than GDI. It's much
+    /// faster for recording, reports `dirty_rects`, and grabs monitor
+    /// `screen` alone, where GDI grabs the whole virtual screen; the region
+    /// is then relative to the monitor. Frames fall back to GDI for the
+    /// same area whenever duplication fails, and for good once it turns
+    /// out to be unavailable, such as in Remote Desktop sessions or on
+    /// rotated displays. Needs Windows 8 or later.
+    #[cfg(target_os = "windows")]
+    pub fn set_desktop_duplication(&mut self, enabled: bool) {
+        self.cache.set_duplication(enabled);
+    }
+
+    /// The `(x, y, width, height)` rectangles of the last frame that
+    /// changed since the one before, relative to the frame, if the capture
+    /// backend says. Only Desktop Duplication on Windows does (see
+    /// `set_desktop_duplication`).
+    pub fn dirty_rects(&self) -> Option<&[(usize, usize, usize, usize)]> {
+        self.cache.dirty_rects()
+    }
+
+    /// The most recently captured frame; empty before the first capture.
+    pub fn frame(&self) -> &Screenshot {
+        &self.frame
+    }
+
+    /// Turn the capturer into an endless iterator of frames, captured at
+    /// most `fps` times a second with its region and reused handles.
+    ///
+    /// Panics if `fps` isn't positive and finite.
+    pub fn frames(self, fps: f64) -> FrameStream {
+        assert!(fps > 0.0 && fps.is_finite(), "fps must be positive");
+        FrameStream {
+            capturer: self,
+            interval: Duration::from_secs_f64(1.0 / fps),
+            next: None,
+        }
+    }
+}
+
+/// Frames from `Capturer::frames`. Each `next` sleeps until the frame is
+/// due, then captures it. A stream that falls behind doesn't try to catch
+/// up with a burst of frames.
+pub struct FrameStream {
+    capturer: Capturer,
+    interval: Duration,
+    /// When the next frame is due; `None` before the first.
+    next: Option<Instant>,
+}
+
+impl FrameStream {
+    /// Stop streaming and get the capturer back.
+    pub fn into_capturer(self) -> Capturer {
+        self.capturer
+    }
+}
+
+impl Iterator for FrameStream {
+    type Item = Result<Screenshot, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = Instant::now();
+        let due = match self.next {
+            Some(due) if due > now => {
+                thread::sleep(due - now);
+                due
+            }
+            _ => now,
+        };
+        self.next = Some(due + self.interval);
+        Some(self.capturer.capture().cloned())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod ffi {
+    #![allow(non_upper_case_globals)]
+
+    extern crate xlib;
+
+    use self::xlib::{
+        Atom, Display, Visual, Window, XAllPlanes, XCloseDisplay, XDefaultRootWindow,
+        XDefaultScreen, XDestroyWindow, XFree, XGetImage, XGetWindowAttributes, XHeightOfScreen,
+        XImage, XOpenDisplay, XPending, XRootWindow, XRootWindowOfScreen, XScreenCount,
+        XScreenOfDisplay, XTranslateCoordinates, XWidthOfScreen, XWindowAttributes, ZPixmap,
+    };
+    use libc::{c_char, c_int, c_long, c_uchar, c_uint, c_ulong, c_ushort, c_void};
+    use std::cmp;
+    use std::ffi::CString;
+    use std::mem;
+    use std::ptr::null_mut;
+    use std::slice;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant, SystemTime};
+    use {
+        AlphaMode, Backend, CaptureError, CaptureOptions, CaptureStats, DisplayId, DisplayInfo,
+        PixelFormat, Region, Rotation, ScreenResult, Screenshot, WatchCallback,
+    };
+
+    const RRScreenChangeNotify: c_int = 0;
+    const Success: c_int = 0;
+    const XA_WINDOW: Atom = 33;
+    const RRScreenChangeNotifyMask: c_int = 1 << 0;
+    const RR_Connected: c_ushort = 0;
+    const RR_Rotate_90: c_ushort = 2;
+    const RR_Rotate_180: c_ushort = 4;
+    const RR_Rotate_270: c_ushort = 8;
+    const RR_Interlace: c_ulong = 0x0000_0010;
+    const RR_DoubleScan: c_ulong = 0x0000_0020;
+
+    type RROutput = c_ulong;
+    type RRCrtc = c_ulong;
+    type RRMode = c_ulong;
+
+    #[repr(C)]
+    struct XRRModeInfo {
+        id: RRMode,
+        width: c_uint,
+        height: c_uint,
+        dot_clock: c_ulong,
+        h_sync_start: c_uint,
+        h_sync_end: c_uint,
+        h_total: c_uint,
+        h_skew: c_uint,
+        v_sync_start: c_uint,
+        v_sync_end: c_uint,
+        v_total: c_uint,
+        name: *mut c_char,
+        name_length: c_uint,
+        mode_flags: c_ulong,
+    }
+
+    #[repr(C)]
+    struct XRRCrtcInfo {
+        timestamp: c_ulong,
+        x: c_int,
+        y: c_int,
+        width: c_uint,
+        height: c_uint,
+        mode: RRMode,
+        rotation: c_ushort,
+        noutput: c_int,
+        outputs: *mut RROutput,
+        rotations: c_ushort,
+        npossible: c_int,
+        possible: *mut RROutput,
+    }
+
+    #[repr(C)]
+    struct XRRScreenResources {
+        timestamp: c_ulong,
+        config_timestamp: c_ulong,
+        ncrtc: c_int,
+        crtcs: *mut RRCrtc,
+        noutput: c_int,
+        outputs: *mut RROutput,
+        nmode: c_int,
+        modes: *mut XRRModeInfo,
+    }
+
+    #[repr(C)]
+    struct XRROutputInfo {
+        timestamp: c_ulong,
+        crtc: RRCrtc,
+        name: *mut c_char,
+        name_len: c_int,
+        mm_width: c_ulong,
+        mm_height: c_ulong,
+        connection: c_ushort,
+        subpixel_order: c_ushort,
+        ncrtc: c_int,
+        crtcs: *mut RRCrtc,
+        nclone: c_int,
+        clones: *mut RROutput,
+        nmode: c_int,
+        npreferred: c_int,
+        modes: *mut RRMode,
+    }
+
+    /// How often the watcher thread checks for RandR events.
+    const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    #[link(name = "Xrandr")]
+    extern "C" {
+        fn XRRQueryExtension(
+            display: *mut Display,
+            event_base: *mut c_int,
+            error_base: *mut c_int,
+        ) -> c_int;
+        fn XRRSelectInput(display: *mut Display, window: Window, mask: c_int);
+        fn XRRUpdateConfiguration(event: *mut c_long) -> c_int;
+        fn XRRGetScreenResourcesCurrent(
+            display: *mut Display,
+            window: Window,
+        ) -> *mut XRRScreenResources;
+        fn XRRFreeScreenResources(resources: *mut XRRScreenResources);
+        fn XRRGetOutputInfo(
+            display: *mut Display,
+            resources: *mut XRRScreenResources,
+            output: RROutput,
+        ) -> *mut XRROutputInfo;
+        fn XRRFreeOutputInfo(info: *mut XRROutputInfo);
+        fn XRRGetCrtcInfo(
+            display: *mut Display,
+            resources: *mut XRRScreenResources,
+            crtc: RRCrtc,
+        ) -> *mut XRRCrtcInfo;
+        fn XRRFreeCrtcInfo(info: *mut XRRCrtcInfo);
+        fn XRRGetOutputPrimary(display: *mut Display, window: Window) -> RROutput;
+    }
+
+    #[repr(C)]
+    struct XShmSegmentInfo {
+        shmseg: c_ulong,
+        shmid: c_int,
+        shmaddr: *mut c_char,
+        read_only: c_int,
+    }
+
+    type XErrorHandler = Option<extern "C" fn(*mut Display, *mut c_void) -> c_int>;
+
+    #[link(name = "Xext")]
+    extern "C" {
+        fn XShmQueryExtension(display: *mut Display) -> c_int;
+        fn XShmCreateImage(
+            display: *mut Display,
+            visual: *mut Visual,
+            depth: c_uint,
+            format: c_int,
+            data: *mut c_char,
+            shminfo: *mut XShmSegmentInfo,
+            width: c_uint,
+            height: c_uint,
+        ) -> *mut XImage;
+        fn XShmAttach(display: *mut Display, shminfo: *mut XShmSegmentInfo) -> c_int;
+        fn XShmDetach(display: *mut Display, shminfo: *mut XShmSegmentInfo) -> c_int;
+        fn XShmGetImage(
+            display: *mut Display,
+            drawable: Window,
+            image: *mut XImage,
+            x: c_int,
+            y: c_int,
+            plane_mask: c_ulong,
+        ) -> c_int;
+    }
+
+    extern "C" {
+        // Declared here rather than taken from xlib so the event can be
+        // read as a plain buffer; only its leading `type` field is needed.
+        fn XNextEvent(display: *mut Display, event: *mut c_long) -> c_int;
+        // The error event is never read, so it is left opaque.
+        fn XSetErrorHandler(handler: XErrorHandler) -> XErrorHandler;
+        fn XSync(display: *mut Display, discard: c_int) -> c_int;
+        fn XConnectionNumber(display: *mut Display) -> c_int;
+
+        fn XInternAtom(display: *mut Display, name: *const c_char, only_if_exists: c_int) -> Atom;
+        fn XCreateImage(
+            display: *mut Display,
+            visual: *mut Visual,
+            depth: c_uint,
+            format: c_int,
+            offset: c_int,
+            data: *mut c_char,
+            width: c_uint,
+            height: c_uint,
+            bitmap_pad: c_int,
+            bytes_per_line: c_int,
+        ) -> *mut XImage;
+        fn XGetSubImage(
+            display: *mut Display,
+            drawable: Window,
+            x: c_int,
+            y: c_int,
+            width: c_uint,
+            height: c_uint,
+            plane_mask: c_ulong,
+            format: c_int,
+            dest_image: *mut XImage,
+            dest_x: c_int,
+            dest_y: c_int,
+        ) -> *mut XImage;
+        fn XGetWindowProperty(
+            display: *mut Display,
+            window: Window,
+            property: Atom,
+            long_offset: c_long,
+            long_length: c_long,
+            delete: c_int,
+            req_type: Atom,
+            actual_type: *mut Atom,
+            actual_format: *mut c_int,
+            nitems: *mut c_ulong,
+            bytes_after: *mut c_ulong,
+            prop: *mut *mut c_uchar,
+        ) -> c_int;
+    }
+
+    /// Copy an XImage into a Screenshot, then destroy it.
+    unsafe fn screenshot_from_image(img: *mut XImage) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        copy_image(img, &mut frame, PixelFormat::Bgra)?;
+        Ok(frame)
+    }
+
+    /// Copy `img` into `frame` with its pixels in `format`, reusing its
+    /// buffer, and destroy `img`.
+    unsafe fn copy_image(
+        img: *mut XImage,
+        frame: &mut Screenshot,
+        format: PixelFormat,
+    ) -> Result<(), CaptureError> {
+        if img.is_null() {
+            warn!("XGetImage returned no image");
+            return Err(CaptureError::Native("XGetImage failed."));
+        }
+        frame.captured_at = SystemTime::now();
+        let res = copy_image_data(&*img, frame, format);
+        destroy_image(img);
+        res
+    }
+
+    /// What the XDestroyImage macro does.
+    unsafe fn destroy_image(img: *mut XImage) {
+        let img = &mut *img;
+        // servo/rust-xlib doesn't handle function pointers correctly.
+        // We have to transmute the variable.
+        let destroy_image: extern "C" fn(*mut XImage) -> c_int =
+            mem::transmute(img.f.destroy_image);
+        destroy_image(img);
+    }
+
+    /// Copy `img` into `frame` with its pixels in `format`, reusing its
+    /// buffer.
+    unsafe fn copy_image_data(
+        img: &XImage,
+        frame: &mut Screenshot,
+        format: PixelFormat,
+    ) -> Result<(), CaptureError> {
+        let height = img.height as usize;
+        let width = img.width as usize;
+        let row_len = img.bytes_per_line as usize;
+        let pixel_bits = img.bits_per_pixel as usize;
+        debug!(
+            "X image is {}x{} pixels, {} bits per pixel, {} bytes per line",
+            width, height, pixel_bits, row_len
+        );
+        if pixel_bits % 8 != 0 {
+            return Err(CaptureError::Native("Pixels aren't integral bytes."));
+        }
+        let pixel_width = pixel_bits / 8;
+
+        frame.height = height;
+        frame.width = width;
+        frame.row_len = row_len;
+        frame.pixel_width = pixel_width;
+        frame.color_space = None;
+        frame.format = format;
+        frame.bits_per_component = 8;
+
+        // Copy the image into the frame's buffer, fixing the alpha channel
+        // when xlib cannot retrieve info correctly
+        let size = row_len * height;
+        let src = slice::from_raw_parts(img.data as *const u8, size);
+        let has_alpha = src.iter().enumerate().any(|(n, x)| n % 4 == 3 && *x != 0);
+        if !has_alpha {
+            debug!("X image has no alpha channel; making it opaque");
+        }
+        frame.fill_from(src, PixelFormat::Bgra, !has_alpha);
+        // Only ARGB visuals carry alpha, and X Render premultiplies them.
+        frame.alpha_mode = if has_alpha {
+            AlphaMode::Premultiplied
+        } else {
+            AlphaMode::Opaque
+        };
+        Ok(())
+    }
+
+    pub fn get_screenshot(screen: u32) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        capture_into(screen as usize, &mut frame)?;
+        Ok(frame)
+    }
+
+    pub fn capture_into(screen: usize, frame: &mut Screenshot) -> Result<(), CaptureError> {
+        capture_into_timed(screen, frame, &mut CaptureStats::default())
+    }
+
+    pub fn capture_into_timed(
+        screen: usize,
+        frame: &mut Screenshot,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        capture_timed(screen, None, frame, PixelFormat::Bgra, stats)
+    }
+
+    /// An X connection and an image to capture into, kept between a
+    /// `Capturer`'s frames so each one doesn't allocate a new `XImage`.
+    pub struct CaptureCache {
+        display: *mut Display,
+        image: *mut XImage,
+        /// The screen `image` was made for.
+        screen: usize,
+    }
+
+    // The connection is only ever used by the thread that owns the
+    // `Capturer`, one call at a time.
+    unsafe impl Send for CaptureCache {}
+
+    impl CaptureCache {
+        pub fn new() -> CaptureCache {
+            CaptureCache {
+                display: null_mut(),
+                image: null_mut(),
+                screen: 0,
+            }
+        }
+
+        /// X doesn't say what changed between frames.
+        pub fn dirty_rects(&self) -> Option<&[Region]> {
+            None
+        }
+
+        pub fn is_valid(&self, screen: usize) -> bool {
+            if self.display.is_null() {
+                return ::display_info(screen).is_ok();
+            }
+            unsafe {
+                connection_alive(self.display) && screen < XScreenCount(self.display) as usize
+            }
+        }
+
+        pub fn reconnect(&mut self) {
+            // Closing a dead connection flushes it, which runs Xlib's I/O
+            // error handler and exits the process, so it's leaked instead.
+            if !self.display.is_null() && unsafe { !connection_alive(self.display) } {
+                self.display = null_mut();
+            }
+            *self = CaptureCache::new();
+        }
+    }
+
+    /// Whether the X server is still on the other end of `display`. This
+    /// polls the socket rather than making a request, since a request on a
+    /// dead connection would exit the process.
+    unsafe fn connection_alive(display: *mut Display) -> bool {
+        let mut fd = libc::pollfd {
+            fd: XConnectionNumber(display),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        libc::poll(&mut fd, 1, 0) >= 0
+            && fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) == 0
+    }
+
+    impl Drop for CaptureCache {
+        fn drop(&mut self) {
+            unsafe {
+                if !self.image.is_null() {
+                    destroy_image(self.image);
+                }
+                if !self.display.is_null() {
+                    XCloseDisplay(self.display);
+                }
+            }
+        }
+    }
+
+    /// Capture `region` of `screen`, or all of it, with `XGetSubImage` into
+    /// the cached image, which is only remade when the screen or the size
+    /// of the capture changes.
+    pub fn capture_cached(
+        cache: &mut CaptureCache,
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        unsafe {
+            if cache.display.is_null() {
+                cache.display = XOpenDisplay(null_mut());
+                if cache.display.is_null() {
+                    warn!("XOpenDisplay failed; is DISPLAY set?");
+                    return Err(CaptureError::Native("Can't open X display."));
+                }
+            }
+            let display = cache.display;
+            if screen >= XScreenCount(display) as usize {
+                return Err(CaptureError::DisplayNotFound(DisplayId::Index(screen)));
+            }
+            let root = XRootWindow(display, screen as c_int);
+            let mut attr: XWindowAttributes = mem::zeroed();
+            if XGetWindowAttributes(display, root, &mut attr) == 0 {
+                return Err(CaptureError::Native("Can't get window attributes."));
+            }
+            // XGetSubImage raises a fatal X error for areas outside the window.
+            if !::region_fits(region, attr.width as usize, attr.height as usize) {
+                return Err(CaptureError::InvalidArgument(
+                    "Region extends past the display.",
+                ));
+            }
+            let (x, y, width, height) =
+                region.unwrap_or((0, 0, attr.width as usize, attr.height as usize));
+
+            let stale = cache.image.is_null()
+                || cache.screen != screen
+                || (*cache.image).width as usize != width
+                || (*cache.image).height as usize != height;
+            if stale {
+                if !cache.image.is_null() {
+                    destroy_image(cache.image);
+                    cache.image = null_mut();
+                }
+                debug!(
+                    "allocating a {}x{} image for X screen {}",
+                    width, height, screen
+                );
+                let image = XCreateImage(
+                    display,
+                    attr.visual,
+                    attr.depth as c_uint,
+                    ZPixmap,
+                    0,
+                    null_mut(),
+                    width as c_uint,
+                    height as c_uint,
+                    32,
+                    0,
+                );
+                if image.is_null() {
+                    return Err(CaptureError::Native("XCreateImage failed."));
+                }
+                // XDestroyImage frees the data with free().
+                let size = (*image).bytes_per_line as usize * height;
+                (*image).data = libc::malloc(size) as *mut c_char;
+                if (*image).data.is_null() {
+                    destroy_image(image);
+                    return Err(CaptureError::Native("Can't allocate an X image."));
+                }
+                cache.image = image;
+                cache.screen = screen;
+            }
+
+            let img = XGetSubImage(
+                display,
+                root,
+                x as c_int,
+                y as c_int,
+                width as c_uint,
+                height as c_uint,
+                XAllPlanes(),
+                ZPixmap,
+                cache.image,
+                0,
+                0,
+            );
+            if img.is_null() {
+                warn!("XGetSubImage returned no image");
+                return Err(CaptureError::Native("XGetSubImage failed."));
+            }
+            frame.captured_at = SystemTime::now();
+            copy_image_data(&*cache.image, frame, PixelFormat::Bgra)
+        }
+    }
+
+    /// Capture `region` of `screen`, or all of it, into `frame` with its
+    /// pixels in `format`.
+    fn capture_into_as(
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+        format: PixelFormat,
+    ) -> Result<(), CaptureError> {
+        capture_timed(screen, region, frame, format, &mut CaptureStats::default())
+    }
+
+    /// `capture_into_as`, recording in `stats` how long the backend that
+    /// succeeded took to grab the image and to copy it into `frame`.
+    fn capture_timed(
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+        format: PixelFormat,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        unsafe {
+            let display = XOpenDisplay(null_mut());
+            if display.is_null() {
+                warn!("XOpenDisplay failed; is DISPLAY set?");
+                return Err(CaptureError::Native("Can't open X display."));
+            }
+            // XScreenOfDisplay doesn't check its index.
+            let count = XScreenCount(display) as usize;
+            if screen >= count {
+                XCloseDisplay(display);
+                warn!(
+                    "X screen {} requested, but there are only {}",
+                    screen, count
+                );
+                return Err(CaptureError::DisplayNotFound(DisplayId::Index(screen)));
+            }
+            let screen_number = screen;
+            let screen = XScreenOfDisplay(display, screen as c_int);
+            let root = XRootWindowOfScreen(screen);
+
+            let mut attr: XWindowAttributes = mem::zeroed();
+            if XGetWindowAttributes(display, root, &mut attr) == 0 {
+                warn!("XGetWindowAttributes failed on the root window");
+                XCloseDisplay(display);
+                return Err(CaptureError::Native("Can't get window attributes."));
+            }
+            let (x, y, width, height) =
+                region.unwrap_or((0, 0, attr.width as usize, attr.height as usize));
+            // XGetImage raises a fatal X error for areas outside the window.
+            if !::region_fits(region, attr.width as usize, attr.height as usize) {
+                XCloseDisplay(display);
+                return Err(CaptureError::InvalidArgument(
+                    "Region extends past the display.",
+                ));
+            }
+
+            let mut res = Err(CaptureError::Native("No capture backend is enabled."));
+            for backend in backend_order() {
+                debug!("capturing X screen {} with {:?}", screen_number, backend);
+                res = match backend {
+                    Backend::XShm => capture_shm(
+                        display,
+                        root,
+                        &attr,
+                        (x, y, width, height),
+                        frame,
+                        format,
+                        stats,
+                    ),
+                    Backend::Xlib => {
+                        let start = Instant::now();
+                        let img = XGetImage(
+                            display,
+                            root,
+                            x as c_int,
+                            y as c_int,
+                            width as c_uint,
+                            height as c_uint,
+                            XAllPlanes(),
+                            ZPixmap,
+                        );
+                        stats.native_duration = start.elapsed();
+                        let start = Instant::now();
+                        let res = copy_image(img, frame, format);
+                        stats.copy_duration = start.elapsed();
+                        res
+                    }
+                };
+                match res {
+                    Err(CaptureError::Native(msg)) => {
+                        warn!("{:?} capture failed: {}", backend, msg)
+                    }
+                    _ => break,
+                }
+            }
+            XDestroyWindow(display, root);
+            XCloseDisplay(display);
+            res
+        }
+    }
+
+    /// Set by `shm_error_handler` when an X error arrives.
+    static SHM_ERROR: AtomicBool = AtomicBool::new(false);
+
+    /// Held while `shm_error_handler` is installed. The handler and
+    /// `SHM_ERROR` are process-wide, so without it concurrent captures
+    /// could save each other's handler as the one to restore.
+    static SHM_ERROR_LOCK: Mutex<()> = Mutex::new(());
+
+    extern "C" fn shm_error_handler(_: *mut Display, _: *mut c_void) -> c_int {
+        SHM_ERROR.store(true, Ordering::SeqCst);
+        0
+    }
+
+    /// Capture `region` of `root` with `XShmGetImage`, which has the X server
+    /// write the pixels into shared memory instead of sending them over the
+    /// socket. Fails on servers without MIT-SHM, including remote ones.
+    unsafe fn capture_shm(
+        display: *mut Display,
+        root: Window,
+        attr: &XWindowAttributes,
+        region: Region,
+        frame: &mut Screenshot,
+        format: PixelFormat,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        let (x, y, width, height) = region;
+        if XShmQueryExtension(display) == 0 {
+            return Err(CaptureError::Native("MIT-SHM is unavailable."));
+        }
+        let mut info: XShmSegmentInfo = mem::zeroed();
+        let image = XShmCreateImage(
+            display,
+            attr.visual,
+            attr.depth as c_uint,
+            ZPixmap,
+            null_mut(),
+            &mut info,
+            width as c_uint,
+            height as c_uint,
+        );
+        if image.is_null() {
+            return Err(CaptureError::Native("XShmCreateImage failed."));
+        }
+        let size = (*image).bytes_per_line as usize * height;
+        info.shmid = libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600);
+        if info.shmid < 0 {
+            destroy_image(image);
+            return Err(CaptureError::Native("Can't create shared memory."));
+        }
+        info.shmaddr = libc::shmat(info.shmid, null_mut(), 0) as *mut c_char;
+        if info.shmaddr as isize == -1 {
+            libc::shmctl(info.shmid, libc::IPC_RMID, null_mut());
+            destroy_image(image);
+            return Err(CaptureError::Native("Can't attach shared memory."));
+        }
+        (*image).data = info.shmaddr;
+
+        // Attaching fails with an X error on a remote server, and the
+        // default handler would end the process.
+        let guard = SHM_ERROR_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        SHM_ERROR.store(false, Ordering::SeqCst);
+        let previous = XSetErrorHandler(Some(shm_error_handler));
+        let attached = XShmAttach(display, &mut info) != 0;
+        XSync(display, 0);
+        // The segment goes away once both sides have detached.
+        libc::shmctl(info.shmid, libc::IPC_RMID, null_mut());
+        let res = if !attached || SHM_ERROR.load(Ordering::SeqCst) {
+            Err(CaptureError::Native("XShmAttach failed."))
+        } else {
+            let start = Instant::now();
+            let got = XShmGetImage(display, root, image, x as c_int, y as c_int, XAllPlanes());
+            XSync(display, 0);
+            stats.native_duration = start.elapsed();
+            let res = if got == 0 || SHM_ERROR.load(Ordering::SeqCst) {
+                Err(CaptureError::Native("XShmGetImage failed."))
+            } else {
+                frame.captured_at = SystemTime::now();
+                let start = Instant::now();
+                let res = copy_image_data(&*image, frame, format);
+                stats.copy_duration = start.elapsed();
+                res
+            };
+            XShmDetach(display, &mut info);
+            XSync(display, 0);
+            res
+        };
+        XSetErrorHandler(previous);
+        drop(guard);
+
+        // XDestroyImage would free() the shared memory.
+        (*image).data = null_mut();
+        destroy_image(image);
+        libc::shmdt(info.shmaddr as *const c_void);
+        res
+    }
+
+    /// Set by `set_backend_order`; empty means `DEFAULT_BACKEND_ORDER`.
+    static BACKEND_ORDER: Mutex<Vec<Backend>> = Mutex::new(Vec::new());
+
+    const DEFAULT_BACKEND_ORDER: [Backend; 2] = [Backend::XShm, Backend::Xlib];
+
+    pub fn set_backend_order(order: Vec<Backend>) {
+        *BACKEND_ORDER.lock().unwrap_or_else(|e| e.into_inner()) = order;
+    }
+
+    pub fn backend_order() -> Vec<Backend> {
+        let order = BACKEND_ORDER.lock().unwrap_or_else(|e| e.into_inner());
+        if order.is_empty() {
+            DEFAULT_BACKEND_ORDER.to_vec()
+        } else {
+            order.clone()
+        }
+    }
+
+    /// Only `output_format` applies to X.
+    pub fn get_screenshot_with_options(screen: usize, options: &CaptureOptions) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        capture_into_as(screen, None, &mut frame, options.output_format)?;
+        Ok(frame)
+    }
+
+    /// X has no vblank notification for the root window, so capture now.
+    pub fn capture_into_vsync(
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        debug!("X can't wait for vblank; capturing immediately");
+        capture_into_as(screen, region, frame, PixelFormat::Bgra)
+    }
+
+    pub fn get_screenshot_for(id: DisplayId) -> ScreenResult {
+        let screen = match id {
+            DisplayId::Index(index) => index,
+            DisplayId::Native(number) => number as usize,
+            DisplayId::Name(ref name) => return ::get_screenshot_by_name(name),
+            DisplayId::Primary | DisplayId::Virtual => unsafe {
+                let display = XOpenDisplay(null_mut());
+                if display.is_null() {
+                    return Err(CaptureError::Native("Can't open X display."));
+                }
+                let screen = XDefaultScreen(display);
+                XCloseDisplay(display);
+                screen as usize
+            },
+        };
+        get_screenshot(screen as u32)
+    }
+
+    /// The window named by the root window's `_NET_ACTIVE_WINDOW` property.
+    unsafe fn active_window(display: *mut Display, root: Window) -> Option<Window> {
+        let name = CString::new("_NET_ACTIVE_WINDOW").unwrap();
+        let property = XInternAtom(display, name.as_ptr(), 1);
+        if property == 0 {
+            return None;
+        }
+
+        let mut actual_type: Atom = 0;
+        let mut actual_format: c_int = 0;
+        let mut nitems: c_ulong = 0;
+        let mut bytes_after: c_ulong = 0;
+        let mut prop: *mut c_uchar = null_mut();
+        let status = XGetWindowProperty(
+            display,
+            root,
+            property,
+            0,
+            1,
+            0,
+            XA_WINDOW,
+            &mut actual_type,
+            &mut actual_format,
+            &mut nitems,
+            &mut bytes_after,
+            &mut prop,
+        );
+        if status != Success || prop.is_null() {
+            return None;
+        }
+        // Format 32 properties come back as an array of longs.
+        let window = if actual_type == XA_WINDOW && actual_format == 32 && nitems == 1 {
+            *(prop as *const Window)
+        } else {
+            0
+        };
+        XFree(prop as *mut _);
+        if window == 0 {
+            None
+        } else {
+            Some(window)
+        }
+    }
+
+    /// Capture the part of `window` that is on screen.
+    unsafe fn window_screenshot(display: *mut Display, window: Window) -> ScreenResult {
+        let root = XDefaultRootWindow(display);
+        let mut attr: XWindowAttributes = mem::zeroed();
+        let mut root_attr: XWindowAttributes = mem::zeroed();
+        if XGetWindowAttributes(display, window, &mut attr) == 0
+            || XGetWindowAttributes(display, root, &mut root_attr) == 0
+        {
+            return Err(CaptureError::Native("Can't get window attributes."));
+        }
+
+        // XGetImage fails on windows that hang off the screen, so grab the
+        // window's visible bounds from the root window instead.
+        let mut x = 0;
+        let mut y = 0;
+        let mut child: Window = 0;
+        XTranslateCoordinates(display, window, root, 0, 0, &mut x, &mut y, &mut child);
+        let left = cmp::max(x, 0);
+        let top = cmp::max(y, 0);
+        let right = cmp::min(x + attr.width, root_attr.width);
+        let bottom = cmp::min(y + attr.height, root_attr.height);
+        if right <= left || bottom <= top {
+            return Err(CaptureError::Native("Window is not on screen."));
+        }
+
+        let img = XGetImage(
+            display,
+            root,
+            left,
+            top,
+            (right - left) as c_uint,
+            (bottom - top) as c_uint,
+            XAllPlanes(),
+            ZPixmap,
+        );
+        screenshot_from_image(img)
+    }
+
+    pub fn capture_region_into(
+        screen: usize,
+        region: Region,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        capture_into_as(screen, Some(region), frame, PixelFormat::Bgra)
+    }
+
+    pub fn get_screenshot_global_rect(x: i32, y: i32, width: usize, height: usize) -> ScreenResult {
+        unsafe {
+            let display = XOpenDisplay(null_mut());
+            if display.is_null() {
+                return Err(CaptureError::Native("Can't open X display."));
+            }
+            let root = XDefaultRootWindow(display);
+            let mut root_attr: XWindowAttributes = mem::zeroed();
+            if XGetWindowAttributes(display, root, &mut root_attr) == 0 {
+                XCloseDisplay(display);
+                return Err(CaptureError::Native("Can't get window attributes."));
+            }
+
+            // The root window spans every monitor of the screen, but XGetImage
+            // fails on areas outside it, so grab the overlap and paste it onto
+            // a black canvas.
+            let left = cmp::max(x, 0);
+            let top = cmp::max(y, 0);
+            let right = cmp::min(x + width as i32, root_attr.width);
+            let bottom = cmp::min(y + height as i32, root_attr.height);
+            if right <= left || bottom <= top {
+                XCloseDisplay(display);
+                return Ok(Screenshot::black(width, height, 4));
+            }
+
+            let img = XGetImage(
+                display,
+                root,
+                left,
+                top,
+                (right - left) as c_uint,
+                (bottom - top) as c_uint,
+                XAllPlanes(),
+                ZPixmap,
+            );
+            XCloseDisplay(display);
+            let part = screenshot_from_image(img)?;
+            let mut canvas = Screenshot::black(width, height, part.pixel_width);
+            canvas.captured_at = part.captured_at;
+            canvas.alpha_mode = part.alpha_mode;
+            canvas.paste(&part, (top - y) as usize, (left - x) as usize);
+            Ok(canvas)
+        }
+    }
+
+    /// Get a screenshot of the window the window manager reports as active.
+    pub fn get_active_window_screenshot() -> ScreenResult {
+        unsafe {
+            let display = XOpenDisplay(null_mut());
+            if display.is_null() {
+                return Err(CaptureError::Native("Can't open X display."));
+            }
+            let res = match active_window(display, XDefaultRootWindow(display)) {
+                Some(window) => window_screenshot(display, window),
+                None => Err(CaptureError::Native("Can't determine the active window.")),
+            };
+            XCloseDisplay(display);
+            res
+        }
+    }
+
+    /// What `list_displays` reports about an X screen's main RandR output.
+    struct OutputDetails {
+        name: String,
+        refresh_rate_hz: Option<f64>,
+        physical_size_mm: Option<(u32, u32)>,
+        rotation: Rotation,
+    }
+
+    /// How `crtc` rotates its output. RandR measures rotation
+    /// counterclockwise.
+    unsafe fn crtc_rotation(
+        display: *mut Display,
+        resources: *mut XRRScreenResources,
+        crtc: RRCrtc,
+    ) -> Rotation {
+        if crtc == 0 {
+            return Rotation::Normal;
+        }
+        let info = XRRGetCrtcInfo(display, resources, crtc);
+        if info.is_null() {
+            return Rotation::Normal;
+        }
+        let turns = match (*info).rotation & 0xf {
+            RR_Rotate_90 => 1,
+            RR_Rotate_180 => 2,
+            RR_Rotate_270 => 3,
+            _ => 0,
+        };
+        XRRFreeCrtcInfo(info);
+        Rotation::from_quarter_turns(-turns)
+    }
+
+    /// Refresh rate of the mode `crtc` is driving, from its pixel clock and
+    /// total frame size.
+    unsafe fn refresh_rate(
+        display: *mut Display,
+        resources: *mut XRRScreenResources,
+        crtc: RRCrtc,
+    ) -> Option<f64> {
+        if crtc == 0 {
+            return None;
+        }
+        let info = XRRGetCrtcInfo(display, resources, crtc);
+        if info.is_null() {
+            return None;
+        }
+        let mode_id = (*info).mode;
+        XRRFreeCrtcInfo(info);
+
+        let modes = slice::from_raw_parts((*resources).modes, (*resources).nmode as usize);
+        let mode = modes.iter().find(|mode| mode.id == mode_id)?;
+        let mut v_total = mode.v_total as f64;
+        if mode.mode_flags & RR_DoubleScan != 0 {
+            v_total *= 2.0;
+        }
+        if mode.mode_flags & RR_Interlace != 0 {
+            v_total /= 2.0;
+        }
+        if mode.dot_clock == 0 || mode.h_total == 0 || mode.v_total == 0 {
+            return None;
+        }
+        Some(mode.dot_clock as f64 / (mode.h_total as f64 * v_total))
+    }
+
+    /// The screen's primary RandR output, or its first connected output when
+    /// no primary is set.
+    unsafe fn main_output(display: *mut Display, root: Window) -> Option<OutputDetails> {
+        let resources = XRRGetScreenResourcesCurrent(display, root);
+        if resources.is_null() {
+            return None;
+        }
+        let outputs = slice::from_raw_parts((*resources).outputs, (*resources).noutput as usize);
+        let primary = XRRGetOutputPrimary(display, root);
+        let mut chosen: *mut XRROutputInfo = null_mut();
+        for &output in outputs {
+            let info = XRRGetOutputInfo(display, resources, output);
+            if info.is_null() {
+                continue;
+            }
+            if (*info).connection == RR_Connected && (chosen.is_null() || output == primary) {
+                if !chosen.is_null() {
+                    XRRFreeOutputInfo(chosen);
+                }
+                chosen = info;
+                if output == primary {
+                    break;
+                }
+            } else {
+                XRRFreeOutputInfo(info);
+            }
+        }
+
+        let details = if chosen.is_null() {
+            None
+        } else {
+            let name =
+                slice::from_raw_parts((*chosen).name as *const u8, (*chosen).name_len as usize);
+            let details = OutputDetails {
+                name: String::from_utf8_lossy(name).into_owned(),
+                refresh_rate_hz: refresh_rate(display, resources, (*chosen).crtc),
+                physical_size_mm: ::physical_size_mm(
+                    (*chosen).mm_width as f64,
+                    (*chosen).mm_height as f64,
+                ),
+                rotation: crtc_rotation(display, resources, (*chosen).crtc),
+            };
+            XRRFreeOutputInfo(chosen);
+            Some(details)
+        };
+        XRRFreeScreenResources(resources);
+        details
+    }
+
+    /// List the X screens of the default display.
+    pub fn list_displays() -> Result<Vec<DisplayInfo>, CaptureError> {
+        unsafe {
+            let display = XOpenDisplay(null_mut());
+            if display.is_null() {
+                return Err(CaptureError::Native("Can't open X display."));
+            }
+            let (mut event_base, mut error_base) = (0, 0);
+            let has_randr = XRRQueryExtension(display, &mut event_base, &mut error_base) != 0;
+            let displays = (0..XScreenCount(display))
+                .map(|index| {
+                    let screen = XScreenOfDisplay(display, index);
+                    let output = if has_randr {
+                        main_output(display, XRootWindowOfScreen(screen))
+                    } else {
+                        None
+                    };
+                    DisplayInfo {
+                        index: index as usize,
+                        x: 0,
+                        y: 0,
+                        width: XWidthOfScreen(screen) as usize,
+                        height: XHeightOfScreen(screen) as usize,
+                        name: output.as_ref().map(|output| output.name.clone()),
+                        refresh_rate_hz: output.as_ref().and_then(|output| output.refresh_rate_hz),
+                        physical_size_mm: output
+                            .as_ref()
+                            .and_then(|output| output.physical_size_mm),
+                        mirror_of: None,
+                        rotation: output.map_or(Rotation::Normal, |output| output.rotation),
+                    }
+                })
+                .collect();
+            XCloseDisplay(display);
+            Ok(displays)
+        }
+    }
+
+    pub struct DisplayWatch {
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Drop for DisplayWatch {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Displays aren't cached on X11.
+    pub fn invalidate_display_cache() {}
+
+    /// Listen for RandR screen changes on a dedicated X connection.
+    pub fn watch_displays(mut callback: WatchCallback) -> Result<DisplayWatch, CaptureError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || unsafe {
+            let display = XOpenDisplay(null_mut());
+            if display.is_null() {
+                let _ = started_tx.send(Err(CaptureError::Native("Can't open X display.")));
+                return;
+            }
+            let mut event_base = 0;
+            let mut error_base = 0;
+            if XRRQueryExtension(display, &mut event_base, &mut error_base) == 0 {
+                XCloseDisplay(display);
+                let _ = started_tx.send(Err(CaptureError::Native("RandR isn't available.")));
+                return;
+            }
+            for screen in 0..XScreenCount(display) {
+                XRRSelectInput(
+                    display,
+                    XRootWindow(display, screen),
+                    RRScreenChangeNotifyMask,
+                );
+            }
+            let _ = started_tx.send(Ok(()));
+
+            // XEvent is a 24-long union.
+            let mut event = [0 as c_long; 24];
+            while !thread_stop.load(Ordering::SeqCst) {
+                let mut changed = false;
+                while XPending(display) > 0 {
+                    XNextEvent(display, event.as_mut_ptr());
+                    let event_type = *(event.as_ptr() as *const c_int);
+                    if event_type == event_base + RRScreenChangeNotify {
+                        XRRUpdateConfiguration(event.as_mut_ptr());
+                        changed = true;
+                    }
+                }
+                if changed {
+                    if let Ok(displays) = list_displays() {
+                        callback(displays);
+                    }
+                }
+                thread::sleep(WATCH_POLL_INTERVAL);
+            }
+            XCloseDisplay(display);
+        });
+
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok(DisplayWatch {
+                stop,
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => Err(CaptureError::Native("Display watcher thread died.")),
+        }
+    }
+
+    #[cfg(feature = "clipboard")]
+    pub use self::clipboard::copy_to_clipboard;
+
+    /// X11 clipboard ownership. The selection owner has to answer every paste
+    /// request itself, so the data lives on a thread of this process.
+    #[cfg(feature = "clipboard")]
+    mod clipboard {
+        use super::xlib::{
+            Atom, Display, Window, XCloseDisplay, XDefaultRootWindow, XDestroyWindow, XOpenDisplay,
+        };
+        use super::{XInternAtom, XNextEvent};
+        use libc::{c_int, c_long, c_uchar, c_uint, c_ulong};
+        use std::cmp;
+        use std::ffi::CString;
+        use std::ptr::{null, null_mut};
+        use std::sync::mpsc;
+        use std::thread;
+        use {CaptureError, Screenshot};
+
+        const XA_ATOM: Atom = 4;
+        const XA_INTEGER: Atom = 19;
+        const PropertyNotify: c_int = 28;
+        const SelectionClear: c_int = 29;
+        const SelectionRequest: c_int = 30;
+        const SelectionNotify: c_int = 31;
+        const PropModeReplace: c_int = 0;
+        const PropModeAppend: c_int = 2;
+        const PropertyDelete: c_int = 1;
+        const PropertyChangeMask: c_long = 1 << 22;
+
+        /// Bytes sent per step of an INCR transfer.
+        const INCR_CHUNK_LEN: usize = 64 * 1024;
+
+        extern "C" {
+            fn XCreateSimpleWindow(
+                display: *mut Display,
+                parent: Window,
+                x: c_int,
+                y: c_int,
+                width: c_uint,
+                height: c_uint,
+                border_width: c_uint,
+                border: c_ulong,
+                background: c_ulong,
+            ) -> Window;
+            fn XSetSelectionOwner(
+                display: *mut Display,
+                selection: Atom,
+                owner: Window,
+                time: c_ulong,
+            ) -> c_int;
+            fn XGetSelectionOwner(display: *mut Display, selection: Atom) -> Window;
+            fn XChangeProperty(
+                display: *mut Display,
+                window: Window,
+                property: Atom,
+                type_: Atom,
+                format: c_int,
+                mode: c_int,
+                data: *const c_uchar,
+                nelements: c_int,
+            ) -> c_int;
+            fn XSendEvent(
+                display: *mut Display,
+                window: Window,
+                propagate: c_int,
+                event_mask: c_long,
+                event: *mut c_long,
+            ) -> c_int;
+            fn XFlush(display: *mut Display) -> c_int;
+            fn XSelectInput(display: *mut Display, window: Window, event_mask: c_long) -> c_int;
+            fn XExtendedMaxRequestSize(display: *mut Display) -> c_long;
+            fn XMaxRequestSize(display: *mut Display) -> c_long;
+        }
+
+        #[repr(C)]
+        struct XSelectionRequestEvent {
+            type_: c_int,
+            serial: c_ulong,
+            send_event: c_int,
+            display: *mut Display,
+            owner: Window,
+            requestor: Window,
+            selection: Atom,
+            target: Atom,
+            property: Atom,
+            time: c_ulong,
+        }
+
+        #[repr(C)]
+        struct XSelectionEvent {
+            type_: c_int,
+            serial: c_ulong,
+            send_event: c_int,
+            display: *mut Display,
+            requestor: Window,
+            selection: Atom,
+            target: Atom,
+            property: Atom,
+            time: c_ulong,
+        }
+
+        #[repr(C)]
+        struct XPropertyEvent {
+            type_: c_int,
+            serial: c_ulong,
+            send_event: c_int,
+            display: *mut Display,
+            window: Window,
+            atom: Atom,
+            time: c_ulong,
+            state: c_int,
+        }
+
+        /// The atoms the clipboard thread needs, and when it took ownership.
+        struct Selection {
+            targets: Atom,
+            timestamp: Atom,
+            incr: Atom,
+            png: Atom,
+            owned_at: c_ulong,
+        }
+
+        /// An INCR transfer in progress: the PNG goes into `requestor`'s
+        /// `property` a chunk at a time, each once the requestor has deleted
+        /// the one before.
+        struct Transfer {
+            requestor: Window,
+            property: Atom,
+            sent: usize,
+        }
+
+        unsafe fn intern(display: *mut Display, name: &str) -> Atom {
+            let name = CString::new(name).unwrap();
+            XInternAtom(display, name.as_ptr(), 0)
+        }
+
+        /// A current server timestamp, from the `PropertyNotify` that
+        /// appending nothing to a property of `window` generates. `window`
+        /// must select `PropertyChangeMask`.
+        unsafe fn server_time(display: *mut Display, window: Window, property: Atom) -> c_ulong {
+            XChangeProperty(
+                display,
+                window,
+                property,
+                XA_INTEGER,
+                8,
+                PropModeAppend,
+                null(),
+                0,
+            );
+            // XEvent is a 24-long union.
+            let mut event = [0 as c_long; 24];
+            loop {
+                XNextEvent(display, event.as_mut_ptr());
+                let event = &*(event.as_ptr() as *const XPropertyEvent);
+                if event.type_ == PropertyNotify && event.window == window {
+                    return event.time;
+                }
+            }
+        }
+
+        /// Answer one `SelectionRequest` for the PNG in `png`, returning the
+        /// transfer to continue if it's too big to send at once.
+        unsafe fn answer_selection_request(
+            display: *mut Display,
+            req: &XSelectionRequestEvent,
+            selection: &Selection,
+            png: &[u8],
+        ) -> Option<Transfer> {
+            // Obsolete clients leave the property unset and expect the target.
+            let property = if req.property == 0 {
+                req.target
+            } else {
+                req.property
+            };
+            // Anything that doesn't fit in a single request goes by INCR.
+            let max_len = cmp::max(XExtendedMaxRequestSize(display), XMaxRequestSize(display)) * 4;
+            let mut transfer = None;
+            let stored = if req.target == selection.targets {
+                let supported = [selection.targets, selection.timestamp, selection.png];
+                XChangeProperty(
+                    display,
+                    req.requestor,
+                    property,
+                    XA_ATOM,
+                    32,
+                    PropModeReplace,
+                    supported.as_ptr() as *const c_uchar,
+                    supported.len() as c_int,
+                );
+                true
+            } else if req.target == selection.timestamp {
+                let owned_at = [selection.owned_at as c_long];
+                XChangeProperty(
+                    display,
+                    req.requestor,
+                    property,
+                    XA_INTEGER,
+                    32,
+                    PropModeReplace,
+                    owned_at.as_ptr() as *const c_uchar,
+                    1,
+                );
+                true
+            } else if req.target == selection.png && (png.len() as c_long) < max_len - 1024 {
+                XChangeProperty(
+                    display,
+                    req.requestor,
+                    property,
+                    selection.png,
+                    8,
+                    PropModeReplace,
+                    png.as_ptr(),
+                    png.len() as c_int,
+                );
+                true
+            } else if req.target == selection.png && req.property != 0 {
+                // The requestor deletes the property to ask for each chunk.
+                XSelectInput(display, req.requestor, PropertyChangeMask);
+                let len = [png.len() as c_long];
+                XChangeProperty(
+                    display,
+                    req.requestor,
+                    property,
+                    selection.incr,
+                    32,
+                    PropModeReplace,
+                    len.as_ptr() as *const c_uchar,
+                    1,
+                );
+                transfer = Some(Transfer {
+                    requestor: req.requestor,
+                    property,
+                    sent: 0,
+                });
+                true
+            } else {
+                false
+            };
+
+            let mut notify = XSelectionEvent {
+                type_: SelectionNotify,
+                serial: 0,
+                send_event: 1,
+                display,
+                requestor: req.requestor,
+                selection: req.selection,
+                target: req.target,
+                property: if stored { property } else { 0 },
+                time: req.time,
+            };
+            XSendEvent(
+                display,
+                req.requestor,
+                0,
+                0,
+                &mut notify as *mut XSelectionEvent as *mut c_long,
+            );
+            XFlush(display);
+            transfer
+        }
+
+        /// Send the next chunk of `transfer`, or the empty one that ends it.
+        /// Returns whether the transfer is over.
+        unsafe fn continue_transfer(
+            display: *mut Display,
+            transfer: &mut Transfer,
+            png_atom: Atom,
+            png: &[u8],
+        ) -> bool {
+            let end = cmp::min(transfer.sent + INCR_CHUNK_LEN, png.len());
+            let chunk = &png[transfer.sent..end];
+            XChangeProperty(
+                display,
+                transfer.requestor,
+                transfer.property,
+                png_atom,
+                8,
+                PropModeReplace,
+                chunk.as_ptr(),
+                chunk.len() as c_int,
+            );
+            transfer.sent = end;
+            if chunk.is_empty() {
+                XSelectInput(display, transfer.requestor, 0);
+            }
+            XFlush(display);
+            chunk.is_empty()
+        }
+
+        /// Take ownership of `CLIPBOARD` from a thread that serves the image as
+        /// `image/png` until another client claims the selection and any
+        /// INCR transfers under way are finished.
+        pub fn copy_to_clipboard(screenshot: &Screenshot) -> Result<(), CaptureError> {
+            let png = screenshot.to_png_bytes()?;
+            let (started_tx, started_rx) = mpsc::channel();
+
+            thread::spawn(move || unsafe {
+                let display = XOpenDisplay(null_mut());
+                if display.is_null() {
+                    let _ = started_tx.send(Err(CaptureError::Native("Can't open X display.")));
+                    return;
+                }
+                let root = XDefaultRootWindow(display);
+                let window = XCreateSimpleWindow(display, root, 0, 0, 1, 1, 0, 0, 0);
+                XSelectInput(display, window, PropertyChangeMask);
+                let clipboard = intern(display, "CLIPBOARD");
+                let png_atom = intern(display, "image/png");
+                // ICCCM forbids taking ownership at CurrentTime.
+                let owned_at = server_time(display, window, png_atom);
+                let selection = Selection {
+                    targets: intern(display, "TARGETS"),
+                    timestamp: intern(display, "TIMESTAMP"),
+                    incr: intern(display, "INCR"),
+                    png: png_atom,
+                    owned_at,
+                };
+
+                XSetSelectionOwner(display, clipboard, window, owned_at);
+                if XGetSelectionOwner(display, clipboard) != window {
+                    XDestroyWindow(display, window);
+                    XCloseDisplay(display);
+                    let _ = started_tx.send(Err(CaptureError::Native(
+                        "Can't take ownership of the clipboard.",
+                    )));
+                    return;
+                }
+                let _ = started_tx.send(Ok(()));
+
+                // XEvent is a 24-long union.
+                let mut event = [0 as c_long; 24];
+                let mut owned = true;
+                let mut transfers: Vec<Transfer> = Vec::new();
+                while owned || !transfers.is_empty() {
+                    XNextEvent(display, event.as_mut_ptr());
+                    let event_type = *(event.as_ptr() as *const c_int);
+                    if event_type == SelectionClear {
+                        owned = false;
+                    } else if event_type == SelectionRequest && owned {
+                        let req = &*(event.as_ptr() as *const XSelectionRequestEvent);
+                        transfers.extend(answer_selection_request(display, req, &selection, &png));
+                    } else if event_type == PropertyNotify {
+                        let ev = &*(event.as_ptr() as *const XPropertyEvent);
+                        let pending = transfers
+                            .iter()
+                            .position(|t| t.requestor == ev.window && t.property == ev.atom);
+                        if let (PropertyDelete, Some(i)) = (ev.state, pending) {
+                            if continue_transfer(display, &mut transfers[i], png_atom, &png) {
+                                transfers.swap_remove(i);
+                            }
+                        }
+                    }
+                }
+                XDestroyWindow(display, window);
+                XCloseDisplay(display);
+            });
+
+            match started_rx.recv() {
+                Ok(res) => res,
+                Err(_) => Err(CaptureError::Native("Clipboard thread died.")),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod ffi {
+    #![allow(non_upper_case_globals, non_camel_case_types, dead_code)]
+
+    use libc;
+    #[cfg(feature = "clipboard")]
+    use std::mem;
+    use std::ptr::null_mut;
+    use std::slice;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant, SystemTime};
+    use ScreenResult;
+    use Screenshot;
+    use {
+        AlphaMode, CaptureError, CaptureOptions, CaptureStats, DisplayId, DisplayInfo, PixelFormat,
+        Region, Rotation, WatchCallback,
+    };
+
+    type CFIndex = libc::c_long;
+    type CFDataRef = *const u8; // *const CFData
+    type CFArrayRef = *const libc::c_void;
+    type CFDictionaryRef = *const libc::c_void;
+    type CFNumberRef = *const libc::c_void;
+    type CFStringRef = *const libc::c_void;
+    type CFNumberType = CFIndex;
+
+    #[cfg(target_pointer_width = "32")]
+    type CGFloat = libc::c_float;
+    #[cfg(target_pointer_width = "64")]
+    type CGFloat = libc::c_double;
+    type CGError = libc::int32_t;
+
+    type CGDirectDisplayID = libc::uint32_t;
+    type CGDisplayCount = libc::uint32_t;
+    type CGImageRef = *mut u8;
+    // *mut CGImage
+    type CGDataProviderRef = *mut u8; // *mut CGDataProvider
+    type CGDisplayChangeSummaryFlags = libc::uint32_t;
+    type CGDisplayReconfigurationCallBack =
+        extern "C" fn(CGDirectDisplayID, CGDisplayChangeSummaryFlags, *mut libc::c_void);
+    type CGWindowID = libc::uint32_t;
+    type CGWindowListOption = libc::uint32_t;
+    type CGWindowImageOption = libc::uint32_t;
+    type CFStringEncoding = libc::uint32_t;
+    type CGColorSpaceRef = *mut libc::c_void;
+    type CGContextRef = *mut libc::c_void;
+    type CGDisplayModeRef = *mut libc::c_void;
+    type CGBitmapInfo = libc::uint32_t;
+    type CVDisplayLinkRef = *mut libc::c_void;
+    type CVOptionFlags = libc::uint64_t;
+    type CVReturn = libc::int32_t;
+    type CVDisplayLinkOutputCallback = extern "C" fn(
+        CVDisplayLinkRef,
+        *const libc::c_void, // *const CVTimeStamp
+        *const libc::c_void, // *const CVTimeStamp
+        CVOptionFlags,
+        *mut CVOptionFlags,
+        *mut libc::c_void,
+    ) -> CVReturn;
+    type io_service_t = libc::c_uint;
+    type IOOptionBits = libc::uint32_t;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CGPoint {
+        x: CGFloat,
+        y: CGFloat,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CGSize {
+        width: CGFloat,
+        height: CGFloat,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CGRect {
+        origin: CGPoint,
+        size: CGSize,
+    }
+
+    const kCGErrorSuccess: CGError = 0;
+    const kCGErrorFailure: CGError = 1000;
+    const CGDisplayNoErr: CGError = kCGErrorSuccess;
+    const kCGDisplayBeginConfigurationFlag: CGDisplayChangeSummaryFlags = 1 << 0;
+    const kCGNullWindowID: CGWindowID = 0;
+    const kCGWindowListOptionOnScreenOnly: CGWindowListOption = 1 << 0;
+    const kCGWindowListOptionIncludingWindow: CGWindowListOption = 1 << 3;
+    const kCGWindowListExcludeDesktopElements: CGWindowListOption = 1 << 4;
+    const kCGWindowImageDefault: CGWindowImageOption = 0;
+    const kCGWindowImageBoundsIgnoreFraming: CGWindowImageOption = 1 << 0;
+    const kCFNumberSInt32Type: CFNumberType = 3;
+    const kCFStringEncodingUTF8: CFStringEncoding = 0x0800_0100;
+    const kIODisplayOnlyPreferredName: IOOptionBits = 0x0000_0200;
+    const kCVReturnSuccess: CVReturn = 0;
+    const kCGImageAlphaPremultipliedFirst: CGBitmapInfo = 2;
+    const kCGBitmapByteOrder32Little: CGBitmapInfo = 2 << 12;
+    const kCGBitmapAlphaInfoMask: CGBitmapInfo = 0x1f;
+    const kCGBitmapByteOrderMask: CGBitmapInfo = 0x7000;
+    const kCGImageAlphaFirst: CGBitmapInfo = 4;
+    const kCGImageAlphaLast: CGBitmapInfo = 3;
+    const kCGImageAlphaNoneSkipFirst: CGBitmapInfo = 6;
+    const kCGImageAlphaPremultipliedLast: CGBitmapInfo = 1;
+    const kCGBitmapByteOrder16Little: CGBitmapInfo = 1 << 12;
+
+    /// How long `capture_into_vsync` waits for a vblank; displays refresh far
+    /// more often than this, so hitting it means the link never fired.
+    const VSYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGGetActiveDisplayList(
+            max_displays: libc::uint32_t,
+            active_displays: *mut CGDirectDisplayID,
+            display_count: *mut CGDisplayCount,
+        ) -> CGError;
+        fn CGMainDisplayID() -> CGDirectDisplayID;
+        fn CGDisplayBounds(display: CGDirectDisplayID) -> CGRect;
+        fn CGDisplayMirrorsDisplay(display: CGDirectDisplayID) -> CGDirectDisplayID;
+        fn CGDisplayScreenSize(display: CGDirectDisplayID) -> CGSize;
+        fn CGDisplayRotation(display: CGDirectDisplayID) -> f64;
+        fn CGDisplayCreateImage(displayID: CGDirectDisplayID) -> CGImageRef;
+        fn CGDisplayCreateImageForRect(display: CGDirectDisplayID, rect: CGRect) -> CGImageRef;
+        fn CGDisplayIOServicePort(display: CGDirectDisplayID) -> io_service_t;
+        fn CGDisplayCopyDisplayMode(display: CGDirectDisplayID) -> CGDisplayModeRef;
+        fn CGDisplayModeGetRefreshRate(mode: CGDisplayModeRef) -> libc::c_double;
+        fn CGDisplayModeRelease(mode: CGDisplayModeRef);
+        fn CGDisplayRegisterReconfigurationCallback(
+            callback: CGDisplayReconfigurationCallBack,
+            user_info: *mut libc::c_void,
+        ) -> CGError;
+        fn CGDisplayRemoveReconfigurationCallback(
+            callback: CGDisplayReconfigurationCallBack,
+            user_info: *mut libc::c_void,
+        ) -> CGError;
+        fn CGImageRelease(image: CGImageRef);
+        fn CGImageGetColorSpace(image: CGImageRef) -> CGColorSpaceRef;
+        fn CGImageGetBitmapInfo(image: CGImageRef) -> CGBitmapInfo;
+
+        fn CGColorSpaceCreateWithName(name: CFStringRef) -> CGColorSpaceRef;
+        fn CGColorSpaceCopyName(space: CGColorSpaceRef) -> CFStringRef;
+        fn CGColorSpaceRelease(space: CGColorSpaceRef);
+        fn CGBitmapContextCreate(
+            data: *mut libc::c_void,
+            width: libc::size_t,
+            height: libc::size_t,
+            bits_per_component: libc::size_t,
+            bytes_per_row: libc::size_t,
+            space: CGColorSpaceRef,
+            bitmap_info: CGBitmapInfo,
+        ) -> CGContextRef;
+        fn CGBitmapContextCreateImage(context: CGContextRef) -> CGImageRef;
+        fn CGContextDrawImage(context: CGContextRef, rect: CGRect, image: CGImageRef);
+        fn CGContextRelease(context: CGContextRef);
+
+        fn CGImageGetBitsPerComponent(image: CGImageRef) -> libc::size_t;
+        fn CGImageGetBitsPerPixel(image: CGImageRef) -> libc::size_t;
+        fn CGImageGetBytesPerRow(image: CGImageRef) -> libc::size_t;
+        fn CGImageGetDataProvider(image: CGImageRef) -> CGDataProviderRef;
+        fn CGImageGetHeight(image: CGImageRef) -> libc::size_t;
+        fn CGImageGetWidth(image: CGImageRef) -> libc::size_t;
+
+        fn CGDataProviderCopyData(provider: CGDataProviderRef) -> CFDataRef;
+
+        fn CGWindowListCopyWindowInfo(
+            option: CGWindowListOption,
+            relative_to_window: CGWindowID,
+        ) -> CFArrayRef;
+        fn CGWindowListCreateImage(
+            screen_bounds: CGRect,
+            list_option: CGWindowListOption,
+            window_id: CGWindowID,
+            image_option: CGWindowImageOption,
+        ) -> CGImageRef;
+
+        static kCGRectNull: CGRect;
+        static kCGColorSpaceSRGB: CFStringRef;
+        static kCGWindowLayer: CFStringRef;
+        static kCGWindowNumber: CFStringRef;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFDataGetLength(theData: CFDataRef) -> CFIndex;
+        fn CFDataGetBytePtr(theData: CFDataRef) -> *const u8;
+        fn CFRelease(cf: *const libc::c_void);
+
+        fn CFArrayGetCount(array: CFArrayRef) -> CFIndex;
+        fn CFArrayGetValueAtIndex(array: CFArrayRef, idx: CFIndex) -> *const libc::c_void;
+        fn CFDictionaryGetValue(
+            dict: CFDictionaryRef,
+            key: *const libc::c_void,
+        ) -> *const libc::c_void;
+        fn CFNumberGetValue(
+            number: CFNumberRef,
+            the_type: CFNumberType,
+            value_ptr: *mut libc::c_void,
+        ) -> bool;
+        fn CFDictionaryGetCount(dict: CFDictionaryRef) -> CFIndex;
+        fn CFDictionaryGetKeysAndValues(
+            dict: CFDictionaryRef,
+            keys: *mut *const libc::c_void,
+            values: *mut *const libc::c_void,
+        );
+        fn CFStringCreateWithCString(
+            alloc: *const libc::c_void,
+            c_str: *const libc::c_char,
+            encoding: CFStringEncoding,
+        ) -> CFStringRef;
+        fn CFStringGetLength(string: CFStringRef) -> CFIndex;
+        fn CFStringGetMaximumSizeForEncoding(
+            length: CFIndex,
+            encoding: CFStringEncoding,
+        ) -> CFIndex;
+        fn CFStringGetCString(
+            string: CFStringRef,
+            buffer: *mut libc::c_char,
+            buffer_size: CFIndex,
+            encoding: CFStringEncoding,
+        ) -> bool;
+    }
+
+    #[link(name = "CoreVideo", kind = "framework")]
+    extern "C" {
+        fn CVDisplayLinkCreateWithCGDisplay(
+            display: CGDirectDisplayID,
+            link_out: *mut CVDisplayLinkRef,
+        ) -> CVReturn;
+        fn CVDisplayLinkSetOutputCallback(
+            link: CVDisplayLinkRef,
+            callback: CVDisplayLinkOutputCallback,
+            user_info: *mut libc::c_void,
+        ) -> CVReturn;
+        fn CVDisplayLinkStart(link: CVDisplayLinkRef) -> CVReturn;
+        fn CVDisplayLinkStop(link: CVDisplayLinkRef) -> CVReturn;
+        fn CVDisplayLinkRelease(link: CVDisplayLinkRef);
+    }
+
+    #[cfg(feature = "clipboard")]
+    type Id = *mut libc::c_void;
+    #[cfg(feature = "clipboard")]
+    type Sel = *mut libc::c_void;
+
+    #[cfg(feature = "clipboard")]
+    #[link(name = "objc")]
+    extern "C" {
+        fn objc_getClass(name: *const libc::c_char) -> Id;
+        fn sel_registerName(name: *const libc::c_char) -> Sel;
+        // Called through a transmuted pointer with each call's real signature.
+        fn objc_msgSend();
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[link(name = "AppKit", kind = "framework")]
+    extern "C" {
+        static NSPasteboardTypePNG: Id;
+    }
+
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {
+        fn IODisplayCreateInfoDictionary(
+            framebuffer: io_service_t,
+            options: IOOptionBits,
+        ) -> CFDictionaryRef;
+    }
+
+    /// How long `active_displays` trusts its last answer.
+    const DISPLAY_CACHE_TTL: Duration = Duration::from_millis(500);
+
+    static DISPLAY_CACHE: Mutex<Option<(Instant, Vec<CGDirectDisplayID>)>> = Mutex::new(None);
+
+    pub fn invalidate_display_cache() {
+        *DISPLAY_CACHE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Get the IDs of all active displays, from the cache when it is fresh.
+    unsafe fn active_displays() -> Result<Vec<CGDirectDisplayID>, CaptureError> {
+        let mut cache = DISPLAY_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((at, ref disps)) = *cache {
+            if at.elapsed() < DISPLAY_CACHE_TTL {
+                return Ok(disps.clone());
+            }
+        }
+        let disps = query_active_displays()?;
+        *cache = Some((Instant::now(), disps.clone()));
+        Ok(disps)
+    }
+
+    /// Ask CoreGraphics for the IDs of all active displays.
+    unsafe fn query_active_displays() -> Result<Vec<CGDirectDisplayID>, CaptureError> {
+        // Get number of displays
+        let mut count: CGDisplayCount = 0;
+        let mut err = CGDisplayNoErr;
+        err = CGGetActiveDisplayList(0, 0 as *mut CGDirectDisplayID, &mut count);
+        if err != CGDisplayNoErr {
+            return Err(CaptureError::Native("Error getting number of displays."));
+        }
+
+        // Get list of displays
+        let mut disps: Vec<CGDirectDisplayID> = vec![0; count as usize];
+        err = CGGetActiveDisplayList(
+            disps.len() as libc::uint32_t,
+            disps.as_mut_ptr(),
+            &mut count,
+        );
+        if err != CGDisplayNoErr {
+            return Err(CaptureError::Native("Error getting list of displays."));
+        }
+        disps.truncate(count as usize);
+        Ok(disps)
+    }
+
+    /// Copy a CGImage into a Screenshot, then release it.
+    unsafe fn screenshot_from_image(cg_img: CGImageRef) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        copy_image(cg_img, &mut frame)?;
+        Ok(frame)
+    }
+
+    /// Copy `cg_img` into `frame`, reusing its buffer, and release `cg_img`.
+    /// Deep images, as HDR displays give, are reduced to 8 bits per
+    /// component first.
+    unsafe fn copy_image(cg_img: CGImageRef, frame: &mut Screenshot) -> Result<(), CaptureError> {
+        copy_image_as_is(reduce_depth(cg_img)?, frame, None)
+    }
+
+    /// Like `copy_image`, but keeps the component depth of `cg_img`, and
+    /// reorders 4-byte pixels to `format` if one is given.
+    unsafe fn copy_image_as_is(
+        cg_img: CGImageRef,
+        frame: &mut Screenshot,
+        format: Option<PixelFormat>,
+    ) -> Result<(), CaptureError> {
+        if cg_img.is_null() {
+            warn!("CoreGraphics returned no image");
+            return Err(CaptureError::Native("Error creating image."));
+        }
+        frame.captured_at = SystemTime::now();
+
+        // Get info about image
+        let width = CGImageGetWidth(cg_img) as usize;
+        let height = CGImageGetHeight(cg_img) as usize;
+        let row_len = CGImageGetBytesPerRow(cg_img) as usize;
+        let pixel_bits = CGImageGetBitsPerPixel(cg_img) as usize;
+        debug!(
+            "CGImage is {}x{} pixels, {} bits per pixel, {} bytes per row, bitmap info {:#x}",
+            width,
+            height,
+            pixel_bits,
+            row_len,
+            CGImageGetBitmapInfo(cg_img)
+        );
+        if pixel_bits % 8 != 0 {
+            CGImageRelease(cg_img);
+            return Err(CaptureError::Native("Pixels aren't integral bytes."));
+        }
+
+        // Copy image into a Vec buffer
+        let cf_data = CGDataProviderCopyData(CGImageGetDataProvider(cg_img));
+        let raw_len = CFDataGetLength(cf_data) as usize;
+
+        // Rows may be padded, e.g. in window images.
+        let res = if row_len * height != raw_len || width * pixel_bits > row_len * 8 {
+            warn!(
+                "CGImage holds {} bytes, expected {} rows of {}",
+                raw_len, height, row_len
+            );
+            Err(CaptureError::Native(
+                "Image size is inconsistent with W*H*D.",
+            ))
+        } else {
+            let src_format = pixel_format(CGImageGetBitmapInfo(cg_img));
+            frame.height = height;
+            frame.width = width;
+            frame.row_len = row_len;
+            frame.pixel_width = pixel_bits / 8;
+            frame.color_space = color_space_name(CGImageGetColorSpace(cg_img));
+            frame.format = match format {
+                Some(format) if frame.pixel_width == 4 => format,
+                _ => src_format,
+            };
+            frame.bits_per_component = CGImageGetBitsPerComponent(cg_img) as usize;
+            frame.alpha_mode = alpha_mode(CGImageGetBitmapInfo(cg_img));
+            let src = slice::from_raw_parts(CFDataGetBytePtr(cf_data), raw_len);
+            frame.fill_from(src, src_format, false);
+            Ok(())
+        };
+
+        // Release native objects
+        CGImageRelease(cg_img);
+        CFRelease(cf_data as *const libc::c_void);
+
+        res
+    }
+
+    /// Memory order of a 32-bit pixel with the given bitmap info. For the
+    /// 16-bit little-endian components `redraw` makes, the byte order only
+    /// swaps within a component, so this gives their order too.
+    fn pixel_format(info: CGBitmapInfo) -> PixelFormat {
+        let alpha_first = match info & kCGBitmapAlphaInfoMask {
+            kCGImageAlphaPremultipliedFirst | kCGImageAlphaFirst | kCGImageAlphaNoneSkipFirst => {
+                true
+            }
+            _ => false,
+        };
+        let little_endian = info & kCGBitmapByteOrderMask == kCGBitmapByteOrder32Little;
+        match (little_endian, alpha_first) {
+            (true, true) => PixelFormat::Bgra,
+            (true, false) => PixelFormat::Abgr,
+            (false, true) => PixelFormat::Argb,
+            (false, false) => PixelFormat::Rgba,
+        }
+    }
+
+    fn alpha_mode(info: CGBitmapInfo) -> AlphaMode {
+        match info & kCGBitmapAlphaInfoMask {
+            kCGImageAlphaPremultipliedFirst | kCGImageAlphaPremultipliedLast => {
+                AlphaMode::Premultiplied
+            }
+            kCGImageAlphaFirst | kCGImageAlphaLast => AlphaMode::Straight,
+            _ => AlphaMode::Opaque,
+        }
+    }
+
+    /// The name CoreGraphics gives `space`, if it has one.
+    unsafe fn color_space_name(space: CGColorSpaceRef) -> Option<String> {
+        if space.is_null() {
+            return None;
+        }
+        let name = CGColorSpaceCopyName(space);
+        if name.is_null() {
+            return None;
+        }
+        let res = string_from_cf(name);
+        CFRelease(name);
+        res
+    }
+
+    /// Draw `cg_img` into a `bits`-per-component bitmap in `space` and
+    /// release it. 8-bit bitmaps are BGRA in memory, like every other
+    /// capture; 16-bit ones are RGBA with little-endian components.
+    unsafe fn redraw(
+        cg_img: CGImageRef,
+        space: CGColorSpaceRef,
+        bits: usize,
+    ) -> Result<CGImageRef, CaptureError> {
+        if cg_img.is_null() {
+            return Err(CaptureError::Native("Error creating image."));
+        }
+        let width = CGImageGetWidth(cg_img);
+        let height = CGImageGetHeight(cg_img);
+        let info = if bits == 16 {
+            kCGImageAlphaPremultipliedLast | kCGBitmapByteOrder16Little
+        } else {
+            kCGImageAlphaPremultipliedFirst | kCGBitmapByteOrder32Little
+        };
+        let ctx = CGBitmapContextCreate(null_mut(), width, height, bits, 0, space, info);
+        if ctx.is_null() {
+            warn!("CGBitmapContextCreate failed for a {}-bit bitmap", bits);
+            CGImageRelease(cg_img);
+            return Err(CaptureError::Native("Can't create a bitmap."));
+        }
+        let bounds = CGRect {
+            origin: CGPoint { x: 0.0, y: 0.0 },
+            size: CGSize {
+                width: width as CGFloat,
+                height: height as CGFloat,
+            },
+        };
+        CGContextDrawImage(ctx, bounds, cg_img);
+        CGImageRelease(cg_img);
+        let converted = CGBitmapContextCreateImage(ctx);
+        CGContextRelease(ctx);
+        Ok(converted)
+    }
+
+    /// Redraw `cg_img` at 8 bits per component in its own color space if
+    /// it's any deeper.
+    unsafe fn reduce_depth(cg_img: CGImageRef) -> Result<CGImageRef, CaptureError> {
+        if cg_img.is_null() || CGImageGetBitsPerComponent(cg_img) <= 8 {
+            return Ok(cg_img);
+        }
+        debug!(
+            "reducing a {}-bit image to 8 bits per component",
+            CGImageGetBitsPerComponent(cg_img)
+        );
+        redraw(cg_img, CGImageGetColorSpace(cg_img), 8)
+    }
+
+    /// Draw `cg_img` into an sRGB bitmap, which makes CoreGraphics
+    /// color-match it, and release `cg_img`. Only re-tagging the image with
+    /// `CGImageCreateCopyWithColorSpace` would leave the pixel values in the
+    /// display's space.
+    unsafe fn convert_to_srgb(cg_img: CGImageRef, bits: usize) -> Result<CGImageRef, CaptureError> {
+        let srgb = CGColorSpaceCreateWithName(kCGColorSpaceSRGB);
+        let res = redraw(cg_img, srgb, bits);
+        CGColorSpaceRelease(srgb);
+        res
+    }
+
+    /// Get a screenshot of the requested display.
+    pub fn get_screenshot(screen: usize) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        capture_into(screen, &mut frame)?;
+        Ok(frame)
+    }
+
+    pub fn get_screenshot_with_options(screen: usize, options: &CaptureOptions) -> ScreenResult {
+        unsafe {
+            let disp_id = *active_displays()?
+                .get(screen)
+                .ok_or(CaptureError::DisplayNotFound(DisplayId::Index(screen)))?;
+            let cg_img = CGDisplayCreateImage(disp_id);
+            let deep = !cg_img.is_null() && CGImageGetBitsPerComponent(cg_img) > 8;
+            let bits = if options.preserve_depth && deep {
+                16
+            } else {
+                8
+            };
+            let cg_img = if options.convert_to_srgb {
+                convert_to_srgb(cg_img, bits)?
+            } else if bits == 16 {
+                redraw(cg_img, CGImageGetColorSpace(cg_img), bits)?
+            } else {
+                reduce_depth(cg_img)?
+            };
+            let mut frame = Screenshot::default();
+            copy_image_as_is(cg_img, &mut frame, Some(options.output_format))?;
+            Ok(frame)
+        }
+    }
+
+    pub fn capture_into(screen: usize, frame: &mut Screenshot) -> Result<(), CaptureError> {
+        capture_into_timed(screen, frame, &mut CaptureStats::default())
+    }
+
+    pub fn capture_into_timed(
+        screen: usize,
+        frame: &mut Screenshot,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        unsafe {
+            // Get screenshot of requested display
+            let disp_id = *active_displays()?
+                .get(screen)
+                .ok_or(CaptureError::DisplayNotFound(DisplayId::Index(screen)))?;
+            debug!("capturing display {} with CGDisplayCreateImage", disp_id);
+            let start = Instant::now();
+            let cg_img = reduce_depth(CGDisplayCreateImage(disp_id))?;
+            stats.native_duration = start.elapsed();
+            let start = Instant::now();
+            let res = copy_image_as_is(cg_img, frame, None);
+            stats.copy_duration = start.elapsed();
+            res
+        }
+    }
+
+    pub fn capture_region_into(
+        screen: usize,
+        region: Region,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        unsafe {
+            let disp_id = match active_displays()?.get(screen) {
+                Some(&disp_id) => disp_id,
+                None => return Err(CaptureError::DisplayNotFound(DisplayId::Index(screen))),
+            };
+            check_region(disp_id, Some(region))?;
+            copy_image(display_image(disp_id, Some(region)), frame)
+        }
+    }
+
+    /// The display a `Capturer` first captured, to tell when the display at
+    /// its index has gone or been replaced.
+    pub struct CaptureCache {
+        display: Option<CGDirectDisplayID>,
+    }
+
+    impl CaptureCache {
+        pub fn new() -> CaptureCache {
+            CaptureCache { display: None }
+        }
+
+        pub fn dirty_rects(&self) -> Option<&[Region]> {
+            None
+        }
+
+        pub fn is_valid(&self, screen: usize) -> bool {
+            // Skip the cached list, which may predate the change.
+            let disps = match unsafe { query_active_displays() } {
+                Ok(disps) => disps,
+                Err(_) => return false,
+            };
+            match self.display {
+                Some(disp_id) => disps.get(screen) == Some(&disp_id),
+                None => screen < disps.len(),
+            }
+        }
+
+        pub fn reconnect(&mut self) {
+            self.display = None;
+        }
+    }
+
+    pub fn capture_cached(
+        cache: &mut CaptureCache,
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        if cache.display.is_none() {
+            cache.display = unsafe { active_displays()? }.get(screen).cloned();
+        }
+        match region {
+            Some(region) => capture_region_into(screen, region, frame),
+            None => capture_into(screen, frame),
+        }
+    }
+
+    /// Fail unless `region` lies within `display`, in points.
+    unsafe fn check_region(
+        display: CGDirectDisplayID,
+        region: Option<Region>,
+    ) -> Result<(), CaptureError> {
+        let size = CGDisplayBounds(display).size;
+        if ::region_fits(region, size.width as usize, size.height as usize) {
+            Ok(())
+        } else {
+            Err(CaptureError::InvalidArgument(
+                "Region extends past the display.",
+            ))
+        }
+    }
+
+    /// An image of `region` of `display`, or of all of it.
+    unsafe fn display_image(display: CGDirectDisplayID, region: Option<Region>) -> CGImageRef {
+        match region {
+            Some((x, y, width, height)) => CGDisplayCreateImageForRect(
+                display,
+                CGRect {
+                    origin: CGPoint {
+                        x: x as CGFloat,
+                        y: y as CGFloat,
+                    },
+                    size: CGSize {
+                        width: width as CGFloat,
+                        height: height as CGFloat,
+                    },
+                },
+            ),
+            None => CGDisplayCreateImage(display),
+        }
+    }
+
+    /// State shared with the display link callback.
+    struct VsyncCapture {
+        display: CGDirectDisplayID,
+        region: Option<Region>,
+        /// The first image taken after a vblank; null until then.
+        image: AtomicPtr<u8>,
+        done: mpsc::SyncSender<()>,
+    }
+
+    extern "C" fn vsync_callback(
+        _link: CVDisplayLinkRef,
+        _now: *const libc::c_void,
+        _output_time: *const libc::c_void,
+        _flags_in: CVOptionFlags,
+        _flags_out: *mut CVOptionFlags,
+        user_info: *mut libc::c_void,
+    ) -> CVReturn {
+        unsafe {
+            let state = &*(user_info as *const VsyncCapture);
+            // The link keeps firing until it's stopped; only the first frame
+            // is kept.
+            if state.image.load(Ordering::SeqCst).is_null() {
+                let image = display_image(state.display, state.region);
+                if state
+                    .image
+                    .compare_exchange(null_mut(), image, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    CGImageRelease(image);
+                }
+                let _ = state.done.try_send(());
+            }
+        }
+        kCVReturnSuccess
+    }
+
+    /// Capture from inside a `CVDisplayLink` callback, which CoreVideo runs
+    /// right after each vertical blank of the display.
+    pub fn capture_into_vsync(
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        unsafe {
+            let disp_id = *active_displays()?
+                .get(screen)
+                .ok_or(CaptureError::DisplayNotFound(DisplayId::Index(screen)))?;
+            check_region(disp_id, region)?;
+
+            let mut link: CVDisplayLinkRef = null_mut();
+            let ret = CVDisplayLinkCreateWithCGDisplay(disp_id, &mut link);
+            if ret != kCVReturnSuccess {
+                warn!("CVDisplayLinkCreateWithCGDisplay failed with {}", ret);
+                return Err(CaptureError::Native("Can't create a display link."));
+            }
+            let (done_tx, done_rx) = mpsc::sync_channel(1);
+            let state = VsyncCapture {
+                display: disp_id,
+                region,
+                image: AtomicPtr::new(null_mut()),
+                done: done_tx,
+            };
+            let user_info = &state as *const VsyncCapture as *mut libc::c_void;
+            let started = CVDisplayLinkSetOutputCallback(link, vsync_callback, user_info)
+                == kCVReturnSuccess
+                && CVDisplayLinkStart(link) == kCVReturnSuccess;
+            if started {
+                let _ = done_rx.recv_timeout(VSYNC_TIMEOUT);
+                // Blocks until a running callback returns, so `state` is no
+                // longer shared afterwards.
+                CVDisplayLinkStop(link);
+            }
+            CVDisplayLinkRelease(link);
+
+            let image = state.image.load(Ordering::SeqCst);
+            if started && image.is_null() {
+                warn!(
+                    "no vblank on display {} within {:?}",
+                    disp_id, VSYNC_TIMEOUT
+                );
+            }
+            if !started {
+                Err(CaptureError::Native("Can't start the display link."))
+            } else if image.is_null() {
+                Err(CaptureError::Native("Timed out waiting for vblank."))
+            } else {
+                copy_image(image, frame)
+            }
+        }
+    }
+
+    pub fn get_screenshot_for(id: DisplayId) -> ScreenResult {
+        unsafe {
+            let disps = active_displays()?;
+            let disp_id = match id {
+                DisplayId::Index(index) => disps.get(index).cloned(),
+                DisplayId::Primary => Some(CGMainDisplayID()),
+                DisplayId::Native(native) => {
+                    disps.iter().cloned().find(|&disp| disp as u64 == native)
+                }
+                DisplayId::Name(ref name) => return ::get_screenshot_by_name(name),
+                DisplayId::Virtual => return get_virtual_screenshot(),
+            };
+            match disp_id {
+                Some(disp_id) => screenshot_from_image(CGDisplayCreateImage(disp_id)),
+                None => Err(CaptureError::DisplayNotFound(id)),
+            }
+        }
+    }
+
+    /// Capture the bounding box of all displays, in global coordinates.
+    fn get_virtual_screenshot() -> ScreenResult {
+        match ::virtual_bounds(&list_displays()?) {
+            Some((x, y, width, height)) => get_screenshot_global_rect(x, y, width, height),
+            None => Err(CaptureError::DisplayNotFound(DisplayId::Virtual)),
+        }
+    }
+
+    /// Read an `i32` entry of a window info dictionary.
+    unsafe fn window_info_i32(info: CFDictionaryRef, key: CFStringRef) -> Option<i32> {
+        let number = CFDictionaryGetValue(info, key);
+        let mut value: i32 = 0;
+        if !number.is_null()
+            && CFNumberGetValue(
+                number,
+                kCFNumberSInt32Type,
+                &mut value as *mut i32 as *mut libc::c_void,
+            )
+        {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// The frontmost normal window. The window list is ordered front to
+    /// back, and normal application windows sit on layer 0.
+    unsafe fn frontmost_window() -> Option<CGWindowID> {
+        let windows = CGWindowListCopyWindowInfo(
+            kCGWindowListOptionOnScreenOnly | kCGWindowListExcludeDesktopElements,
+            kCGNullWindowID,
+        );
+        if windows.is_null() {
+            return None;
+        }
+        let mut found = None;
+        for i in 0..CFArrayGetCount(windows) {
+            let info = CFArrayGetValueAtIndex(windows, i);
+            if window_info_i32(info, kCGWindowLayer) == Some(0) {
+                found = window_info_i32(info, kCGWindowNumber).map(|id| id as CGWindowID);
+                break;
+            }
+        }
+        CFRelease(windows);
+        found
+    }
+
+    /// Get a screenshot of the frontmost application window.
+    pub fn get_active_window_screenshot() -> ScreenResult {
+        unsafe {
+            let window = match frontmost_window() {
+                Some(window) => window,
+                None => return Err(CaptureError::Native("Can't determine the active window.")),
+            };
+            screenshot_from_image(CGWindowListCreateImage(
+                kCGRectNull,
+                kCGWindowListOptionIncludingWindow,
+                window,
+                kCGWindowImageBoundsIgnoreFraming,
+            ))
+        }
+    }
+
+    /// Copy a `CFString` into a `String`.
+    unsafe fn string_from_cf(string: CFStringRef) -> Option<String> {
+        let size =
+            CFStringGetMaximumSizeForEncoding(CFStringGetLength(string), kCFStringEncodingUTF8) + 1;
+        let mut buf = vec![0u8; size as usize];
+        if !CFStringGetCString(
+            string,
+            buf.as_mut_ptr() as *mut libc::c_char,
+            size,
+            kCFStringEncodingUTF8,
+        ) {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        buf.truncate(len);
+        String::from_utf8(buf).ok()
+    }
+
+    /// The monitor's product name from IOKit. `CGDisplayIOServicePort` has
+    /// no replacement in CoreGraphics and returns 0 on Apple silicon, where
+    /// this gives `None`.
+    unsafe fn display_name(display: CGDirectDisplayID) -> Option<String> {
+        let port = CGDisplayIOServicePort(display);
+        if port == 0 {
+            return None;
+        }
+        let info = IODisplayCreateInfoDictionary(port, kIODisplayOnlyPreferredName);
+        if info.is_null() {
+            return None;
+        }
+        let key = CFStringCreateWithCString(
+            0 as *const libc::c_void,
+            b"DisplayProductName\0".as_ptr() as *const libc::c_char,
+            kCFStringEncodingUTF8,
+        );
+        // Maps locale names to product names; with
+        // `kIODisplayOnlyPreferredName` it holds just the preferred one.
+        let names = CFDictionaryGetValue(info, key) as CFDictionaryRef;
+        let mut name = None;
+        if !names.is_null() && CFDictionaryGetCount(names) > 0 {
+            let count = CFDictionaryGetCount(names) as usize;
+            let mut keys = vec![0 as *const libc::c_void; count];
+            let mut values = vec![0 as *const libc::c_void; count];
+            CFDictionaryGetKeysAndValues(names, keys.as_mut_ptr(), values.as_mut_ptr());
+            name = string_from_cf(values[0]);
+        }
+        CFRelease(key);
+        CFRelease(info);
+        name
+    }
+
+    /// Composite every on-screen window within the rectangle, which
+    /// CoreGraphics gives in global display coordinates.
+    pub fn get_screenshot_global_rect(x: i32, y: i32, width: usize, height: usize) -> ScreenResult {
+        let bounds = CGRect {
+            origin: CGPoint {
+                x: x as CGFloat,
+                y: y as CGFloat,
+            },
+            size: CGSize {
+                width: width as CGFloat,
+                height: height as CGFloat,
+            },
+        };
+        unsafe {
+            screenshot_from_image(CGWindowListCreateImage(
+                bounds,
+                kCGWindowListOptionOnScreenOnly,
+                kCGNullWindowID,
+                kCGWindowImageDefault,
+            ))
+        }
+    }
+
+    /// The current mode's refresh rate. Built-in panels report 0, which
+    /// gives `None`.
+    unsafe fn refresh_rate(display: CGDirectDisplayID) -> Option<f64> {
+        let mode = CGDisplayCopyDisplayMode(display);
+        if mode.is_null() {
+            return None;
+        }
+        let rate = CGDisplayModeGetRefreshRate(mode);
+        CGDisplayModeRelease(mode);
+        if rate > 0.0 {
+            Some(rate)
+        } else {
+            None
+        }
+    }
+
+    /// List active displays. Coordinates are in points, which differ from
+    /// pixels on Retina displays.
+    pub fn list_displays() -> Result<Vec<DisplayInfo>, CaptureError> {
+        unsafe {
+            let ids = active_displays()?;
+            let displays = ids
+                .iter()
+                .enumerate()
+                .map(|(index, &id)| {
+                    let bounds = CGDisplayBounds(id);
+                    // kCGNullDirectDisplay (0) when it isn't mirroring.
+                    let mirrored = CGDisplayMirrorsDisplay(id);
+                    DisplayInfo {
+                        index,
+                        x: bounds.origin.x as i32,
+                        y: bounds.origin.y as i32,
+                        width: bounds.size.width as usize,
+                        height: bounds.size.height as usize,
+                        name: display_name(id),
+                        refresh_rate_hz: refresh_rate(id),
+                        physical_size_mm: {
+                            let size = CGDisplayScreenSize(id);
+                            ::physical_size_mm(size.width as f64, size.height as f64)
+                        },
+                        mirror_of: ids
+                            .iter()
+                            .position(|&other| mirrored != 0 && other == mirrored),
+                        rotation: Rotation::from_quarter_turns(
+                            (CGDisplayRotation(id) / 90.0).round() as i64,
+                        ),
+                    }
+                })
+                .collect();
+            Ok(displays)
+        }
+    }
+
+    pub struct DisplayWatch {
+        callback: *mut WatchCallback,
+    }
+
+    impl Drop for DisplayWatch {
+        fn drop(&mut self) {
+            unsafe {
+                CGDisplayRemoveReconfigurationCallback(
+                    reconfiguration_callback,
+                    self.callback as *mut libc::c_void,
+                );
+                drop(Box::from_raw(self.callback));
+            }
+        }
+    }
+
+    extern "C" fn reconfiguration_callback(
+        _display: CGDirectDisplayID,
+        flags: CGDisplayChangeSummaryFlags,
+        user_info: *mut libc::c_void,
+    ) {
+        invalidate_display_cache();
+        // Every change is announced once before and once after it happens.
+        if flags & kCGDisplayBeginConfigurationFlag != 0 {
+            return;
+        }
+        let callback = unsafe { &mut *(user_info as *mut WatchCallback) };
+        if let Ok(displays) = list_displays() {
+            callback(displays);
+        }
+    }
+
+    pub fn watch_displays(callback: WatchCallback) -> Result<DisplayWatch, CaptureError> {
+        let callback = Box::into_raw(Box::new(callback));
+        unsafe {
+            let err = CGDisplayRegisterReconfigurationCallback(
+                reconfiguration_callback,
+                callback as *mut libc::c_void,
+            );
+            if err != CGDisplayNoErr {
+                drop(Box::from_raw(callback));
+                return Err(CaptureError::Native(
+                    "Error registering display reconfiguration callback.",
+                ));
+            }
+        }
+        Ok(DisplayWatch { callback })
+    }
+
+    /// Put the screenshot on the general pasteboard as PNG data.
+    #[cfg(feature = "clipboard")]
+    pub fn copy_to_clipboard(screenshot: &Screenshot) -> Result<(), CaptureError> {
+        let png = screenshot.to_png_bytes()?;
+        unsafe {
+            let send = objc_msgSend as *const libc::c_void;
+            let send_id: extern "C" fn(Id, Sel) -> Id = mem::transmute(send);
+            let send_void: extern "C" fn(Id, Sel) = mem::transmute(send);
+            let init_data: extern "C" fn(Id, Sel, *const libc::c_void, libc::c_ulong) -> Id =
+                mem::transmute(send);
+            let set_data: extern "C" fn(Id, Sel, Id, Id) -> libc::c_schar = mem::transmute(send);
+
+            let pasteboard = send_id(
+                objc_getClass(b"NSPasteboard\0".as_ptr() as *const libc::c_char),
+                selector(b"generalPasteboard\0"),
+            );
+            if pasteboard.is_null() {
+                return Err(CaptureError::Native("Can't get the general pasteboard."));
+            }
+            let data = init_data(
+                send_id(
+                    objc_getClass(b"NSData\0".as_ptr() as *const libc::c_char),
+                    selector(b"alloc\0"),
+                ),
+                selector(b"initWithBytes:length:\0"),
+                png.as_ptr() as *const libc::c_void,
+                png.len() as libc::c_ulong,
+            );
+            if data.is_null() {
+                return Err(CaptureError::Native("Can't allocate pasteboard data."));
+            }
+            // `clearContents` returns the change count, which isn't needed.
+            send_id(pasteboard, selector(b"clearContents\0"));
+            let ok = set_data(
+                pasteboard,
+                selector(b"setData:forType:\0"),
+                data,
+                NSPasteboardTypePNG,
+            );
+            send_void(data, selector(b"release\0"));
+            if ok != 0 {
+                Ok(())
+            } else {
+                Err(CaptureError::Native("Can't write to the pasteboard."))
+            }
+        }
+    }
+
+    #[cfg(feature = "clipboard")]
+    unsafe fn selector(name: &[u8]) -> Sel {
+        sel_registerName(name.as_ptr() as *const libc::c_char)
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod ffi {
+    #![allow(non_snake_case, dead_code)]
+
+    use std::io;
+    use std::mem::{self, size_of};
+    #[cfg(feature = "clipboard")]
+    use std::ptr;
+    use std::ptr::null_mut;
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+    use std::time::{Instant, SystemTime};
+
+    use winapi::ctypes::c_int;
+    use winapi::shared::minwindef;
+    use winapi::shared::ntdef;
+    use winapi::shared::windef;
+    use winapi::um::libloaderapi;
+    #[cfg(feature = "clipboard")]
+    use winapi::um::winbase;
+    use winapi::um::wingdi;
+    use winapi::um::winuser;
+
+    use ScreenResult;
+    use Screenshot;
+    use {
+        AlphaMode, CaptureError, CaptureOptions, CaptureStats, DisplayId, DisplayInfo, PixelFormat,
+        Region, Rotation, WatchCallback,
+    };
+
+    /// The raster operation for `BitBlt`. `CAPTUREBLT` pulls in layered
+    /// windows, at a large cost in speed.
+    const DEFAULT_ROP: minwindef::DWORD = wingdi::SRCCOPY | wingdi::CAPTUREBLT;
+
+    /// Missing from winapi 0.3; needs Windows 10 version 2004.
+    const WDA_EXCLUDEFROMCAPTURE: minwindef::DWORD = 0x11;
+
+    /// Windows hidden from captures, with the display affinities to give
+    /// them back when this is dropped.
+    struct ExcludedWindows(Vec<(windef::HWND, minwindef::DWORD)>);
+
+    impl ExcludedWindows {
+        unsafe fn new(h_wnds: &[usize]) -> Result<ExcludedWindows, CaptureError> {
+            let mut excluded = ExcludedWindows(Vec::with_capacity(h_wnds.len()));
+            for &h_wnd in h_wnds {
+                let h_wnd = h_wnd as windef::HWND;
+                let mut affinity = 0;
+                if winuser::GetWindowDisplayAffinity(h_wnd, &mut affinity) == 0 {
+                    warn!(
+                        "GetWindowDisplayAffinity failed: {}",
+                        io::Error::last_os_error()
+                    );
+                    return Err(CaptureError::Native("Can't get window display affinity."));
+                }
+                if winuser::SetWindowDisplayAffinity(h_wnd, WDA_EXCLUDEFROMCAPTURE) == 0 {
+                    warn!(
+                        "SetWindowDisplayAffinity failed: {}",
+                        io::Error::last_os_error()
+                    );
+                    // Dropping `excluded` restores the windows done so far.
+                    return Err(CaptureError::Native("Can't exclude window from capture."));
+                }
+                excluded.0.push((h_wnd, affinity));
+            }
+            Ok(excluded)
+        }
+    }
+
+    impl Drop for ExcludedWindows {
+        fn drop(&mut self) {
+            for &(h_wnd, affinity) in &self.0 {
+                unsafe {
+                    winuser::SetWindowDisplayAffinity(h_wnd, affinity);
+                }
+            }
+        }
+    }
+
+    /// TODO Support multiple screens
+    /// This may never happen, given the horrific quality of Win32 APIs
+    pub fn get_screenshot(screen: usize) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        capture_into(screen, &mut frame)?;
+        Ok(frame)
+    }
+
+    pub fn capture_into(screen: usize, frame: &mut Screenshot) -> Result<(), CaptureError> {
+        capture_into_timed(screen, frame, &mut CaptureStats::default())
+    }
+
+    pub fn capture_into_timed(
+        _screen: usize,
+        frame: &mut Screenshot,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        capture_virtual_screen(frame, DEFAULT_ROP, stats)
+    }
+
+    fn capture_virtual_screen(
+        frame: &mut Screenshot,
+        rop: minwindef::DWORD,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        //        use std::ptr::null;
+        unsafe {
+            // Enumerate monitors, getting a handle and DC for requested monitor.
+            // loljk, because doing that on Windows is worse than death
+            let x = winuser::GetSystemMetrics(winuser::SM_XVIRTUALSCREEN);
+            let y = winuser::GetSystemMetrics(winuser::SM_YVIRTUALSCREEN);
+            let width = winuser::GetSystemMetrics(winuser::SM_CXVIRTUALSCREEN);
+            let height = winuser::GetSystemMetrics(winuser::SM_CYVIRTUALSCREEN);
+            debug!(
+                "capturing the {}x{} virtual screen at ({}, {}) with rop {:#x}",
+                width, height, x, y, rop
+            );
+            capture_rect_timed(x, y, width, height, rop, frame, stats)
+        }
+    }
+
+    pub fn get_screenshot_with_options(_screen: usize, options: &CaptureOptions) -> ScreenResult {
+        let rop = if options.include_layered_windows {
+            DEFAULT_ROP
+        } else {
+            wingdi::SRCCOPY
+        };
+        let mut frame = Screenshot::default();
+        {
+            let _excluded = unsafe { ExcludedWindows::new(&options.exclude_hwnds)? };
+            capture_virtual_screen(&mut frame, rop, &mut CaptureStats::default())?;
+        }
+        // GDI writes straight into the frame, so there's no copy to reorder
+        // during.
+        frame.reorder_in_place(options.output_format);
+        Ok(frame)
+    }
+
+    /// `region` is relative to the virtual screen, which is what `capture_into`
+    /// grabs.
+    pub fn capture_region_into(
+        _screen: usize,
+        region: Region,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        let (x, y, width, height) = region;
+        unsafe {
+            let left = winuser::GetSystemMetrics(winuser::SM_XVIRTUALSCREEN);
+            let top = winuser::GetSystemMetrics(winuser::SM_YVIRTUALSCREEN);
+            let virtual_width = winuser::GetSystemMetrics(winuser::SM_CXVIRTUALSCREEN);
+            let virtual_height = winuser::GetSystemMetrics(winuser::SM_CYVIRTUALSCREEN);
+            if !::region_fits(
+                Some(region),
+                virtual_width as usize,
+                virtual_height as usize,
+            ) {
+                return Err(CaptureError::InvalidArgument(
+                    "Region extends past the display.",
+                ));
+            }
+            capture_rect_into(
+                left + x as c_int,
+                top + y as c_int,
+                width as c_int,
+                height as c_int,
+                DEFAULT_ROP,
+                frame,
+            )
+        }
+    }
+
+    /// What a `Capturer` keeps between frames: its Desktop Duplication
+    /// session, if it uses one.
+    pub struct CaptureCache {
+        /// Try Desktop Duplication before GDI.
+        duplicate: bool,
+        duplication: Option<duplication::Duplication>,
+        /// Set when Desktop Duplication can't work in this session, such as
+        /// over Remote Desktop, so it isn't retried every frame.
+        unavailable: bool,
+        dirty_rects: Option<Vec<Region>>,
+    }
+
+    impl CaptureCache {
+        pub fn new() -> CaptureCache {
+            CaptureCache {
+                duplicate: false,
+                duplication: None,
+                unavailable: false,
+                dirty_rects: None,
+            }
+        }
+
+        pub fn set_duplication(&mut self, enabled: bool) {
+            self.duplicate = enabled;
+            self.reconnect();
+        }
+
+        pub fn dirty_rects(&self) -> Option<&[Region]> {
+            self.dirty_rects.as_ref().map(|rects| &rects[..])
+        }
+
+        /// GDI keeps no handles, so only a duplication session can go stale.
+        pub fn is_valid(&self, screen: usize) -> bool {
+            match self.duplication {
+                Some(ref duplication) => duplication.is_live(),
+                None => ::display_info(screen).is_ok(),
+            }
+        }
+
+        pub fn reconnect(&mut self) {
+            self.duplication = None;
+            self.unavailable = false;
+            self.dirty_rects = None;
+        }
+    }
+
+    /// With Desktop Duplication on, a `Capturer` grabs monitor `screen`
+    /// alone, falling back to GDI for that monitor's area when duplication
+    /// fails. Otherwise it captures the virtual screen like `capture_into`.
+    pub fn capture_cached(
+        cache: &mut CaptureCache,
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        cache.dirty_rects = None;
+        if !cache.duplicate {
+            return match region {
+                Some(region) => capture_region_into(screen, region, frame),
+                None => capture_into(screen, frame),
+            };
+        }
+        let info = list_displays()?
+            .into_iter()
+            .nth(screen)
+            .ok_or(CaptureError::DisplayNotFound(DisplayId::Index(screen)))?;
+        if !::region_fits(region, info.width, info.height) {
+            return Err(CaptureError::InvalidArgument(
+                "Region extends past the display.",
+            ));
+        }
+        let region = region.unwrap_or((0, 0, info.width, info.height));
+        if !cache.unavailable {
+            match duplication::capture(cache, screen, region, frame) {
+                Ok(()) => return Ok(()),
+                Err(err) => warn!("Desktop Duplication failed, using GDI: {}", err),
+            }
+        }
+        let (x, y, width, height) = region;
+        unsafe {
+            capture_rect_into(
+                info.x + x as c_int,
+                info.y + y as c_int,
+                width as c_int,
+                height as c_int,
+                DEFAULT_ROP,
+                frame,
+            )
+        }
+    }
+
+    /// Capturing through DXGI Desktop Duplication (Windows 8 and later),
+    /// which hands over the compositor's copy of a monitor's image instead
+    /// of reading it back through GDI, and says which parts changed.
+    mod duplication {
+        use std::mem::{self, size_of};
+        use std::ptr::{self, null_mut};
+        use std::time::SystemTime;
+
+        use winapi::ctypes::c_void;
+        use winapi::shared::dxgi::{
+            CreateDXGIFactory1, IDXGIAdapter, IDXGIAdapter1, IDXGIFactory1, IDXGIOutput,
+            IDXGIResource, DXGI_OUTPUT_DESC,
+        };
+        use winapi::shared::dxgi1_2::{
+            IDXGIOutput1, IDXGIOutputDuplication, DXGI_OUTDUPL_FRAME_INFO, DXGI_OUTDUPL_MOVE_RECT,
+        };
+        use winapi::shared::dxgiformat::DXGI_FORMAT_B8G8R8A8_UNORM;
+        use winapi::shared::dxgitype::{
+            DXGI_MODE_ROTATION_IDENTITY, DXGI_MODE_ROTATION_UNSPECIFIED,
+        };
+        use winapi::shared::minwindef::{self, TRUE};
+        use winapi::shared::windef::{HDC, HMONITOR, LPRECT, RECT};
+        use winapi::shared::winerror::{
+            DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_WAIT_TIMEOUT, SUCCEEDED,
+        };
+        use winapi::um::d3d11::{
+            D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, ID3D11Resource, ID3D11Texture2D,
+            D3D11_CPU_ACCESS_READ, D3D11_MAPPED_SUBRESOURCE, D3D11_MAP_READ, D3D11_SDK_VERSION,
+            D3D11_TEXTURE2D_DESC, D3D11_USAGE_STAGING,
+        };
+        use winapi::um::d3dcommon::D3D_DRIVER_TYPE_UNKNOWN;
+        use winapi::um::unknwnbase::IUnknown;
+        use winapi::um::winuser;
+        use winapi::Interface;
+
+        use super::CaptureCache;
+        use {AlphaMode, CaptureError, PixelFormat, Region, Screenshot};
+
+        /// How long to wait for the compositor to present a new frame
+        /// before reusing the last one.
+        const FRAME_TIMEOUT_MS: u32 = 50;
+
+        /// A duplication session for one monitor.
+        pub struct Duplication {
+            screen: usize,
+            monitor: HMONITOR,
+            device: *mut ID3D11Device,
+            context: *mut ID3D11DeviceContext,
+            duplication: *mut IDXGIOutputDuplication,
+            /// CPU-readable copy of the last frame, remade when the
+            /// monitor's size changes.
+            staging: *mut ID3D11Texture2D,
+            staging_size: (u32, u32),
+        }
+
+        // The COM objects are only used by the thread that owns the
+        // `Capturer`, one call at a time.
+        unsafe impl Send for Duplication {}
+
+        impl Drop for Duplication {
+            fn drop(&mut self) {
+                unsafe {
+                    release(self.staging);
+                    release(self.duplication);
+                    release(self.context);
+                    release(self.device);
+                }
+            }
+        }
+
+        /// Release a COM object, if there is one.
+        unsafe fn release<T>(object: *mut T) {
+            if !object.is_null() {
+                (*(object as *mut IUnknown)).Release();
+            }
+        }
+
+        unsafe extern "system" fn push_handle(
+            h_monitor: HMONITOR,
+            _h_dc: HDC,
+            _rect: LPRECT,
+            handles: minwindef::LPARAM,
+        ) -> minwindef::BOOL {
+            (*(handles as *mut Vec<HMONITOR>)).push(h_monitor);
+            TRUE
+        }
+
+        /// The monitor `list_displays` numbers `screen`.
+        unsafe fn monitor_handle(screen: usize) -> Option<HMONITOR> {
+            let mut handles: Vec<HMONITOR> = Vec::new();
+            winuser::EnumDisplayMonitors(
+                null_mut(),
+                null_mut(),
+                Some(push_handle),
+                &mut handles as *mut Vec<HMONITOR> as minwindef::LPARAM,
+            );
+            handles.get(screen).cloned()
+        }
+
+        /// The adapter and output that drive `h_monitor`, each with a
+        /// reference the caller must release.
+        unsafe fn find_output(
+            h_monitor: HMONITOR,
+        ) -> Result<(*mut IDXGIAdapter1, *mut IDXGIOutput), CaptureError> {
+            let mut factory: *mut IDXGIFactory1 = null_mut();
+            let hr = CreateDXGIFactory1(
+                &IDXGIFactory1::uuidof(),
+                &mut factory as *mut *mut IDXGIFactory1 as *mut *mut c_void,
+            );
+            if !SUCCEEDED(hr) {
+                warn!("CreateDXGIFactory1 failed: {:#x}", hr);
+                return Err(CaptureError::Native("Can't create a DXGI factory."));
+            }
+            let mut found = None;
+            let mut adapter: *mut IDXGIAdapter1 = null_mut();
+            let mut a = 0;
+            while found.is_none() && SUCCEEDED((*factory).EnumAdapters1(a, &mut adapter)) {
+                let mut output: *mut IDXGIOutput = null_mut();
+                let mut o = 0;
+                while SUCCEEDED((*adapter).EnumOutputs(o, &mut output)) {
+                    let mut desc: DXGI_OUTPUT_DESC = mem::zeroed();
+                    if SUCCEEDED((*output).GetDesc(&mut desc)) && desc.Monitor == h_monitor {
+                        found = Some((adapter, output));
+                        break;
+                    }
+                    release(output);
+                    o += 1;
+                }
+                if found.is_none() {
+                    release(adapter);
+                }
+                a += 1;
+            }
+            release(factory);
+            found.ok_or(CaptureError::Native("No DXGI output drives the display."))
+        }
+
+        impl Duplication {
+            /// Whether the monitor is still connected and still the one
+            /// `list_displays` numbers `screen`.
+            pub fn is_live(&self) -> bool {
+                unsafe { monitor_handle(self.screen) == Some(self.monitor) }
+            }
+
+            unsafe fn new(screen: usize) -> Result<Duplication, CaptureError> {
+                let h_monitor =
+                    monitor_handle(screen).ok_or(CaptureError::Native("The display went away."))?;
+                let (adapter, output) = find_output(h_monitor)?;
+                let mut dup = Duplication {
+                    screen,
+                    monitor: h_monitor,
+                    device: null_mut(),
+                    context: null_mut(),
+                    duplication: null_mut(),
+                    staging: null_mut(),
+                    staging_size: (0, 0),
+                };
+                let res = dup.start(adapter, output);
+                release(output);
+                release(adapter);
+                res.map(|()| dup)
+            }
+
+            /// Make a D3D11 device on `adapter` and duplicate `output` with it.
+            unsafe fn start(
+                &mut self,
+                adapter: *mut IDXGIAdapter1,
+                output: *mut IDXGIOutput,
+            ) -> Result<(), CaptureError> {
+                let mut desc: DXGI_OUTPUT_DESC = mem::zeroed();
+                (*output).GetDesc(&mut desc);
+                // The desktop texture isn't rotated with the display.
+                if desc.Rotation != DXGI_MODE_ROTATION_IDENTITY
+                    && desc.Rotation != DXGI_MODE_ROTATION_UNSPECIFIED
+                {
+                    return Err(CaptureError::Native("The display is rotated."));
+                }
+                let hr = D3D11CreateDevice(
+                    adapter as *mut IDXGIAdapter,
+                    D3D_DRIVER_TYPE_UNKNOWN,
+                    null_mut(),
+                    0,
+                    ptr::null(),
+                    0,
+                    D3D11_SDK_VERSION,
+                    &mut self.device,
+                    null_mut(),
+                    &mut self.context,
+                );
+                if !SUCCEEDED(hr) {
+                    warn!("D3D11CreateDevice failed: {:#x}", hr);
+                    return Err(CaptureError::Native("Can't create a Direct3D device."));
+                }
+                let mut output1: *mut IDXGIOutput1 = null_mut();
+                let hr = (*output).QueryInterface(
+                    &IDXGIOutput1::uuidof(),
+                    &mut output1 as *mut *mut IDXGIOutput1 as *mut *mut c_void,
+                );
+                if !SUCCEEDED(hr) {
+                    return Err(CaptureError::Native(
+                        "Desktop Duplication needs Windows 8 or later.",
+                    ));
+                }
+                let hr =
+                    (*output1).DuplicateOutput(self.device as *mut IUnknown, &mut self.duplication);
+                release(output1);
+                if !SUCCEEDED(hr) {
+                    // E_ACCESSDENIED on the secure desktop and in some
+                    // Remote Desktop sessions, DXGI_ERROR_UNSUPPORTED on
+                    // some drivers.
+                    warn!("DuplicateOutput failed: {:#x}", hr);
+                    return Err(CaptureError::Native("Can't duplicate the display."));
+                }
+                Ok(())
+            }
+
+            /// Copy the next frame into the staging texture, returning the
+            /// rectangles that changed, or an empty list if the compositor
+            /// had nothing new within `FRAME_TIMEOUT_MS`.
+            unsafe fn acquire(&mut self) -> Result<Vec<RECT>, AcquireError> {
+                let mut info: DXGI_OUTDUPL_FRAME_INFO = mem::zeroed();
+                let mut resource: *mut IDXGIResource = null_mut();
+                let hr = (*self.duplication).AcquireNextFrame(
+                    FRAME_TIMEOUT_MS,
+                    &mut info,
+                    &mut resource,
+                );
+                if hr == DXGI_ERROR_WAIT_TIMEOUT {
+                    return if self.staging.is_null() {
+                        Err(AcquireError::Failed("No frame has been presented yet."))
+                    } else {
+                        Ok(Vec::new())
+                    };
+                }
+                if hr == DXGI_ERROR_ACCESS_LOST {
+                    // A mode change, desktop switch or UAC prompt; a new
+                    // session is needed.
+                    return Err(AcquireError::Lost);
+                }
+                if !SUCCEEDED(hr) {
+                    warn!("AcquireNextFrame failed: {:#x}", hr);
+                    return Err(AcquireError::Lost);
+                }
+                let res = self.copy_frame(resource, &info);
+                release(resource);
+                (*self.duplication).ReleaseFrame();
+                res
+            }
+
+            unsafe fn copy_frame(
+                &mut self,
+                resource: *mut IDXGIResource,
+                info: &DXGI_OUTDUPL_FRAME_INFO,
+            ) -> Result<Vec<RECT>, AcquireError> {
+                let mut texture: *mut ID3D11Texture2D = null_mut();
+                let hr = (*resource).QueryInterface(
+                    &ID3D11Texture2D::uuidof(),
+                    &mut texture as *mut *mut ID3D11Texture2D as *mut *mut c_void,
+                );
+                if !SUCCEEDED(hr) {
+                    return Err(AcquireError::Failed("Frame isn't a Direct3D texture."));
+                }
+                let mut desc: D3D11_TEXTURE2D_DESC = mem::zeroed();
+                (*texture).GetDesc(&mut desc);
+                // HDR and wide-color desktops come as float or 10-bit frames,
+                // which `read` can't copy as BGRA.
+                if desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM {
+                    release(texture);
+                    return Err(AcquireError::Failed("Frame isn't 8-bit BGRA."));
+                }
+                if (desc.Width, desc.Height) != self.staging_size {
+                    release(self.staging);
+                    self.staging = null_mut();
+                    self.staging_size = (0, 0);
+                    let staging_desc = D3D11_TEXTURE2D_DESC {
+                        MipLevels: 1,
+                        ArraySize: 1,
+                        Usage: D3D11_USAGE_STAGING,
+                        BindFlags: 0,
+                        CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                        MiscFlags: 0,
+                        ..desc
+                    };
+                    let hr = (*self.device).CreateTexture2D(
+                        &staging_desc,
+                        ptr::null(),
+                        &mut self.staging,
+                    );
+                    if !SUCCEEDED(hr) {
+                        release(texture);
+                        warn!("CreateTexture2D failed: {:#x}", hr);
+                        return Err(AcquireError::Failed("Can't create a staging texture."));
+                    }
+                    self.staging_size = (desc.Width, desc.Height);
+                }
+                (*self.context).CopyResource(
+                    self.staging as *mut ID3D11Resource,
+                    texture as *mut ID3D11Resource,
+                );
+                release(texture);
+                Ok(self.changed_rects(info))
+            }
+
+            /// The dirty rectangles of the acquired frame, plus the
+            /// destinations of its moved rectangles.
+            unsafe fn changed_rects(&self, info: &DXGI_OUTDUPL_FRAME_INFO) -> Vec<RECT> {
+                let size = info.TotalMetadataBufferSize;
+                if size == 0 {
+                    return Vec::new();
+                }
+                let mut rects: Vec<RECT> = Vec::with_capacity(size as usize / size_of::<RECT>());
+                let mut used = 0;
+                if SUCCEEDED((*self.duplication).GetFrameDirtyRects(
+                    size,
+                    rects.as_mut_ptr(),
+                    &mut used,
+                )) {
+                    rects.set_len(used as usize / size_of::<RECT>());
+                }
+                let mut moves: Vec<DXGI_OUTDUPL_MOVE_RECT> =
+                    Vec::with_capacity(size as usize / size_of::<DXGI_OUTDUPL_MOVE_RECT>());
+                if SUCCEEDED((*self.duplication).GetFrameMoveRects(
+                    size,
+                    moves.as_mut_ptr(),
+                    &mut used,
+                )) {
+                    moves.set_len(used as usize / size_of::<DXGI_OUTDUPL_MOVE_RECT>());
+                }
+                rects.extend(moves.iter().map(|m| m.DestinationRect));
+                rects
+            }
+
+            /// Copy `region` of the staging texture into `frame`.
+            unsafe fn read(
+                &self,
+                region: Region,
+                frame: &mut Screenshot,
+            ) -> Result<(), CaptureError> {
+                let (x, y, width, height) = region;
+                if x + width > self.staging_size.0 as usize
+                    || y + height > self.staging_size.1 as usize
+                {
+                    return Err(CaptureError::Native("The display changed size."));
+                }
+                let mut mapped: D3D11_MAPPED_SUBRESOURCE = mem::zeroed();
+                let staging = self.staging as *mut ID3D11Resource;
+                let hr = (*self.context).Map(staging, 0, D3D11_MAP_READ, 0, &mut mapped);
+                if !SUCCEEDED(hr) {
+                    warn!("Map failed: {:#x}", hr);
+                    return Err(CaptureError::Native("Can't read the staging texture."));
+                }
+                let row_len = width * 4;
+                frame.data.clear();
+                frame.data.reserve(row_len * height);
+                for row in y..y + height {
+                    let src =
+                        (mapped.pData as *const u8).add(row * mapped.RowPitch as usize + x * 4);
+                    frame
+                        .data
+                        .extend_from_slice(::std::slice::from_raw_parts(src, row_len));
+                }
+                (*self.context).Unmap(staging, 0);
+
+                frame.height = height;
+                frame.width = width;
+                frame.row_len = row_len;
+                frame.pixel_width = 4;
+                frame.color_space = None;
+                frame.format = PixelFormat::Bgra;
+                frame.bits_per_component = 8;
+                frame.alpha_mode = AlphaMode::Opaque;
+                Ok(())
+            }
+        }
+
+        enum AcquireError {
+            /// The session is dead and must be recreated.
+            Lost,
+            Failed(&'static str),
+        }
+
+        /// Capture `region` of monitor `screen` into `frame` and record what
+        /// changed in `cache.dirty_rects`. Errors mean the caller should
+        /// fall back to GDI for this frame.
+        pub fn capture(
+            cache: &mut CaptureCache,
+            screen: usize,
+            region: Region,
+            frame: &mut Screenshot,
+        ) -> Result<(), CaptureError> {
+            unsafe {
+                if cache.duplication.as_ref().map(|d| d.screen) != Some(screen) {
+                    cache.duplication = None;
+                    match Duplication::new(screen) {
+                        Ok(dup) => cache.duplication = Some(dup),
+                        Err(err) => {
+                            cache.unavailable = true;
+                            return Err(err);
+                        }
+                    }
+                }
+                let rects = match cache.duplication.as_mut().unwrap().acquire() {
+                    Ok(rects) => rects,
+                    Err(AcquireError::Lost) => {
+                        cache.duplication = None;
+                        return Err(CaptureError::Native("Desktop Duplication access lost."));
+                    }
+                    Err(AcquireError::Failed(msg)) => return Err(CaptureError::Native(msg)),
+                };
+                frame.captured_at = SystemTime::now();
+                cache.duplication.as_ref().unwrap().read(region, frame)?;
+                cache.dirty_rects = Some(
+                    rects
+                        .iter()
+                        .filter_map(|rect| clip_to_region(rect, region))
+                        .collect(),
+                );
+                Ok(())
+            }
+        }
+
+        /// `rect`'s overlap with `region`, relative to `region`, if any.
+        fn clip_to_region(rect: &RECT, region: Region) -> Option<Region> {
+            let (x, y, width, height) = region;
+            let left = (rect.left.max(0) as usize).max(x);
+            let top = (rect.top.max(0) as usize).max(y);
+            let right = (rect.right.max(0) as usize).min(x + width);
+            let bottom = (rect.bottom.max(0) as usize).min(y + height);
+            if left < right && top < bottom {
+                Some((left - x, top - y, right - left, bottom - top))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// GDI can't wait for vblank, so capture now.
+    pub fn capture_into_vsync(
+        screen: usize,
+        region: Option<Region>,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        match region {
+            Some(region) => capture_region_into(screen, region, frame),
+            None => capture_into(screen, frame),
+        }
+    }
+
+    pub fn get_screenshot_for(id: DisplayId) -> ScreenResult {
+        unsafe {
+            let h_monitor = match id {
+                DisplayId::Index(index) => {
+                    return match list_displays()?.get(index) {
+                        Some(info) => {
+                            capture_rect(info.x, info.y, info.width as c_int, info.height as c_int)
+                        }
+                        None => Err(CaptureError::DisplayNotFound(id)),
+                    };
+                }
+                DisplayId::Primary => winuser::MonitorFromPoint(
+                    windef::POINT { x: 0, y: 0 },
+                    winuser::MONITOR_DEFAULTTOPRIMARY,
+                ),
+                DisplayId::Native(native) => native as usize as windef::HMONITOR,
+                DisplayId::Name(ref name) => return ::get_screenshot_by_name(name),
+                DisplayId::Virtual => return get_screenshot(0),
+            };
+
+            let mut info: winuser::MONITORINFO = mem::zeroed();
+            info.cbSize = size_of::<winuser::MONITORINFO>() as minwindef::DWORD;
+            if winuser::GetMonitorInfoW(h_monitor, &mut info) == 0 {
+                return Err(CaptureError::DisplayNotFound(id));
+            }
+            let rect = info.rcMonitor;
+            capture_rect(
+                rect.left,
+                rect.top,
+                rect.right - rect.left,
+                rect.bottom - rect.top,
+            )
+        }
+    }
+
+    /// The screen DC covers the whole virtual desktop, and areas outside
+    /// every monitor read as black.
+    pub fn get_screenshot_global_rect(x: i32, y: i32, width: usize, height: usize) -> ScreenResult {
+        unsafe { capture_rect(x, y, width as c_int, height as c_int) }
+    }
+
+    /// Get a screenshot of the foreground window's bounds.
+    pub fn get_active_window_screenshot() -> ScreenResult {
+        unsafe {
+            let h_wnd = winuser::GetForegroundWindow();
+            if h_wnd.is_null() {
+                return Err(CaptureError::Native("There is no foreground window."));
+            }
+            let mut rect: windef::RECT = mem::zeroed();
+            if winuser::GetWindowRect(h_wnd, &mut rect) == 0 {
+                return Err(CaptureError::Native(
+                    "Can't get the foreground window's bounds.",
+                ));
+            }
+            capture_rect(
+                rect.left,
+                rect.top,
+                rect.right - rect.left,
+                rect.bottom - rect.top,
+            )
+        }
+    }
+
+    /// Copy a rectangle of the virtual screen, given in screen coordinates.
+    unsafe fn capture_rect(
+        screen_x: c_int,
+        screen_y: c_int,
+        screen_width: c_int,
+        screen_height: c_int,
+    ) -> ScreenResult {
+        let mut frame = Screenshot::default();
+        capture_rect_into(
+            screen_x,
+            screen_y,
+            screen_width,
+            screen_height,
+            DEFAULT_ROP,
+            &mut frame,
+        )?;
+        Ok(frame)
+    }
+
+    /// Like `capture_rect`, but copies into `frame`, reusing its buffer, with
+    /// the raster operation `rop`.
+    unsafe fn capture_rect_into(
+        screen_x: c_int,
+        screen_y: c_int,
+        screen_width: c_int,
+        screen_height: c_int,
+        rop: minwindef::DWORD,
+        frame: &mut Screenshot,
+    ) -> Result<(), CaptureError> {
+        capture_rect_timed(
+            screen_x,
+            screen_y,
+            screen_width,
+            screen_height,
+            rop,
+            frame,
+            &mut CaptureStats::default(),
+        )
+    }
+
+    /// `capture_rect_into`, recording in `stats` how long `BitBlt` and the
+    /// copy out with `GetDIBits` took.
+    unsafe fn capture_rect_timed(
+        screen_x: c_int,
+        screen_y: c_int,
+        screen_width: c_int,
+        screen_height: c_int,
+        rop: minwindef::DWORD,
+        frame: &mut Screenshot,
+        stats: &mut CaptureStats,
+    ) -> Result<(), CaptureError> {
+        let scale = 1;
+
+        let h_wnd_screen = winuser::GetDesktopWindow();
+        let h_dc_screen = winuser::GetDC(h_wnd_screen);
+        let width = screen_width / scale;
+        let height = screen_height / scale;
+
+        // Create a Windows Bitmap, and copy the bits into it
+        let h_dc = wingdi::CreateCompatibleDC(h_dc_screen);
+        if h_dc.is_null() {
+            warn!("CreateCompatibleDC failed: {}", io::Error::last_os_error());
+            return Err(CaptureError::Native("Can't get a Windows display."));
+        }
+
+        let h_bmp = wingdi::CreateCompatibleBitmap(h_dc_screen, width, height);
+        if h_bmp.is_null() {
+            warn!(
+                "CreateCompatibleBitmap failed for {}x{}: {}",
+                width,
+                height,
+                io::Error::last_os_error()
+            );
+            return Err(CaptureError::Native("Can't create a Windows buffer"));
+        }
+
+        let res = wingdi::SelectObject(h_dc, h_bmp as windef::HGDIOBJ);
+        if res == ntdef::NULL || res == wingdi::HGDI_ERROR {
+            warn!("SelectObject failed: {}", io::Error::last_os_error());
+            return Err(CaptureError::Native("Can't select Windows buffer."));
+        }
+
+        let start = Instant::now();
+        if scale != 1 {
+            wingdi::SetStretchBltMode(h_dc, wingdi::COLORONCOLOR);
+            let res = wingdi::StretchBlt(
+                h_dc,
+                0,
+                0,
+                width,
+                height,
+                h_dc_screen,
+                screen_x,
+                screen_y,
+                width * scale,
+                height * scale,
+                rop,
+            );
+            if res == 0 {
+                warn!("StretchBlt failed: {}", io::Error::last_os_error());
+                return Err(CaptureError::Native(
+                    "Failed to copy screen to Windows buffer",
+                ));
+            }
+        } else {
+            let res = wingdi::BitBlt(
+                h_dc,
+                0,
+                0,
+                width,
+                height,
+                h_dc_screen,
+                screen_x,
+                screen_y,
+                rop,
+            );
+            if res == 0 {
+                warn!("BitBlt failed: {}", io::Error::last_os_error());
+                return Err(CaptureError::Native(
+                    "Failed to copy screen to Windows buffer",
+                ));
+            }
+        }
+        stats.native_duration = start.elapsed();
+
+        frame.captured_at = SystemTime::now();
+
+        // Get image info
+        let pixel_width: usize = 4; // FIXME
+
+        let mut bmi = wingdi::BITMAPINFO {
+            bmiHeader: wingdi::BITMAPINFOHEADER {
+                biSize: size_of::<wingdi::BITMAPINFOHEADER>() as minwindef::DWORD,
+                biWidth: width as ntdef::LONG,
+                // Negative height asks GDI for top-down rows.
+                biHeight: -height as ntdef::LONG,
+                biPlanes: 1,
+                biBitCount: 8 * pixel_width as minwindef::WORD,
+                biCompression: wingdi::BI_RGB,
+                biSizeImage: (width * height * pixel_width as minwindef::INT) as minwindef::DWORD,
+                biXPelsPerMeter: 0,
+                biYPelsPerMeter: 0,
+                biClrUsed: 0,
+                biClrImportant: 0,
+            },
+            bmiColors: [wingdi::RGBQUAD {
+                rgbBlue: 0,
+                rgbGreen: 0,
+                rgbRed: 0,
+                rgbReserved: 0,
+            }],
+        };
+
+        // Size the frame's buffer for the image
+        let size: usize = (width * height) as usize * pixel_width;
+        debug!("copying {} bytes out of the GDI bitmap", size);
+        let data = &mut frame.data;
+        data.clear();
+        data.resize(size, 0);
+
+        // copy bits into Vec
+        let start = Instant::now();
+        let lines = wingdi::GetDIBits(
+            h_dc,
+            h_bmp,
+            0,
+            height as minwindef::DWORD,
+            data.as_mut_ptr() as minwindef::LPVOID,
+            &mut bmi as wingdi::LPBITMAPINFO,
+            wingdi::DIB_RGB_COLORS,
+        );
+        stats.copy_duration = start.elapsed();
+
+        // Release native image buffers
+        winuser::ReleaseDC(h_wnd_screen, h_dc_screen); // don't need screen anymore
+        wingdi::DeleteDC(h_dc);
+        wingdi::DeleteObject(h_bmp as windef::HGDIOBJ);
+
+        if lines != height {
+            warn!("GetDIBits copied {} of {} lines", lines, height);
+            // Leave an empty frame rather than dimensions the data can't back.
+            data.clear();
+            frame.height = 0;
+            frame.width = 0;
+            frame.row_len = 0;
+            return Err(CaptureError::Native("Failed to read Windows buffer."));
+        }
+
+        ::dib_rows_top_down(data, bmi.bmiHeader.biHeight, width as usize * pixel_width);
+
+        frame.height = height as usize;
+        frame.width = width as usize;
+        frame.row_len = width as usize * pixel_width;
+        frame.pixel_width = pixel_width;
+        frame.color_space = None;
+        frame.format = PixelFormat::Bgra;
+        frame.bits_per_component = 8;
+        frame.alpha_mode = AlphaMode::Opaque;
+        Ok(())
+    }
+
+    /// Convert a NUL-terminated UTF-16 buffer to a `String`.
+    fn from_wide(buf: &[u16]) -> String {
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        String::from_utf16_lossy(&buf[..len])
+    }
+
+    /// The adapter output `h_monitor` is on, such as `\\.\DISPLAY1`.
+    unsafe fn monitor_device(h_monitor: windef::HMONITOR) -> Option<winuser::MONITORINFOEXW> {
+        let mut info: winuser::MONITORINFOEXW = mem::zeroed();
+        info.cbSize = size_of::<winuser::MONITORINFOEXW>() as minwindef::DWORD;
+        if winuser::GetMonitorInfoW(
+            h_monitor,
+            &mut info as *mut winuser::MONITORINFOEXW as winuser::LPMONITORINFO,
+        ) == 0
+        {
+            return None;
+        }
+        Some(info)
+    }
+
+    /// The product name of the monitor plugged into the adapter output
+    /// `device`, as reported by `EnumDisplayDevicesW`.
+    unsafe fn monitor_name(device: &[u16]) -> Option<String> {
+        // The output's first device is the monitor.
+        let mut monitor: wingdi::DISPLAY_DEVICEW = mem::zeroed();
+        monitor.cb = size_of::<wingdi::DISPLAY_DEVICEW>() as minwindef::DWORD;
+        if winuser::EnumDisplayDevicesW(device.as_ptr(), 0, &mut monitor, 0) == 0 {
+            return None;
+        }
+        Some(from_wide(&monitor.DeviceString))
+    }
+
+    /// The current mode of the adapter output `device`.
+    unsafe fn current_mode(device: &[u16]) -> Option<wingdi::DEVMODEW> {
+        let mut mode: wingdi::DEVMODEW = mem::zeroed();
+        mode.dmSize = size_of::<wingdi::DEVMODEW>() as minwindef::WORD;
+        if winuser::EnumDisplaySettingsW(device.as_ptr(), winuser::ENUM_CURRENT_SETTINGS, &mut mode)
+            == 0
+        {
+            return None;
+        }
+        Some(mode)
+    }
+
+    /// The refresh rate of `mode`.
+    fn refresh_rate(mode: &wingdi::DEVMODEW) -> Option<f64> {
+        // 0 and 1 both mean the hardware default, whatever that is.
+        if mode.dmDisplayFrequency > 1 {
+            Some(mode.dmDisplayFrequency as f64)
+        } else {
+            None
+        }
+    }
+
+    /// The size GDI reports for the adapter output `device`.
+    unsafe fn physical_size(device: &[u16]) -> Option<(u32, u32)> {
+        let h_dc = wingdi::CreateDCW(null_mut(), device.as_ptr(), null_mut(), null_mut());
+        if h_dc.is_null() {
+            return None;
+        }
+        let width = wingdi::GetDeviceCaps(h_dc, wingdi::HORZSIZE);
+        let height = wingdi::GetDeviceCaps(h_dc, wingdi::VERTSIZE);
+        wingdi::DeleteDC(h_dc);
+        ::physical_size_mm(width as f64, height as f64)
+    }
+
+    unsafe extern "system" fn push_monitor(
+        h_monitor: windef::HMONITOR,
+        _h_dc: windef::HDC,
+        rect: windef::LPRECT,
+        displays: minwindef::LPARAM,
+    ) -> minwindef::BOOL {
+        let displays = &mut *(displays as *mut Vec<DisplayInfo>);
+        let rect = &*rect;
+        let device = monitor_device(h_monitor);
+        let device = device.as_ref().map(|info| &info.szDevice[..]);
+        let mode = device.and_then(|device| current_mode(device));
+        displays.push(DisplayInfo {
+            index: displays.len(),
+            x: rect.left,
+            y: rect.top,
+            width: (rect.right - rect.left) as usize,
+            height: (rect.bottom - rect.top) as usize,
+            name: device.and_then(|device| monitor_name(device)),
+            refresh_rate_hz: mode.as_ref().and_then(refresh_rate),
+            physical_size_mm: device.and_then(|device| physical_size(device)),
+            mirror_of: None,
+            // DMDO_DEFAULT to DMDO_270 count clockwise quarter turns.
+            rotation: mode.map_or(Rotation::Normal, |mode| {
+                Rotation::from_quarter_turns(mode.u1.s2().dmDisplayOrientation as i64)
+            }),
+        });
+        minwindef::TRUE
+    }
+
+    /// List monitors in the order `EnumDisplayMonitors` reports them.
+    pub fn list_displays() -> Result<Vec<DisplayInfo>, CaptureError> {
+        let mut displays: Vec<DisplayInfo> = Vec::new();
+        let res = unsafe {
+            winuser::EnumDisplayMonitors(
+                null_mut(),
+                null_mut(),
+                Some(push_monitor),
+                &mut displays as *mut Vec<DisplayInfo> as minwindef::LPARAM,
+            )
+        };
+        if res == 0 {
+            return Err(CaptureError::Native("Can't enumerate Windows monitors."));
+        }
+        Ok(displays)
+    }
+
+    /// Null-terminated UTF-16 copy of `s`, for wide Win32 calls.
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    unsafe extern "system" fn watch_wnd_proc(
+        h_wnd: windef::HWND,
+        msg: minwindef::UINT,
+        w_param: minwindef::WPARAM,
+        l_param: minwindef::LPARAM,
+    ) -> minwindef::LRESULT {
+        match msg {
+            winuser::WM_DISPLAYCHANGE => {
+                let callback =
+                    winuser::GetWindowLongPtrW(h_wnd, winuser::GWLP_USERDATA) as *mut WatchCallback;
+                if !callback.is_null() {
+                    if let Ok(displays) = list_displays() {
+                        (*callback)(displays);
+                    }
+                }
+                0
+            }
+            winuser::WM_DESTROY => {
+                winuser::PostQuitMessage(0);
+                0
+            }
+            _ => winuser::DefWindowProcW(h_wnd, msg, w_param, l_param),
+        }
+    }
+
+    pub struct DisplayWatch {
+        // HWND isn't Send, so it's carried as an integer.
+        h_wnd: usize,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Drop for DisplayWatch {
+        fn drop(&mut self) {
+            unsafe {
+                winuser::PostMessageW(self.h_wnd as windef::HWND, winuser::WM_CLOSE, 0, 0);
+            }
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Displays aren't cached on Windows.
+    pub fn invalidate_display_cache() {}
+
+    /// WM_DISPLAYCHANGE is only broadcast to top-level windows, so the
+    /// watcher owns a hidden one and pumps its messages on a new thread.
+    pub fn watch_displays(callback: WatchCallback) -> Result<DisplayWatch, CaptureError> {
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || unsafe {
+            let class_name = wide("screenshot-rs display watch");
+            let h_instance = libloaderapi::GetModuleHandleW(null_mut());
+            let mut wnd_class: winuser::WNDCLASSW = mem::zeroed();
+            wnd_class.lpfnWndProc = Some(watch_wnd_proc);
+            wnd_class.hInstance = h_instance;
+            wnd_class.lpszClassName = class_name.as_ptr();
+            // Fails harmlessly if an earlier watcher registered the class.
+            winuser::RegisterClassW(&wnd_class);
+
+            let h_wnd = winuser::CreateWindowExW(
+                0,
+                class_name.as_ptr(),
+                class_name.as_ptr(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                null_mut(),
+                null_mut(),
+                h_instance,
+                null_mut(),
+            );
+            if h_wnd.is_null() {
+                let _ = started_tx.send(Err(CaptureError::Native(
+                    "Can't create a Windows message window.",
+                )));
+                return;
+            }
+            let callback = Box::into_raw(Box::new(callback));
+            winuser::SetWindowLongPtrW(h_wnd, winuser::GWLP_USERDATA, callback as isize);
+            let _ = started_tx.send(Ok(h_wnd as usize));
+
+            let mut msg: winuser::MSG = mem::zeroed();
+            while winuser::GetMessageW(&mut msg, null_mut(), 0, 0) > 0 {
+                winuser::TranslateMessage(&msg);
+                winuser::DispatchMessageW(&msg);
+            }
+            drop(Box::from_raw(callback));
+        });
+
+        match started_rx.recv() {
+            Ok(Ok(h_wnd)) => Ok(DisplayWatch {
+                h_wnd,
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => Err(CaptureError::Native("Display watcher thread died.")),
+        }
+    }
+
+    /// Put the screenshot on the clipboard as a `CF_DIB`, which is a BMP file
+    /// without its `BITMAPFILEHEADER`.
+    #[cfg(feature = "clipboard")]
+    pub fn copy_to_clipboard(screenshot: &Screenshot) -> Result<(), CaptureError> {
+        const FILE_HEADER_LEN: usize = 14;
+        let bmp = screenshot.to_bmp_bytes();
+        let dib = &bmp[FILE_HEADER_LEN..];
+        unsafe {
+            let h_mem = winbase::GlobalAlloc(winbase::GMEM_MOVEABLE, dib.len());
+            if h_mem.is_null() {
+                return Err(CaptureError::Native("Can't allocate clipboard memory."));
+            }
+            let ptr = winbase::GlobalLock(h_mem) as *mut u8;
+            if ptr.is_null() {
+                winbase::GlobalFree(h_mem);
+                return Err(CaptureError::Native("Can't lock clipboard memory."));
+            }
+            ptr::copy_nonoverlapping(dib.as_ptr(), ptr, dib.len());
+            winbase::GlobalUnlock(h_mem);
+
+            if winuser::OpenClipboard(null_mut()) == 0 {
+                winbase::GlobalFree(h_mem);
+                return Err(CaptureError::Native("Can't open the clipboard."));
+            }
+            winuser::EmptyClipboard();
+            // On success the clipboard owns the memory.
+            let res = winuser::SetClipboardData(winuser::CF_DIB, h_mem);
+            winuser::CloseClipboard();
+            if res.is_null() {
+                winbase::GlobalFree(h_mem);
+                return Err(CaptureError::Native("Can't set the clipboard data."));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A screenshot of `data` laid out as given, with the other fields as
+/// tests usually want them: 8-bit straight alpha and no color space.
+#[cfg(test)]
+fn test_screenshot(
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+    row_len: usize,
+    pixel_width: usize,
+    format: PixelFormat,
+) -> Screenshot {
+    Screenshot {
+        data,
+        height,
+        width,
+        row_len,
+        pixel_width,
+        color_space: None,
+        format,
+        bits_per_component: 8,
+        captured_at: UNIX_EPOCH,
+        alpha_mode: AlphaMode::Straight,
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_parallel_transforms_match_serial() {
+    let (width, height, row_len) = (67, 41, 67 * 4 + 12);
+    let data = (0..row_len * height)
+        .map(|i| (i * 31 % 251) as u8)
+        .collect();
+    let s = test_screenshot(data, width, height, row_len, 4, PixelFormat::Bgra);
+
+    let mut rgba = Vec::new();
+    let mut gray = Vec::new();
+    for row in 0..height {
+        for col in 0..width {
+            let p = s.get_pixel(row, col);
+            rgba.extend_from_slice(&[p.r, p.g, p.b, p.a]);
+            gray.push(luma(p.r, p.g, p.b));
+        }
+    }
+    assert_eq!(s.to_rgba(), rgba);
+    assert_eq!(s.to_grayscale(), gray);
+
+    let small = s.resize(20, 13).unwrap();
+    for row in 0..13 {
+        for col in 0..20 {
+            let p = small.get_pixel(row, col);
+            let q = s.get_pixel((2 * row + 1) * height / 26, (2 * col + 1) * width / 40);
+            assert_eq!((p.a, p.r, p.g, p.b), (q.a, q.r, q.g, q.b));
+        }
+    }
+}
+
+#[test]
+fn test_extract_channel() {
+    let (width, height, row_len) = (5, 3, 5 * 4 + 4);
+    let data: Vec<u8> = (0..row_len * height).map(|i| i as u8).collect();
+    let formats = [
+        PixelFormat::Bgra,
+        PixelFormat::Argb,
+        PixelFormat::Rgba,
+        PixelFormat::Abgr,
+    ];
+    for &format in &formats {
+        let s = test_screenshot(data.clone(), width, height, row_len, 4, format);
+        for &channel in &[Channel::Alpha, Channel::Red, Channel::Green, Channel::Blue] {
+            let mut expected = Vec::new();
+            for row in 0..height {
+                for col in 0..width {
+                    let p = s.get_pixel(row, col);
+                    expected.push(match channel {
+                        Channel::Alpha => p.a,
+                        Channel::Red => p.r,
+                        Channel::Green => p.g,
+                        Channel::Blue => p.b,
+                    });
+                }
+            }
+            assert_eq!(s.extract_channel(channel), expected);
+        }
+    }
+    // Red is the first byte of an Rgba pixel.
+    let rgba = test_screenshot(data, width, height, row_len, 4, PixelFormat::Rgba);
+    assert_eq!(rgba.extract_channel(Channel::Red)[1], 4);
+    // Pixels without an alpha byte are opaque.
+    let rgb = test_screenshot(vec![1, 2, 3], 1, 1, 3, 3, PixelFormat::Bgra);
+    assert_eq!(rgb.extract_channel(Channel::Alpha), vec![255]);
+    assert_eq!(rgb.extract_channel(Channel::Red), vec![3]);
+}
+
+#[test]
+fn test_index_pixel() {
+    let (width, height, row_len) = (3, 2, 3 * 4 + 4);
+    let data = (0..row_len * height).map(|i| i as u8).collect();
+    let s = test_screenshot(data, width, height, row_len, 4, PixelFormat::Bgra);
+
+    let p = s[(1, 2)];
+    assert_eq!((p.b, p.g, p.r, p.a), (24, 25, 26, 27));
+    let q = s.get_pixel(1, 2);
+    assert_eq!((p.b, p.g, p.r, p.a), (q.b, q.g, q.r, q.a));
+    assert!(s.get_pixel_checked(1, 2).is_some());
+    assert!(s.get_pixel_checked(2, 0).is_none());
+    assert!(s.get_pixel_checked(0, 3).is_none());
+}
+
+#[test]
+#[should_panic(expected = "only 8-bit Bgra screenshots can be indexed")]
+fn test_index_pixel_rejects_other_formats() {
+    let s = test_screenshot(vec![1, 2, 3, 4], 1, 1, 4, 4, PixelFormat::Rgba);
+    assert_eq!(s.get_pixel(0, 0).r, 1);
+    let _ = s[(0, 0)];
+}
+
+#[test]
+#[should_panic(expected = "only 8-bit Bgra screenshots can be indexed")]
+fn test_index_pixel_rejects_3_byte_pixels() {
+    let s = test_screenshot(vec![1, 2, 3], 1, 1, 3, 3, PixelFormat::Bgra);
+    assert_eq!(s.get_pixel(0, 0).r, 3);
+    let _ = s[(0, 0)];
+}
+
+#[test]
+fn test_count_unique_colors() {
+    let black = Pixel {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    let mut s = Screenshot::new_blank(4, 4, black);
+    assert_eq!(s.count_unique_colors(10), 1);
+    s.set_pixel(0, 0, Pixel { r: 1, ..black });
+    s.set_pixel(3, 3, Pixel { a: 0, ..black });
+    assert_eq!(s.count_unique_colors(10), 3);
+    assert_eq!(s.count_unique_colors(2), 2);
+    assert_eq!(s.count_unique_colors(0), 0);
+    assert_eq!(Screenshot::default().count_unique_colors(10), 0);
+}
+
+#[test]
+fn test_rotate() {
+    // 3 x 2, with each pixel's blue channel numbering it in reading order.
+    let mut s = Screenshot::new_blank(
+        3,
+        2,
+        Pixel {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+    for row in 0..2 {
+        for col in 0..3 {
+            let b = (row * 3 + col) as u8;
+            s.set_pixel(
+                row,
+                col,
+                Pixel {
+                    r: 0,
+                    g: 0,
+                    b,
+                    a: 255,
+                },
+            );
+        }
+    }
+    let order = |s: &Screenshot| {
+        let mut blues = Vec::new();
+        for row in 0..s.height() {
+            for col in 0..s.width() {
+                blues.push(s.get_pixel(row, col).b);
+            }
+        }
+        (s.width(), s.height(), blues)
+    };
+    assert_eq!(order(&s.rotate90()), (2, 3, vec![3, 0, 4, 1, 5, 2]));
+    assert_eq!(order(&s.rotate180()), (3, 2, vec![5, 4, 3, 2, 1, 0]));
+    assert_eq!(order(&s.rotate270()), (2, 3, vec![2, 5, 1, 4, 0, 3]));
+    assert!(s.rotate90().rotate270() == s);
+    assert_eq!(Rotation::from_quarter_turns(-1), Rotation::Rot270);
+}
+
+#[test]
+fn test_row_checksums() {
+    let gray = Pixel {
+        r: 50,
+        g: 50,
+        b: 50,
+        a: 255,
+    };
+    let a = Screenshot::new_blank(5, 3, gray);
+    // The same pixels with two bytes of padding per row, which don't count.
+    let mut b = a.clone();
+    b.data = a
+        .data
+        .chunks(20)
+        .flat_map(|row| row.iter().cloned().chain(vec![7, 7]))
+        .collect();
+    b.row_len = 22;
+    assert_eq!(a.row_checksums(), b.row_checksums());
+    b.set_pixel(1, 4, Pixel { r: 51, ..gray });
+    let (sums_a, sums_b) = (a.row_checksums(), b.row_checksums());
+    assert_eq!(sums_a.len(), 3);
+    assert_eq!((sums_a[0], sums_a[2]), (sums_b[0], sums_b[2]));
+    assert_ne!(sums_a[1], sums_b[1]);
+}
+
+#[test]
+fn test_bounding_box_of_change() {
+    let (width, height, row_len) = (6, 5, 6 * 4 + 8);
+    let data: Vec<u8> = (0..row_len * height).map(|i| i as u8).collect();
+    let a = test_screenshot(data, width, height, row_len, 4, PixelFormat::Bgra);
+    let mut b = a.clone();
+    assert_eq!(a.bounding_box_of_change(&b, 0), None);
+
+    b.data[3 * row_len + 4 * 4 + 1] ^= 0x40;
+    assert_eq!(a.bounding_box_of_change(&b, 0), Some((4, 3, 1, 1)));
+    assert_eq!(a.bounding_box_of_change(&b, 0x40), None);
+
+    b.data[row_len + 1] ^= 0x40;
+    assert_eq!(a.bounding_box_of_change(&b, 0), Some((0, 1, 5, 3)));
+}
+
+#[test]
+fn test_pixel_count_and_aspect_ratio() {
+    let s = test_screenshot(vec![0; 16 * 4 * 9], 16, 9, 16 * 4, 4, PixelFormat::Bgra);
+    assert_eq!(s.pixel_count(), 144);
+    assert_eq!(s.aspect_ratio(), Some(16.0 / 9.0));
+
+    let empty = Screenshot::default();
+    assert_eq!(empty.pixel_count(), 0);
+    assert_eq!(empty.aspect_ratio(), None);
+}
+
+#[test]
+fn test_trim_border() {
+    // A 7x6 white image with a 2x3 block of color at column 3, row 1, plus
+    // one near-white pixel that a tolerance of 4 treats as border.
+    let (width, height) = (7, 6);
+    let mut data = vec![0xff; width * height * 4];
+    for row in 1..4 {
+        for col in 3..5 {
+            let i = (row * width + col) * 4;
+            data[i..i + 3].copy_from_slice(&[10, 20, 30]);
+        }
+    }
+    data[(5 * width + 6) * 4] = 0xfc;
+    let s = test_screenshot(data, width, height, width * 4, 4, PixelFormat::Bgra);
+    let white = Pixel {
+        a: 0xff,
+        r: 0xff,
+        g: 0xff,
+        b: 0xff,
+    };
+
+    let trimmed = s.trim_border(white, 4);
+    assert_eq!((trimmed.width(), trimmed.height()), (2, 3));
+    let p = trimmed.get_pixel(0, 0);
+    assert_eq!((p.b, p.g, p.r), (10, 20, 30));
+
+    let trimmed = s.trim_border(white, 0);
+    assert_eq!((trimmed.width(), trimmed.height()), (4, 5));
+
+    let black = Pixel {
+        a: 0xff,
+        r: 0,
+        g: 0,
+        b: 0,
+    };
+    assert_eq!(s.trim_border(black, 0).width(), 7);
+    let trimmed = s.trim_border(white, 255);
+    assert_eq!((trimmed.width(), trimmed.height()), (0, 0));
+}
+
+#[test]
+fn test_crop_to_content() {
+    let white = Pixel {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+    let mut s = Screenshot::new_blank(5, 4, white);
+    assert!(s.crop_to_content(white, 0).is_err());
+
+    let black = Pixel {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    s.set_pixel(1, 2, black);
+    s.set_pixel(2, 3, black);
+    let (content, x, y) = s.crop_to_content(white, 0).unwrap();
+    assert_eq!((x, y, content.width(), content.height()), (2, 1, 2, 2));
+    assert_eq!(content.get_pixel(0, 0).r, 0);
+    assert_eq!(content.get_pixel(1, 1).r, 0);
+    assert_eq!(content.get_pixel(0, 1).r, 255);
+}
+
+#[test]
+fn test_pixel_order_and_quantize() {
+    let p = Pixel {
+        r: 0xab,
+        g: 0x12,
+        b: 0xff,
+        a: 0xff,
+    };
+    assert_eq!(p.to_u32(), 0xffab_12ff);
+    let q = p.quantize(4);
+    assert_eq!((q.r, q.g, q.b, q.a), (0xa0, 0x10, 0xf0, 0xff));
+    assert!(p.quantize(8) == p && p.quantize(0).r == 0);
+
+    let darker = Pixel { r: 0xaa, ..p };
+    let transparent = Pixel { a: 0, ..p };
+    let mut pixels = vec![p, transparent, darker];
+    pixels.sort();
+    assert!(pixels == vec![transparent, darker, p]);
+}
+
+#[test]
+fn test_pixel_blend() {
+    let black = Pixel {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    };
+    let white = Pixel {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+    let rgba = |p: Pixel| (p.r, p.g, p.b, p.a);
+    assert_eq!(rgba(black.blend(white, 0.0)), (0, 0, 0, 0));
+    assert_eq!(rgba(black.blend(white, 0.5)), (128, 128, 128, 128));
+    assert_eq!(rgba(black.blend(white, 1.0)), (255, 255, 255, 255));
+    assert_eq!(rgba(black.blend(white, 7.0)), (255, 255, 255, 255));
+
+    let red = |a: u8| Pixel {
+        r: 255,
+        g: 0,
+        b: 0,
+        a,
+    };
+    let bg = Pixel { a: 255, ..black };
+    assert_eq!(rgba(red(0).blend_over(bg)), (0, 0, 0, 255));
+    assert_eq!(rgba(red(128).blend_over(bg)), (128, 0, 0, 255));
+    assert_eq!(rgba(red(255).blend_over(bg)), (255, 0, 0, 255));
+    assert_eq!(rgba(red(128).blend_over(black)), (128, 0, 0, 128));
+}
+
+#[test]
+fn test_mask_rect() {
+    let mut s = test_screenshot(vec![200; 3 * 3 * 4], 3, 3, 12, 4, PixelFormat::Bgra);
+    let dim = Pixel {
+        r: 0,
+        g: 100,
+        b: 0,
+        a: 255,
+    };
+    s.mask_rect(1, 1, 5, 5, dim, 0.5);
+
+    let p = s.get_pixel(0, 0);
+    assert_eq!((p.r, p.g, p.b, p.a), (100, 150, 100, 228));
+    let p = s.get_pixel(2, 0);
+    assert_eq!((p.r, p.g, p.b), (100, 150, 100));
+    let p = s.get_pixel(1, 1);
+    assert_eq!((p.r, p.g, p.b, p.a), (200, 200, 200, 200));
+    let p = s.get_pixel(2, 2);
+    assert_eq!((p.r, p.g, p.b, p.a), (200, 200, 200, 200));
+}
+
+#[test]
+fn test_get_pixel_u16() {
+    // One RGBA pixel with little-endian 16-bit components.
+    let s = Screenshot {
+        bits_per_component: 16,
+        ..test_screenshot(
+            vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0xff, 0xff],
+            1,
+            1,
+            8,
+            8,
+            PixelFormat::Rgba,
+        )
+    };
+    assert_eq!(
+        s.get_pixel_u16(0, 0),
+        Some(Pixel16 {
+            r: 0x0201,
+            g: 0x0403,
+            b: 0x0605,
+            a: 0xffff,
+        })
+    );
+    assert!(s.get_pixel_u16(0, 1).is_none());
+    assert!(s.get_pixel_checked(0, 0).is_none());
+}
+
+#[test]
+fn test_16_bit_components_are_narrowed() {
+    // Two RGBA pixels with little-endian 16-bit components.
+    let mut s = Screenshot {
+        bits_per_component: 16,
+        ..test_screenshot(
+            vec![
+                0x01, 0x10, 0x02, 0x20, 0x03, 0x30, 0xff, 0xff, //
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+            2,
+            1,
+            16,
+            8,
+            PixelFormat::Rgba,
+        )
+    };
+    let p = s.get_pixel(0, 0);
+    assert_eq!((p.r, p.g, p.b, p.a), (0x10, 0x20, 0x30, 0xff));
+    assert_eq!(s.to_rgba(), vec![0x10, 0x20, 0x30, 0xff, 0, 0, 0, 0]);
+    assert_eq!(s.nonzero_alpha_ratio(), 0.5);
+
+    // BMP has no 64-bpp pixels, so it gets 32-bpp BGRA.
+    let bmp = s.to_bmp_bytes();
+    assert_eq!(u16::from_le_bytes([bmp[28], bmp[29]]), 32);
+    assert_eq!(bmp.len(), 14 + 40 + 2 * 4);
+    assert_eq!(bmp[54..58], [0x30, 0x20, 0x10, 0xff]);
+
+    // Writes widen each channel, and unchanged pixels keep their low bytes.
+    s.set_pixel(
+        0,
+        1,
+        Pixel {
+            r: 0xab,
+            g: 0,
+            b: 0,
+            a: 0xff,
+        },
+    );
+    assert_eq!(s.get_pixel_u16(0, 1).unwrap().r, 0xabab);
+    s.map_pixels(|p| if p.r == 0xab { Pixel { r: 0, ..p } } else { p });
+    assert_eq!(s.get_pixel_u16(0, 0).unwrap().r, 0x1001);
+    assert_eq!(s.get_pixel_u16(0, 1).unwrap().r, 0);
+
+    assert!(s.downscale_box(2).is_err());
+}
+
+#[cfg(feature = "gif")]
+#[test]
+fn test_write_gif() {
+    let color = |r| Pixel {
+        r,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    let shots = [
+        Screenshot::new_blank(2000, 10, color(0)),
+        Screenshot::new_blank(2000, 10, color(255)),
+    ];
+    let mut buf = Vec::new();
+    write_gif(&shots, Duration::from_millis(100), &mut buf).unwrap();
+    assert_eq!(&buf[..6], b"GIF89a");
+    // Downscaled by 3 to 667 x 4.
+    assert_eq!(&buf[6..10], &[0x9b, 0x02, 4, 0]);
+    assert_eq!(buf.last(), Some(&0x3b));
+
+    let mismatched = [shots[0].clone(), Screenshot::new_blank(10, 10, color(0))];
+    assert!(write_gif(&mismatched, Duration::from_millis(100), &mut Vec::new()).is_err());
+    assert!(write_gif(&[], Duration::from_millis(100), &mut Vec::new()).is_err());
+}
+
+#[test]
+fn test_to_qoi_bytes() {
+    // A black pixel, which QOI starts from, then a dark blue one, in BGRA.
+    let s = test_screenshot(
+        vec![0, 0, 0, 255, 30, 20, 10, 255],
+        2,
+        1,
+        8,
+        4,
+        PixelFormat::Bgra,
+    );
+    let mut expected = b"qoif".to_vec();
+    expected.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 1, 4, 0]);
+    expected.extend_from_slice(&[0xc0, 0xfe, 10, 20, 30]);
+    expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(s.to_qoi_bytes().unwrap(), expected);
+}
+
+#[test]
+fn test_to_ppm_bytes() {
+    // Two Argb pixels and a padding byte.
+    let s = test_screenshot(
+        vec![255, 1, 2, 3, 0, 4, 5, 6, 9],
+        2,
+        1,
+        9,
+        4,
+        PixelFormat::Argb,
+    );
+    let ppm = s.to_ppm_bytes();
+    let mut fields = ppm.splitn(4, |&b| b == b'\n');
+    assert_eq!(fields.next(), Some(&b"P6"[..]));
+    assert_eq!(fields.next(), Some(&b"2 1"[..]));
+    assert_eq!(fields.next(), Some(&b"255"[..]));
+    let pixels = fields.next().unwrap();
+    assert_eq!(pixels.len(), s.width * s.height * 3);
+    assert_eq!(pixels, &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(ImageFormat::from_extension("PPM"), Some(ImageFormat::Ppm));
+}
+
+#[test]
+fn test_downscale_box() {
+    let red = |r: u8| Pixel {
+        r,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    // Left 2x2 block averages 0, 10, 20 and 31; the right one is all 100.
+    let mut s = Screenshot::new_blank(4, 2, red(100));
+    s.set_pixel(0, 0, red(0));
+    s.set_pixel(0, 1, red(10));
+    s.set_pixel(1, 0, red(20));
+    s.set_pixel(1, 1, red(31));
+    let d = s.downscale_box(2).unwrap();
+    assert_eq!((d.width(), d.height()), (2, 1));
+    assert_eq!(d.get_pixel(0, 0).r, 15);
+    assert_eq!(d.get_pixel(0, 1).r, 100);
+    assert_eq!(d.get_pixel(0, 0).a, 255);
+    assert!(s.downscale_box(0).is_err());
+    assert_eq!(s.downscale_box(1).unwrap().data, s.data);
+}
+
+#[test]
+fn test_downscale_box_partial_blocks() {
+    let red = |r: u8| Pixel {
+        r,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    // 3x3 by 2: the right and bottom blocks only cover what's left.
+    let mut s = Screenshot::new_blank(3, 3, red(0));
+    s.set_pixel(0, 2, red(40));
+    s.set_pixel(1, 2, red(80));
+    s.set_pixel(2, 0, red(90));
+    s.set_pixel(2, 1, red(30));
+    s.set_pixel(2, 2, red(7));
+    let d = s.downscale_box(2).unwrap();
+    assert_eq!((d.width(), d.height()), (2, 2));
+    assert_eq!(d.get_pixel(0, 0).r, 0);
+    assert_eq!(d.get_pixel(0, 1).r, 60);
+    assert_eq!(d.get_pixel(1, 0).r, 60);
+    assert_eq!(d.get_pixel(1, 1).r, 7);
+
+    // A factor past the image size averages it all into one pixel.
+    let d = s.downscale_box(100).unwrap();
+    assert_eq!((d.width(), d.height()), (1, 1));
+    assert_eq!(d.get_pixel(0, 0).r, 27);
+    assert_eq!(d.get_pixel(0, 0).a, 255);
+}
+
+#[test]
+fn test_apply_gamma() {
+    let p = Pixel {
+        r: 64,
+        g: 0,
+        b: 255,
+        a: 100,
+    };
+    let mut s = Screenshot::new_blank(2, 1, p);
+    s.set_pixel(0, 1, Pixel { r: 200, ..p });
+    let original = s.data.clone();
+    s.apply_gamma(1.0);
+    assert_eq!(s.data, original);
+
+    // 255 * (64 / 255) ^ (1 / 2) = 127.75
+    s.apply_gamma(2.0);
+    let q = s.get_pixel(0, 0);
+    assert_eq!((q.r, q.g, q.b, q.a), (128, 0, 255, 100));
+    assert_eq!(s.get_pixel(0, 1).a, 100);
+}
+
+#[test]
+fn test_thumbnail() {
+    let gray = Pixel {
+        r: 50,
+        g: 50,
+        b: 50,
+        a: 255,
+    };
+    let small = Screenshot::new_blank(3, 2, gray);
+    let same = small.thumbnail(3).unwrap();
+    assert_eq!((same.width(), same.height()), (3, 2));
+    assert_eq!(same.data, small.data);
+    assert!(small.thumbnail(0).is_err());
+    let empty = Screenshot::new_blank(0, 0, gray);
+    assert_eq!(empty.thumbnail(10).unwrap().width(), 0);
+
+    let wide = Screenshot::new_blank(400, 100, gray)
+        .thumbnail(100)
+        .unwrap();
+    assert_eq!((wide.width(), wide.height()), (100, 25));
+    let tall = Screenshot::new_blank(100, 400, gray)
+        .thumbnail(100)
+        .unwrap();
+    assert_eq!((tall.width(), tall.height()), (25, 100));
+    // A side that would round to nothing keeps one pixel.
+    let thin = Screenshot::new_blank(1000, 1, gray).thumbnail(10).unwrap();
+    assert_eq!((thin.width(), thin.height()), (10, 1));
+    assert_eq!(thin.get_pixel(0, 5).r, 50);
+}
+
+#[test]
+fn test_sample_grid() {
+    // Red is the column, green the row.
+    let mut data = Vec::new();
+    for row in 0..4 {
+        for col in 0..6 {
+            data.extend_from_slice(&[0, row, col, 255]);
+        }
+    }
+    let s = test_screenshot(data, 6, 4, 24, 4, PixelFormat::Bgra);
+    let samples = s.sample_grid(3, 2).unwrap();
+    let coords: Vec<_> = samples.iter().map(|p| (p.g, p.r)).collect();
+    assert_eq!(coords, vec![(1, 1), (1, 3), (1, 5), (3, 1), (3, 3), (3, 5)]);
+    assert_eq!(s.sample_grid(10, 10).unwrap().len(), 100);
+    assert!(s.sample_grid(0, 1).is_err());
+}
+
+#[test]
+fn test_into_rgba_vec() {
+    // One Argb pixel and one byte of padding per row.
+    let s = test_screenshot(
+        vec![4, 1, 2, 3, 0, 8, 5, 6, 7, 0],
+        1,
+        2,
+        5,
+        4,
+        PixelFormat::Argb,
+    );
+    let bytes: Vec<u8> = s.into();
+    assert_eq!(bytes, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+    let s = Screenshot {
+        alpha_mode: AlphaMode::Opaque,
+        ..test_screenshot(vec![3, 2, 1], 1, 1, 3, 3, PixelFormat::Bgra)
+    };
+    assert_eq!(Vec::from(s), vec![1, 2, 3, 255]);
+}
+
+#[test]
+fn test_rgba_capture_converts() {
+    // Two Rgba pixels, red and blue, and two bytes of padding.
+    let s = test_screenshot(
+        vec![200, 10, 20, 255, 30, 40, 220, 128, 0, 0],
+        2,
+        1,
+        10,
+        4,
+        PixelFormat::Rgba,
+    );
+    let rgba = vec![200, 10, 20, 255, 30, 40, 220, 128];
+    assert_eq!(s.to_rgba(), rgba);
+    assert_eq!(Vec::from(s.clone()), rgba);
+    assert_eq!(s.to_grayscale(), vec![luma(200, 10, 20), luma(30, 40, 220)]);
+    let bmp = s.to_bmp_bytes();
+    assert_eq!(&bmp[54..], &[20, 10, 200, 255, 220, 40, 30, 128]);
+    assert!(s.is_mostly(
+        Pixel {
+            r: 200,
+            g: 10,
+            b: 20,
+            a: 0
+        },
+        0.5,
+        0
+    ));
+
+    let mut swapped = s.clone();
+    swapped.map_pixels(|p| Pixel {
+        r: p.b,
+        b: p.r,
+        ..p
+    });
+    assert_eq!(swapped.to_rgba(), vec![20, 10, 200, 255, 220, 40, 30, 128]);
+    swapped.map_pixels(|p| Pixel {
+        r: p.b,
+        b: p.r,
+        ..p
+    });
+    assert_eq!(swapped.data, s.data);
+}
+
+#[test]
+fn test_region_average() {
+    // Red on the left half, blue on the right.
+    let red = Pixel {
+        r: 200,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    let mut s = Screenshot::new_blank(4, 2, red);
+    for row in 0..2 {
+        for col in 2..4 {
+            s.set_pixel(
+                row,
+                col,
+                Pixel {
+                    r: 0,
+                    b: 100,
+                    ..red
+                },
+            );
+        }
+    }
+    let p = s.region_average(0, 0, 2, 2).unwrap();
+    assert_eq!((p.r, p.g, p.b, p.a), (200, 0, 0, 255));
+    let p = s.region_average(2, 0, 2, 2).unwrap();
+    assert_eq!((p.r, p.g, p.b, p.a), (0, 0, 100, 255));
+    let p = s.average_color().unwrap();
+    assert_eq!((p.r, p.g, p.b, p.a), (100, 0, 50, 255));
+    assert!(s.region_average(3, 0, 2, 1).is_err());
+    assert!(s.region_average(0, 0, 0, 1).is_err());
+    assert!(Screenshot::default().average_color().is_err());
+}
+
+#[test]
+fn test_edges_sobel() {
+    // Black on the left, white on the right, with 3-byte pixels.
+    let mut data = Vec::new();
+    for _ in 0..3 {
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255]);
+    }
+    let s = Screenshot {
+        alpha_mode: AlphaMode::Opaque,
+        ..test_screenshot(data, 4, 3, 12, 3, PixelFormat::Bgra)
+    };
+    let edges = s.edges_sobel();
+    assert_eq!(
+        (edges.width(), edges.height(), edges.pixel_width()),
+        (4, 3, 4)
+    );
+    for row in 0..3 {
+        let values: Vec<u8> = (0..4).map(|col| edges.get_pixel(row, col).r).collect();
+        assert_eq!(values, vec![0, 255, 255, 0]);
+        assert_eq!(edges.get_pixel(row, 1).a, 255);
+    }
+    assert_eq!(Screenshot::default().edges_sobel().raw_len(), 0);
+}
+
+#[test]
+fn test_dhash() {
+    // A horizontal gradient darkening to the right sets every bit.
+    let mut s = Screenshot::new_blank(
+        18,
+        16,
+        Pixel {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+    for row in 0..16 {
+        for col in 0..18 {
+            let v = 255 - col as u8 * 14;
+            s.set_pixel(
+                row,
+                col,
+                Pixel {
+                    r: v,
+                    g: v,
+                    b: v,
+                    a: 255,
+                },
+            );
+        }
+    }
+    assert_eq!(s.dhash(), !0);
+    let flat = Screenshot::new_blank(
+        3,
+        2,
+        Pixel {
+            r: 7,
+            g: 7,
+            b: 7,
+            a: 255,
+        },
+    );
+    assert_eq!(flat.dhash(), 0);
+    assert_eq!(hamming_distance(s.dhash(), flat.dhash()), 64);
+    assert_eq!(Screenshot::default().dhash(), 0);
+}
+
+#[test]
+fn test_auto_contrast() {
+    // Two dark and two light grey pixels in Argb order, with a padding byte
+    // at the end of each row.
+    let data = vec![
+        0x80, 64, 64, 64, 0x40, 64, 64, 64, 0xee, //
+        0x80, 192, 192, 192, 0x40, 192, 192, 192, 0xee,
+    ];
+    let mut s = test_screenshot(data, 2, 2, 9, 4, PixelFormat::Argb);
+    let histogram = s.luminance_histogram();
+    assert_eq!((histogram[64], histogram[192]), (2, 2));
+    assert_eq!(histogram.iter().sum::<u32>(), 4);
+
+    s.auto_contrast(0.0);
+    assert_eq!(
+        s.data,
+        vec![
+            0x80, 0, 0, 0, 0x40, 0, 0, 0, 0xee, //
+            0x80, 255, 255, 255, 0x40, 255, 255, 255, 0xee,
+        ]
+    );
+
+    // A flat image is left alone.
+    let mut flat = Screenshot::new_blank(
+        2,
+        2,
+        Pixel {
+            r: 9,
+            g: 9,
+            b: 9,
+            a: 255,
+        },
+    );
+    flat.auto_contrast(1.0);
+    assert_eq!(flat.get_pixel(1, 1).r, 9);
+}
+
+#[test]
+fn test_strip_and_add_alpha() {
+    // Two Rgba pixels, then a padding byte.
+    let mut s = test_screenshot(
+        vec![1, 2, 3, 128, 4, 5, 6, 255, 0],
+        2,
+        1,
+        9,
+        4,
+        PixelFormat::Rgba,
+    );
+    s.strip_alpha();
+    assert_eq!(s.raw_data_slice(), &[3, 2, 1, 6, 5, 4]);
+    assert_eq!((s.pixel_width(), s.row_len()), (3, 6));
+    let p = s.get_pixel(0, 1);
+    assert_eq!((p.r, p.g, p.b, p.a), (4, 5, 6, 255));
+
+    s.add_alpha(255);
+    assert_eq!(s.raw_data_slice(), &[3, 2, 1, 255, 6, 5, 4, 255]);
+    assert_eq!((s.pixel_width(), s.row_len()), (4, 8));
+    assert_eq!(s.alpha_mode(), AlphaMode::Opaque);
+}
+
+#[test]
+fn test_to_i420() {
+    let white = Pixel {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+    // 3 x 3: white except for a black last column.
+    let mut s = Screenshot::new_blank(3, 3, white);
+    for row in 0..3 {
+        s.set_pixel(
+            row,
+            2,
+            Pixel {
+                r: 0,
+                g: 0,
+                b: 0,
+                ..white
+            },
+        );
+    }
+    let (y, u, v) = s.to_i420();
+    assert_eq!(y, vec![235, 235, 16, 235, 235, 16, 235, 235, 16]);
+    assert_eq!((u.len(), v.len()), (4, 4));
+    assert!(u.iter().chain(&v).all(|&c| c == 128));
+
+    // Pure red has its chroma in V.
+    let red = Screenshot::new_blank(
+        2,
+        2,
+        Pixel {
+            g: 0,
+            b: 0,
+            ..white
+        },
+    );
+    let (y, u, v) = red.to_i420();
+    assert_eq!((y[0], u, v), (82, vec![90], vec![240]));
+}
+
+#[test]
+fn test_to_ascii_art() {
+    // Black on the left, white on the right.
+    let mut data = Vec::new();
+    for _ in 0..4 {
+        data.extend_from_slice(&[0, 0, 0, 255, 0, 0, 0, 255]);
+        data.extend_from_slice(&[255, 255, 255, 255, 255, 255, 255, 255]);
+    }
+    let s = test_screenshot(data, 4, 4, 16, 4, PixelFormat::Bgra);
+    assert_eq!(s.to_ascii_art(4), "  @@\n  @@\n");
+    assert_eq!(s.to_ascii_art(0), "");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_linux_backend_order() {
+    assert_eq!(linux_backend_order(), vec![Backend::XShm, Backend::Xlib]);
+    set_linux_backend_order(vec![Backend::Xlib]);
+    assert_eq!(linux_backend_order(), vec![Backend::Xlib]);
+    set_linux_backend_order(Vec::new());
+    assert_eq!(linux_backend_order(), vec![Backend::XShm, Backend::Xlib]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_fill_from_reorders() {
+    let mut s = test_screenshot(Vec::new(), 2, 1, 10, 4, PixelFormat::Rgba);
+    // Two BGRA pixels, then two bytes of row padding.
+    let src = [1, 2, 3, 0, 5, 6, 7, 0, 9, 9];
+    s.fill_from(&src, PixelFormat::Bgra, true);
+    assert_eq!(s.data, vec![3, 2, 1, 255, 7, 6, 5, 255, 9, 9]);
+    s.to_bgra_in_place();
+    assert_eq!(s.data, vec![1, 2, 3, 255, 5, 6, 7, 255, 9, 9]);
+}
+
+#[test]
+fn test_to_bgra_in_place_keeps_padding() {
+    // Two rows of two Argb pixels, each followed by two padding bytes.
+    let mut s = test_screenshot(
+        vec![
+            4, 3, 2, 1, 8, 7, 6, 5, 0xaa, 0xbb, //
+            14, 13, 12, 11, 18, 17, 16, 15, 0xcc, 0xdd,
+        ],
+        2,
+        2,
+        10,
+        4,
+        PixelFormat::Argb,
+    );
+    s.to_bgra_in_place();
+    assert_eq!(s.pixel_format(), PixelFormat::Bgra);
+    assert_eq!(
+        s.data,
+        vec![
+            1, 2, 3, 4, 5, 6, 7, 8, 0xaa, 0xbb, //
+            11, 12, 13, 14, 15, 16, 17, 18, 0xcc, 0xdd,
+        ]
+    );
+}
+
+#[test]
+fn test_nonzero_alpha_ratio() {
+    let mut s = test_screenshot(
+        vec![0, 0, 0, 0, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 7],
+        2,
+        2,
+        8,
+        4,
+        PixelFormat::Bgra,
+    );
+    assert_eq!(s.nonzero_alpha_ratio(), 0.5);
+    s.format = PixelFormat::Argb;
+    assert_eq!(s.nonzero_alpha_ratio(), 0.0);
+    assert_eq!(Screenshot::default().nonzero_alpha_ratio(), 0.0);
+}
+
+#[test]
+fn test_eq_and_hash_ignore_padding() {
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash(s: &Screenshot) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        s.hash(&mut hasher);
+        hasher.finish()
+    }
+    let packed = test_screenshot(vec![1, 2, 3, 4, 5, 6, 7, 8], 1, 2, 4, 4, PixelFormat::Bgra);
+    let mut padded = packed.clone();
+    padded.data = vec![1, 2, 3, 4, 0, 0, 5, 6, 7, 8, 9, 9];
+    padded.row_len = 6;
+    assert!(packed == padded);
+    assert_eq!(hash(&packed), hash(&padded));
+    padded.data[5] = 1;
+    assert!(packed == padded);
+    padded.data[6] = 0;
+    assert!(packed != padded);
+}
+
+#[test]
+fn test_save() {
+    let s = test_screenshot(
+        vec![10, 20, 30, 255, 40, 50, 60, 255],
+        2,
+        1,
+        8,
+        4,
+        PixelFormat::Bgra,
+    );
+    let dir = std::env::temp_dir();
+    let check = |name: &str, magic: &[u8]| {
+        let path = dir.join(format!("screenshot-test-save-{}", name));
+        s.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(bytes.starts_with(magic), "{}", name);
+    };
+    #[cfg(feature = "png")]
+    check("a.PNG", b"\x89PNG");
+    check("a.bmp", b"BM");
+    #[cfg(feature = "jpeg")]
+    {
+        check("a.jpg", b"\xff\xd8");
+        check("a.jpeg", b"\xff\xd8");
+    }
+    check("a.qoi", b"qoif");
+    check("a.ppm", b"P6");
+
+    match s.save(dir.join("screenshot-test-save-a.gif")) {
+        Err(CaptureError::UnsupportedFormat(ref ext)) => assert_eq!(ext, "gif"),
+        _ => panic!("saved a GIF"),
+    }
+}
+
+#[test]
+fn test_region_fits() {
+    assert!(region_fits(None, 0, 0));
+    assert!(region_fits(Some((0, 0, 10, 10)), 10, 10));
+    assert!(region_fits(Some((5, 5, 5, 5)), 10, 10));
+    assert!(!region_fits(Some((6, 0, 5, 5)), 10, 10));
+    assert!(!region_fits(Some((0, 0, 0, 5)), 10, 10));
+    assert!(!region_fits(Some((usize::MAX, 0, 1, 1)), 10, 10));
+}
+
+#[test]
+fn test_get_pixel_widths() {
+    let mut s = test_screenshot(vec![1, 2, 3, 4, 5, 6, 7, 8], 2, 1, 8, 4, PixelFormat::Bgra);
+    let p = s.get_pixel(0, 1);
+    assert_eq!((p.b, p.g, p.r, p.a), (5, 6, 7, 8));
+
+    // 24-bit pixels with two bytes of row padding.
+    s.data = vec![1, 2, 3, 4, 5, 6, 0, 0];
+    s.pixel_width = 3;
+    let p = s.get_pixel(0, 1);
+    assert_eq!((p.b, p.g, p.r, p.a), (4, 5, 6, 255));
+    assert!(s.get_pixel_checked(0, 1).is_some());
+    assert!(s.get_pixel_checked(0, 2).is_none());
+}
+
+#[test]
+fn test_contains_point() {
+    let info = DisplayInfo {
+        index: 1,
+        x: -1920,
+        y: 0,
+        width: 1920,
+        height: 1080,
+        name: None,
+        refresh_rate_hz: None,
+        physical_size_mm: None,
+        mirror_of: None,
+        rotation: Rotation::Normal,
+    };
+    assert!(info.contains_point(-1920, 0));
+    assert!(info.contains_point(-1, 1079));
+    assert!(!info.contains_point(0, 0));
+    assert!(!info.contains_point(-100, 1080));
+    assert!(!info.contains_point(i32::MIN, -1));
+}
+
+#[test]
+fn test_dib_rows_top_down() {
+    // Three rows of two bytes, each row distinct and not a palindrome.
+    let top_down = [1, 2, 3, 4, 5, 6];
+    let mut data = top_down;
+    dib_rows_top_down(&mut data, -3, 2);
+    assert_eq!(data, top_down);
+    let mut data = [5, 6, 3, 4, 1, 2];
+    dib_rows_top_down(&mut data, 3, 2);
+    assert_eq!(data, top_down);
+    let mut data = [3, 4, 1, 2];
+    dib_rows_top_down(&mut data, 2, 2);
+    assert_eq!(data, [1, 2, 3, 4]);
+
+    // The BMP writer asks for top-down rows the same way.
+    let mut s = Screenshot::new_blank(
+        1,
+        2,
+        Pixel {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+    s.set_pixel(
+        0,
+        0,
+        Pixel {
+            r: 9,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+    let bmp = s.to_bmp_bytes();
+    assert_eq!(i32::from_le_bytes([bmp[22], bmp[23], bmp[24], bmp[25]]), -2);
+    assert_eq!(&bmp[54..58], &[0, 0, 9, 255]);
+}
+
+#[test]
+fn test_virtual_bounds() {
+    let left = DisplayInfo {
+        index: 0,
+        x: -1920,
+        y: 200,
+        width: 1920,
+        height: 1080,
+        name: None,
+        refresh_rate_hz: None,
+        physical_size_mm: None,
+        mirror_of: None,
+        rotation: Rotation::Normal,
+    };
+    let right = DisplayInfo {
+        index: 1,
+        x: 0,
+        y: 0,
+        width: 2560,
+        height: 1440,
+        ..left.clone()
+    };
+    assert_eq!(virtual_bounds(&[left, right]), Some((-1920, 0, 4480, 1440)));
+    assert_eq!(virtual_bounds(&[]), None);
+}
+
+#[test]
+fn test_named_region() {
+    let left = DisplayInfo {
+        index: 0,
+        x: -1920,
+        y: 200,
+        width: 1920,
+        height: 1080,
+        name: Some("Left".to_string()),
+        refresh_rate_hz: None,
+        physical_size_mm: None,
+        mirror_of: None,
+        rotation: Rotation::Normal,
+    };
+    let right = DisplayInfo {
+        index: 1,
+        x: 0,
+        y: 0,
+        width: 2560,
+        height: 1440,
+        name: Some("Right".to_string()),
+        ..left.clone()
+    };
+    let displays = [left, right];
+    assert_eq!(
+        named_region(&displays, "Left", (10, 20, 100, 50)).unwrap(),
+        (0, (-1910, 220))
+    );
+    assert_eq!(
+        named_region(&displays, "Right", (2460, 1390, 100, 50)).unwrap(),
+        (1, (2460, 1390))
+    );
+    match named_region(&displays, "Right", (2461, 0, 100, 50)) {
+        Err(CaptureError::InvalidArgument(_)) => {}
+        other => panic!("expected InvalidArgument, got {:?}", other),
+    }
+    match named_region(&displays, "Middle", (0, 0, 1, 1)) {
+        Err(CaptureError::DisplayNotFound(DisplayId::Name(ref name))) => assert_eq!(name, "Middle"),
+        other => panic!("expected DisplayNotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_extreme_display() {
+    let middle = DisplayInfo {
+        index: 0,
+        x: 0,
+        y: 0,
+        width: 1920,
+        height: 1080,
+        name: None,
+        refresh_rate_hz: None,
+        physical_size_mm: None,
+        mirror_of: None,
+        rotation: Rotation::Normal,
+    };
+    let left = DisplayInfo {
+        index: 1,
+        x: -1280,
+        y: 300,
+        ..middle.clone()
+    };
+    let right = DisplayInfo {
+        index: 2,
+        x: 1920,
+        y: -200,
+        ..middle.clone()
+    };
+    let displays = [middle, left, right];
+    assert_eq!(extreme_display(&displays, |d| d.x).unwrap(), 1);
+    assert_eq!(
+        extreme_display(&displays, |d| cmp::Reverse(d.x)).unwrap(),
+        2
+    );
+    assert_eq!(extreme_display(&displays, |d| d.y).unwrap(), 2);
+    assert_eq!(
+        extreme_display(&displays, |d| cmp::Reverse(d.y)).unwrap(),
+        1
+    );
+    assert!(extreme_display(&[], |d| d.x).is_err());
+}
+
+#[test]
+fn test_replace_color() {
+    // A red pixel on a slightly noisy green background, with row padding.
+    let mut s = test_screenshot(
+        vec![
+            0, 255, 0, 255, 2, 250, 1, 255, 0, 0, 0, 0, //
+            0, 0, 255, 255, 0, 253, 0, 255, 0, 0, 0, 0,
+        ],
+        2,
+        2,
+        12,
+        4,
+        PixelFormat::Bgra,
+    );
+    let green = Pixel {
+        r: 0,
+        g: 255,
+        b: 0,
+        a: 255,
+    };
+    s.replace_color(green, Pixel { a: 0, ..green }, 5);
+    assert_eq!(
+        s.data,
+        vec![
+            0, 255, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, //
+            0, 0, 255, 255, 0, 255, 0, 0, 0, 0, 0, 0,
+        ]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_captured_at_survives_crop() {
+    let mut s = Screenshot::black(4, 4, 4);
+    assert!(s.captured_at() > UNIX_EPOCH);
+    s.captured_at = UNIX_EPOCH + Duration::from_secs(42);
+    assert_eq!(s.crop(1, 1, 2, 2).unwrap().captured_at(), s.captured_at());
+    assert_eq!(Screenshot::default().captured_at(), UNIX_EPOCH);
+}
+
+#[test]
+fn test_overlay_text() {
+    let mut s = test_screenshot(vec![0; 9 * 8 * 4], 9, 8, 9 * 4, 4, PixelFormat::Bgra);
+    let white = Pixel {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+    // The second "1" runs off the right edge.
+    s.overlay_text("11", 0, 0, white);
+    let lit = |s: &Screenshot, row, col| s.get_pixel(row, col).r == 255;
+    // "1" is a full-height stem in its third column, with a flag and foot.
+    assert!((0..7).all(|row| lit(&s, row, 2)));
+    assert!(lit(&s, 1, 1) && lit(&s, 6, 1) && lit(&s, 6, 3));
+    assert!(!lit(&s, 0, 0) && !lit(&s, 0, 1) && !lit(&s, 7, 2));
+    assert!((0..7).all(|row| lit(&s, row, 8)));
+}
+
+#[test]
+fn test_get_and_set_pixel_follow_format() {
+    let color = Pixel {
+        r: 1,
+        g: 2,
+        b: 3,
+        a: 4,
+    };
+    for &(format, bytes) in &[
+        (PixelFormat::Bgra, [3, 2, 1, 4]),
+        (PixelFormat::Argb, [4, 1, 2, 3]),
+        (PixelFormat::Rgba, [1, 2, 3, 4]),
+        (PixelFormat::Abgr, [4, 3, 2, 1]),
+    ] {
+        let mut s = Screenshot::new_blank(1, 1, color);
+        s.format = format;
+        s.set_pixel(0, 0, color);
+        assert_eq!(s.raw_data_slice(), &bytes[..], "{:?}", format);
+        let p = s.get_pixel(0, 0);
+        assert_eq!((p.r, p.g, p.b, p.a), (1, 2, 3, 4), "{:?}", format);
+    }
+}
+
+#[test]
+fn test_physical_size_mm() {
+    assert_eq!(physical_size_mm(597.4, 336.0), Some((597, 336)));
+    assert_eq!(physical_size_mm(0.0, 336.0), None);
+    assert_eq!(physical_size_mm(-1.0, -1.0), None);
+}
+
+#[test]
+fn test_to_data_url() {
+    let mut out = String::new();
+    for &input in &["", "f", "fo", "foo", "foob", "fooba", "foobar"] {
+        base64_encode(input.as_bytes(), &mut out);
+        out.push(',');
+    }
+    assert_eq!(out, ",Zg==,Zm8=,Zm9v,Zm9vYg==,Zm9vYmE=,Zm9vYmFy,");
+
+    let s = Screenshot::new_blank(
+        1,
+        1,
+        Pixel {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+    );
+    let url = s.to_data_url_as(ImageFormat::Qoi).unwrap();
+    let mut expected = String::from("data:image/qoi;base64,");
+    base64_encode(&s.to_qoi_bytes().unwrap(), &mut expected);
+    assert_eq!(url, expected);
+
+    #[cfg(feature = "png")]
+    {
+        let url = s.to_data_url().unwrap();
+        let mut expected = String::from("data:image/png;base64,");
+        base64_encode(&s.to_png_bytes().unwrap(), &mut expected);
+        assert_eq!(url, expected);
+    }
+}
+
+#[test]
+fn test_crop() {
+    let color = |r: u8| Pixel {
+        r,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    let mut s = Screenshot::new_blank(3, 2, color(0));
+    s.set_pixel(1, 2, color(9));
+    let c = s.crop(1, 1, 2, 1).unwrap();
+    assert_eq!((c.width(), c.height()), (2, 1));
+    assert_eq!(c.get_pixel(0, 1).r, 9);
+    assert!(s.crop(0, 0, 3, 2).is_ok());
+    assert!(s.crop(1, 0, 3, 2).is_err());
+    assert!(s.crop(0, 1, 3, 2).is_err());
+    assert!(s.crop(0, 0, 0, 2).is_err());
+    assert!(s.crop(usize::MAX, 0, 2, 2).is_err());
+    assert!(s.crop(0, 1, 1, usize::MAX).is_err());
+}
+
+#[test]
+fn test_split() {
+    let color = |r: u8| Pixel {
+        r,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    let mut s = Screenshot::new_blank(3, 2, color(0));
+    s.set_pixel(1, 2, color(9));
+    let (top, bottom) = s.split_horizontally(1).unwrap();
+    assert_eq!((top.width(), top.height()), (3, 1));
+    assert_eq!((bottom.width(), bottom.height()), (3, 1));
+    assert_eq!(bottom.get_pixel(0, 2).r, 9);
+    let (left, right) = s.split_vertically(2).unwrap();
+    assert_eq!((left.width(), right.width()), (2, 1));
+    assert_eq!(right.get_pixel(1, 0).r, 9);
+    assert!(s.split_horizontally(0).is_err());
+    assert!(s.split_horizontally(2).is_err());
+    assert!(s.split_vertically(3).is_err());
+    assert!(s.split_vertically(usize::MAX).is_err());
+}
+
+#[test]
+fn test_subtract() {
+    let color = |r: u8, g: u8, b: u8, a: u8| Pixel { r, g, b, a };
+    let a = Screenshot::new_blank(2, 1, color(10, 200, 30, 40));
+    let mut b = a.clone();
+    b.set_pixel(0, 1, color(15, 100, 30, 0));
+
+    let d = a.subtract(&b).unwrap();
+    assert_eq!(d.alpha_mode(), AlphaMode::Opaque);
+    let p = d.get_pixel(0, 0);
+    assert_eq!((p.r, p.g, p.b, p.a), (0, 0, 0, 255));
+    let p = d.get_pixel(0, 1);
+    assert_eq!((p.r, p.g, p.b, p.a), (5, 100, 0, 255));
+    let tall = Screenshot::new_blank(1, 2, color(0, 0, 0, 0));
+    assert!(a.is_same_size(&b) && !a.is_same_size(&tall));
+    match a.subtract(&tall) {
+        Err(CaptureError::SizeMismatch { expected, actual }) => {
+            assert_eq!((expected, actual), ((2, 1), (1, 2)))
+        }
+        _ => panic!("expected a size mismatch"),
+    }
+}
+
+#[test]
+fn test_mean_squared_error() {
+    let color = |r: u8, g: u8, b: u8, a: u8| Pixel { r, g, b, a };
+    let a = Screenshot::new_blank(2, 1, color(10, 200, 30, 255));
+    let mut b = a.clone();
+    b.set_pixel(0, 1, color(16, 200, 30, 0));
+    // One of six samples is off by 6, and alpha doesn't count.
+    assert_eq!(a.mean_squared_error(&b).unwrap(), 6.0);
+    let psnr = a.psnr(&b).unwrap();
+    assert!((psnr - 10.0 * (255.0f64 * 255.0 / 6.0).log10()).abs() < 1e-9);
+    assert_eq!(a.psnr(&a).unwrap(), f64::INFINITY);
+
+    let mut rgba = b.clone();
+    rgba.reorder_in_place(PixelFormat::Rgba);
+    assert_eq!(a.mean_squared_error(&rgba).unwrap(), 6.0);
+    let tall = Screenshot::new_blank(1, 2, color(0, 0, 0, 0));
+    assert!(a.psnr(&tall).is_err());
+}
+
+#[test]
+fn test_changed_pixels() {
+    let gray = Pixel {
+        r: 50,
+        g: 50,
+        b: 50,
+        a: 255,
+    };
+    let a = Screenshot::new_blank(3, 2, gray);
+    let mut b = a.clone();
+    b.set_pixel(0, 1, Pixel { g: 53, ..gray });
+    b.set_pixel(1, 2, Pixel { r: 60, ..gray });
+    assert_eq!(a.changed_pixels(&b, 0), Some(2));
+    assert_eq!(a.changed_pixels(&b, 3), Some(1));
+    assert_eq!(a.changed_pixels(&b, 10), Some(0));
+    assert_eq!(
+        a.changed_pixels(&Screenshot::new_blank(2, 3, gray), 0),
+        None
+    );
+}
+
+#[test]
+fn test_concat() {
+    let color = |r: u8, g: u8, b: u8| Pixel { r, g, b, a: 255 };
+    let red = Screenshot::new_blank(2, 3, color(255, 0, 0));
+    let blue = Screenshot::new_blank(1, 1, color(0, 0, 255));
+    let gray = color(9, 9, 9);
+
+    let h = red.concat_horizontal(&blue, 2, gray).unwrap();
+    assert_eq!((h.width(), h.height()), (5, 3));
+    for row in 0..3 {
+        for col in 0..5 {
+            let expected = match (row, col) {
+                (_, 0..=1) => (255, 0, 0),
+                (0, 4) => (0, 0, 255),
+                _ => (9, 9, 9),
+            };
+            let p = h.get_pixel(row, col);
+            assert_eq!((p.r, p.g, p.b), expected, "({}, {})", row, col);
+        }
+    }
+
+    let v = red.concat_vertical(&blue, 1, gray).unwrap();
+    assert_eq!((v.width(), v.height()), (2, 5));
+    for row in 0..5 {
+        for col in 0..2 {
+            let expected = match (row, col) {
+                (0..=2, _) => (255, 0, 0),
+                (4, 0) => (0, 0, 255),
+                _ => (9, 9, 9),
+            };
+            let p = v.get_pixel(row, col);
+            assert_eq!((p.r, p.g, p.b), expected, "({}, {})", row, col);
+        }
+    }
+
+    let mut other = blue.clone();
+    other.format = PixelFormat::Rgba;
+    assert!(red.concat_horizontal(&other, 0, gray).is_err());
+}
+
+#[test]
+fn test_new_blank() {
+    let fill = Pixel {
+        r: 10,
+        g: 20,
+        b: 30,
+        a: 40,
+    };
+    let s = Screenshot::new_blank(3, 2, fill);
+    assert_eq!(
+        (s.width(), s.height(), s.row_len(), s.pixel_width()),
+        (3, 2, 12, 4)
+    );
+    assert_eq!(s.raw_len(), 24);
+    assert_eq!(s.alpha_mode(), AlphaMode::Straight);
+    for row in 0..2 {
+        for col in 0..3 {
+            let p = s.get_pixel(row, col);
+            assert_eq!((p.r, p.g, p.b, p.a), (10, 20, 30, 40));
+        }
+    }
+    let s = Screenshot::new_blank(1, 1, Pixel { a: 255, ..fill });
+    assert_eq!(s.alpha_mode(), AlphaMode::Opaque);
+    assert!(Screenshot::new_blank(0, 5, fill)
+        .raw_data_slice()
+        .is_empty());
+}
+
+#[test]
+fn test_unpremultiply() {
+    let mut s = Screenshot {
+        alpha_mode: AlphaMode::Premultiplied,
+        ..test_screenshot(
+            vec![
+                32, 64, 200, 128, // over-bright red clamps
+                10, 20, 30, 0, // transparent becomes black
+                1, 2, 3, 255, // opaque is unchanged
+            ],
+            3,
+            1,
+            12,
+            4,
+            PixelFormat::Bgra,
+        )
+    };
+    s.unpremultiply();
+    assert_eq!(s.alpha_mode(), AlphaMode::Straight);
+    assert_eq!(
+        s.raw_data_slice(),
+        &[64, 128, 255, 128, 0, 0, 0, 0, 1, 2, 3, 255][..]
+    );
+
+    // Straight images are left alone.
+    s.data[0] = 7;
+    s.unpremultiply();
+    assert_eq!(s.raw_data_slice()[0], 7);
+
+    // 16-bit RGBA, alpha at half.
+    let mut s = Screenshot {
+        bits_per_component: 16,
+        alpha_mode: AlphaMode::Premultiplied,
+        ..test_screenshot(
+            vec![0x00, 0x40, 0, 0, 0, 0, 0x00, 0x80],
+            1,
+            1,
+            8,
+            8,
+            PixelFormat::Rgba,
+        )
+    };
+    s.unpremultiply();
+    assert_eq!(s.get_pixel_u16(0, 0).unwrap().r, 0x8000);
+}
+
+#[test]
+fn test_eq_region() {
+    let a = test_screenshot(vec![100; 4 * 3 * 4], 4, 3, 16, 4, PixelFormat::Bgra);
+    // Smaller, padded, and different in its bottom-right pixel.
+    let mut b = test_screenshot(vec![100; 3 * 2 * 5], 3, 2, 15, 5, PixelFormat::Bgra);
+    assert!(a.eq_region(&b, 0, 0, 2, 2, 0).is_err());
+    b.pixel_width = 4;
+    b.row_len = 14;
+    b.data[14 + 8] = 103;
+
+    assert!(a.eq_region(&b, 0, 0, 2, 2, 0).unwrap());
+    assert!(!a.eq_region(&b, 1, 1, 2, 1, 2).unwrap());
+    assert!(a.eq_region(&b, 1, 1, 2, 1, 3).unwrap());
+    assert!(a.eq_region(&b, 2, 0, 2, 1, 0).is_err());
+    assert!(a.eq_region(&b, 0, 0, usize::MAX, 1, 0).is_err());
+}
+
+#[test]
+fn test_dominant_colors() {
+    let mut s = test_screenshot(vec![0; 4 * 4 * 4], 4, 4, 16, 4, PixelFormat::Bgra);
+    // Three quarters red, one quarter nearly-equal blues.
+    for (i, px) in s.data.chunks_mut(4).enumerate() {
+        let bgra = match i % 4 {
+            0 => [200, 0, 0, 255],
+            _ => [0, 0, 250, 255],
+        };
+        px.copy_from_slice(&bgra);
+        if i == 4 {
+            px[0] = 202;
+        }
+    }
+    let colors = s.dominant_colors(5);
+    assert_eq!(colors.len(), 2);
+    let (p, weight) = colors[0];
+    assert_eq!((p.r, p.g, p.b, p.a, weight), (250, 0, 0, 255, 0.75));
+    let (p, weight) = colors[1];
+    assert_eq!((p.r, p.g, p.b, p.a, weight), (0, 0, 201, 255, 0.25));
+    assert_eq!(s.dominant_colors(1).len(), 1);
+    assert!(s.dominant_colors(0).is_empty());
+}
+
+#[test]
+fn test_crop_centered() {
+    let mut s = test_screenshot(vec![0; 5 * 3 * 4], 5, 3, 20, 4, PixelFormat::Bgra);
+    for (i, px) in s.data.chunks_mut(4).enumerate() {
+        px[0] = i as u8;
+    }
+    assert!(s.crop_centered(6, 2).is_err());
+
+    // 5x3 to 2x2 cuts one column on the left, two on the right, and the
+    // bottom row.
+    let c = s.crop_centered(2, 2).unwrap();
+    assert_eq!((c.width(), c.height()), (2, 2));
+    assert_eq!(c.get_pixel(0, 0).b, 1);
+    assert_eq!(c.get_pixel(0, 1).b, 2);
+    assert_eq!(c.get_pixel(1, 0).b, 6);
+    assert_eq!(c.get_pixel(1, 1).b, 7);
+}
+
+#[test]
+fn test_pad_to() {
+    let s = test_screenshot(vec![7; 2 * 2 * 4], 2, 2, 8, 4, PixelFormat::Bgra);
+    let fill = Pixel {
+        r: 1,
+        g: 2,
+        b: 3,
+        a: 255,
+    };
+    assert!(s.pad_to(1, 4, fill).is_err());
+
+    let padded = s.pad_to(5, 4, fill).unwrap();
+    assert_eq!((padded.width(), padded.height()), (5, 4));
+    for row in 0..4 {
+        for col in 0..5 {
+            let p = padded.get_pixel(row, col);
+            let px = (p.r, p.g, p.b, p.a);
+            if (row == 1 || row == 2) && (col == 1 || col == 2) {
+                assert_eq!(px, (7, 7, 7, 7));
+            } else {
+                assert_eq!(px, (1, 2, 3, 255));
+            }
         }
     }
 }